@@ -3,16 +3,31 @@
 #![warn(clippy::needless_borrow)]
 #![warn(clippy::redundant_clone)]
 
+use anyhow::Context as _;
+use cargo::core::compiler::unit_graph;
 use cargo::core::compiler::{
-    unit_graph, BuildContext, BuildRunner, DefaultExecutor, Executor, UnitInterner,
+    BuildConfig, CommandLog, Compilation, CompileKind, DefaultExecutor, Executor, ExportFormat,
+    FileFlavor, LinkMethod, Unit, UnitInterner,
 };
-use cargo::core::shell::Shell;
-use cargo::core::Workspace;
-use cargo::ops::{CompileOptions, OutputFormat};
+use cargo::core::shell::{Shell, Verbosity};
+use cargo::core::{FeatureValue, Target, Workspace};
+use cargo::ops::batch::{Batch, BatchCache, BatchCommand, CommandResult};
+use cargo::ops::{self, CleanContext, CompileFilter, OutputFormat, Packages, TestOptions};
+use cargo::util::elf::Elf;
+use cargo::util::machine_message::{self, Message};
 use cargo::util::network::http::{http_handle, needs_custom_http_transport};
-use cargo::util::{command_prelude, CliResult, GlobalContext};
+use cargo::util::{self, command_prelude, style, CargoResult, CliResult, GlobalContext};
+use cargo_util::paths;
+use cargo_util_schemas::core::PackageIdSpec;
+use cargo_util_schemas::manifest::StringOrVec;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::sync::Arc;
-use std::{env, io};
+use std::time::{Duration, SystemTime};
+use std::{env, fmt, io};
 
 use crate::command_prelude::*;
 
@@ -43,20 +58,2507 @@ fn main2(gctx: &mut GlobalContext) -> CliResult {
     if global_args.len() >= 2 && global_args[1] == "batch" {
         global_args.remove(1);
     }
-    let global_args = Command::new("cargo-batch")
+    let global_args = build_global_cli().try_get_matches_from(global_args)?;
+
+    let cwd = gctx.cwd().to_path_buf();
+    config_configure(gctx, &global_args, &[], &cwd)?;
+    init_git_transports(gctx);
+    let gctx = &*gctx;
+
+    if let Some(("serve", serve_args)) = global_args.subcommand() {
+        if global_args.flag("watch") {
+            return Err(anyhow::format_err!("`--watch` cannot be used with `serve`").into());
+        }
+        let socket = serve_args.value_of_path("socket", gctx).unwrap();
+        return serve(gctx, &global_args, &socket);
+    }
+    if let Some(socket) = global_args.value_of_path("connect", gctx) {
+        // Skip the `batch` cargo passes when run as `cargo batch`.
+        let skip = if args.get(1).is_some_and(|arg| arg == "batch") {
+            2
+        } else {
+            1
+        };
+        return connect(&socket, &args[skip..]);
+    }
+
+    let inputs = if global_args.get_flag("stdin") {
+        read_stdin_commands()?
+    } else {
+        subargs.map(|x| (None, x.to_vec())).collect()
+    };
+    if global_args.flag("watch") {
+        return watch(gctx, &global_args, inputs);
+    }
+    run_batch(
+        gctx,
+        &global_args,
+        inputs,
+        &mut BatchState::new(gctx, &global_args),
+    )
+}
+
+/// State kept between the batches run by a single `cargo-batch` process.
+struct BatchState<'gctx> {
+    /// Arguments the process was started with, which configure the contexts
+    /// of commands with their own `--config`.
+    config_args: ArgMatches,
+    /// The directory the process was started in, which relative paths in
+    /// `config_args` are relative to.
+    cwd: PathBuf,
+    /// The directory of the client of the current `cargo-batch serve`
+    /// request, if it differs from `cwd`. Relative paths of the request are
+    /// relative to it, and its commands are created with a context for it.
+    client_cwd: Option<PathBuf>,
+    /// Contexts for commands with their own `--config` or those of another
+    /// client directory, keyed by that directory and those arguments so
+    /// commands with the same overrides share one, see
+    /// [`BatchState::cmd_gctx`].
+    cmd_gctxs: HashMap<(PathBuf, Vec<String>), &'gctx GlobalContext>,
+    /// Creates the shells of `cmd_gctxs` while a `cargo-batch serve` request
+    /// is built, which report to its client, see
+    /// [`BatchState::set_client_shell`].
+    client_shell: Option<Box<dyn Fn() -> Shell>>,
+    /// Target information and resolves of earlier batches.
+    cache: BatchCache<'gctx>,
+    /// Manifests and lockfiles `cache` was derived from, with their
+    /// modification times.
+    watched: HashMap<PathBuf, Option<SystemTime>>,
+    /// Whether batches are requested through `cargo-batch serve`, which
+    /// doesn't run tests.
+    serving: bool,
+    /// The indices of the commands in the last batch, after sharding, see
+    /// [`Batch::command_indices`].
+    commands: Vec<usize>,
+    /// Indices of the commands to build in the next batch, or `None` for all
+    /// of them. Set by `--watch` to rebuild only the affected commands.
+    only: Option<HashSet<usize>>,
+    /// With `--watch`, the source files each command was last built from,
+    /// by command index.
+    sources: HashMap<usize, BTreeSet<PathBuf>>,
+}
+
+impl<'gctx> BatchState<'gctx> {
+    fn new(gctx: &GlobalContext, config_args: &ArgMatches) -> BatchState<'gctx> {
+        BatchState {
+            config_args: config_args.clone(),
+            cwd: gctx.cwd().to_path_buf(),
+            client_cwd: None,
+            client_shell: None,
+            cmd_gctxs: HashMap::new(),
+            cache: BatchCache::default(),
+            watched: HashMap::new(),
+            serving: false,
+            commands: Vec::new(),
+            only: None,
+            sources: HashMap::new(),
+        }
+    }
+
+    /// The directory relative paths of the current batch are relative to.
+    fn batch_cwd(&self) -> &Path {
+        self.client_cwd.as_deref().unwrap_or(&self.cwd)
+    }
+
+    /// The path given to the batch-level option `name` of `args`, relative
+    /// to [`BatchState::batch_cwd`].
+    fn value_of_path(&self, args: &ArgMatches, name: &str) -> Option<PathBuf> {
+        args.get_one::<String>(name)
+            .map(|path| self.batch_cwd().join(path))
+    }
+
+    /// The context of commands with their own `--config` arguments, or of
+    /// all commands of a client in another directory, created the first time
+    /// they are used.
+    ///
+    /// Like the batch-level context, it lives as long as the process. There
+    /// is one per distinct directory and set of overrides, which are never
+    /// created again, so only a bounded number of them is leaked.
+    fn cmd_gctx(&mut self, config_args: Vec<String>) -> Result<&'gctx GlobalContext, CliError> {
+        let key = (self.batch_cwd().to_path_buf(), config_args);
+        if let Some(&cmd_gctx) = self.cmd_gctxs.get(&key) {
+            return Ok(cmd_gctx);
+        }
+        let cwd = key.0.clone();
+        let homedir = util::homedir(&cwd).ok_or_else(|| {
+            anyhow::format_err!(
+                "Cargo couldn't find your home directory. \
+                 This probably means that $HOME was not set."
+            )
+        })?;
+        let shell = self.client_shell.as_ref().map_or_else(Shell::new, |f| f());
+        let mut cmd_gctx = GlobalContext::new(shell, cwd, homedir);
+        config_configure(&mut cmd_gctx, &self.config_args, &key.1, &self.cwd)?;
+        let cmd_gctx = &*Box::leak(Box::new(cmd_gctx));
+        self.cmd_gctxs.insert(key, cmd_gctx);
+        Ok(cmd_gctx)
+    }
+
+    /// Makes the contexts of commands report to the shells created by
+    /// `client_shell`, or to the process' own output for `None`. Each keeps
+    /// its verbosity.
+    fn set_client_shell(&mut self, client_shell: Option<Box<dyn Fn() -> Shell>>) {
+        self.client_shell = client_shell;
+        for cmd_gctx in self.cmd_gctxs.values() {
+            let mut shell = self.client_shell.as_ref().map_or_else(Shell::new, |f| f());
+            let mut cmd_shell = cmd_gctx.shell();
+            shell.set_verbosity(cmd_shell.verbosity());
+            *cmd_shell = shell;
+        }
+    }
+
+    /// Whether a watched manifest or lockfile changed since the cached state
+    /// was derived from it.
+    fn manifests_changed(&self) -> bool {
+        self.watched
+            .iter()
+            .any(|(path, mtime)| modified(path) != *mtime)
+    }
+
+    /// Drops the cached state if a watched manifest or lockfile changed since
+    /// it was cached. Returns whether it did.
+    fn invalidate_changed(&mut self) -> bool {
+        let changed = self.manifests_changed();
+        if changed {
+            // Target information doesn't depend on manifests. Like the
+            // configuration, it's read once per context.
+            self.cache.clear_resolves();
+            self.watched.clear();
+        }
+        changed
+    }
+
+    /// Watches `path` for changes to the cached state.
+    fn watch(&mut self, path: &Path) {
+        if !self.watched.contains_key(path) {
+            self.watched.insert(path.to_path_buf(), modified(path));
+        }
+    }
+}
+
+/// The modification time of `path`, or `None` if it doesn't exist.
+fn modified(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|m| m.modified()).ok()
+}
+
+/// How often `--watch` checks the sources for changes.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Builds the batch, then keeps rebuilding the commands whose sources change
+/// until interrupted.
+///
+/// The sources of a command are the files in the dep-info of the local units
+/// its roots transitively depend on, plus the `rerun-if-changed` paths of
+/// their build scripts. Only the commands that depend on a changed file are
+/// rebuilt, whether their units are up to date is left to the fingerprints.
+/// Changes to manifests, lockfiles or the batch file rebuild everything.
+fn watch(
+    gctx: &GlobalContext,
+    global_args: &ArgMatches,
+    inputs: Vec<(Option<String>, Vec<String>)>,
+) -> CliResult {
+    let batch_file = global_args.value_of_path("batch-file", gctx);
+    let mut state = BatchState::new(gctx, global_args);
+    loop {
+        let start = SystemTime::now();
+        gctx.reset_creation_time();
+        if let Err(e) = run_batch(gctx, global_args, inputs.clone(), &mut state) {
+            if let Some(error) = &e.error {
+                cargo::display_error(error, &mut gctx.shell());
+            }
+        }
+
+        let mut files: HashMap<PathBuf, Option<SystemTime>> = state
+            .sources
+            .values()
+            .flatten()
+            .chain(&batch_file)
+            .map(|path| (path.clone(), modified(path)))
+            .collect();
+        gctx.shell().status(
+            "Watching",
+            format!("{} files for changes", files.len() + state.watched.len()),
+        )?;
+
+        // Files that changed while the batch was building are picked up
+        // right away.
+        let mut changed: Vec<PathBuf> = files
+            .iter()
+            .filter(|(_, mtime)| mtime.is_some_and(|mtime| mtime > start))
+            .map(|(path, _)| path.clone())
+            .collect();
+        let mut manifests_changed = false;
+        while changed.is_empty() && !manifests_changed {
+            std::thread::sleep(WATCH_INTERVAL);
+            manifests_changed = state.manifests_changed();
+            for (path, mtime) in &mut files {
+                let current = modified(path);
+                if current != *mtime {
+                    *mtime = current;
+                    changed.push(path.clone());
+                }
+            }
+        }
+
+        if manifests_changed || batch_file.as_ref().is_some_and(|f| changed.contains(f)) {
+            state.only = None;
+            state.sources.clear();
+            gctx.shell()
+                .status("Rebuilding", "all commands after manifest changes")?;
+            continue;
+        }
+        // Commands without known sources failed before building anything,
+        // any change may fix them.
+        let affected: HashSet<usize> = state
+            .commands
+            .iter()
+            .copied()
+            .filter(|i| match state.sources.get(i) {
+                Some(sources) if !sources.is_empty() => {
+                    changed.iter().any(|path| sources.contains(path))
+                }
+                _ => true,
+            })
+            .collect();
+        gctx.shell().status(
+            "Rebuilding",
+            format!(
+                "{} of {} commands after changes to `{}`{}",
+                affected.len(),
+                state.commands.len(),
+                changed[0].display(),
+                match changed.len() {
+                    1 => String::new(),
+                    n => format!(" and {} more", n - 1),
+                }
+            ),
+        )?;
+        state.only = Some(affected);
+    }
+}
+
+/// Batch-level arguments that configure the `GlobalContext`, which requests
+/// to `cargo-batch serve` can't change, by argument ID and flag.
+const SERVE_FIXED_ARGS: &[(&str, &str)] = &[
+    ("target-dir", "--target-dir"),
+    ("color", "--color"),
+    ("frozen", "--frozen"),
+    ("locked", "--locked"),
+    ("offline", "--offline"),
+    ("config", "--config"),
+    ("unstable-features", "-Z"),
+    ("stdin", "--stdin"),
+    ("connect", "--connect"),
+];
+
+/// Tag of a response frame with output for stdout, see [`write_frame`].
+const FRAME_STDOUT: u8 = b'o';
+/// Tag of a response frame with output for stderr.
+const FRAME_STDERR: u8 = b'e';
+/// Tag of the last response frame, with the exit code of the batch as a
+/// big-endian `i32`.
+const FRAME_EXIT: u8 = b'x';
+
+/// Writes a frame of a response to a `cargo-batch serve` request: the `tag`,
+/// the length of `data` as a big-endian `u32`, then `data`.
+fn write_frame(mut w: impl io::Write, tag: u8, data: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(data.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    let mut frame = Vec::with_capacity(5 + data.len());
+    frame.push(tag);
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(data);
+    w.write_all(&frame)
+}
+
+/// Reads a frame written by [`write_frame`], or `None` at the end of the
+/// stream.
+fn read_frame(mut r: impl io::Read) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0; 5];
+    loop {
+        match r.read(&mut header[..1]) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    r.read_exact(&mut header[1..])?;
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    let mut data = vec![0; len as usize];
+    r.read_exact(&mut data)?;
+    Ok(Some((header[0], data)))
+}
+
+/// Sends everything written to it to a `cargo-batch serve` client, as frames
+/// with the same tag.
+#[cfg(unix)]
+struct FrameWriter {
+    stream: Rc<std::os::unix::net::UnixStream>,
+    tag: u8,
+}
+
+#[cfg(unix)]
+impl io::Write for FrameWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(u32::MAX as usize);
+        write_frame(&*self.stream, self.tag, &buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Builds the batches requested on the Unix socket at `socket`, one at a
+/// time.
+///
+/// A request is a single line with the directory of the client followed by
+/// the same arguments as a `cargo-batch` command line, like
+/// `/path/to/ws --keep-going --- build --- check`. Relative paths are
+/// relative to the directory of the client, and commands see the
+/// configuration of that directory. The response is a sequence of frames,
+/// see [`write_frame`], with the output of the batch for stdout and stderr,
+/// ending with a [`FRAME_EXIT`] frame.
+///
+/// The configuration and target information are kept between requests.
+/// Workspace resolves are kept until a manifest or lockfile they depend on
+/// changes.
+#[cfg(unix)]
+fn serve(gctx: &GlobalContext, global_args: &ArgMatches, socket: &Path) -> CliResult {
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixListener;
+
+    // The socket of an earlier server would make binding fail. Anything
+    // else at that path is left alone.
+    if let Ok(metadata) = socket.symlink_metadata() {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow::format_err!(
+                "`{}` already exists and is not a socket",
+                socket.display()
+            )
+            .into());
+        }
+        paths::remove_file(socket)?;
+    }
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("failed to listen on `{}`", socket.display()))?;
+    gctx.shell().status("Listening", socket.display())?;
+
+    let mut state = BatchState::new(gctx, global_args);
+    state.serving = true;
+    let verbosity = gctx.shell().verbosity();
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                gctx.shell()
+                    .warn(format!("failed to accept a connection: {e}"))?;
+                continue;
+            }
+        };
+        let mut request = String::new();
+        if BufReader::new(&stream).read_line(&mut request).is_err() || request.trim().is_empty() {
+            continue;
+        }
+
+        // The batch-level context and those of the commands all report to
+        // the client for the duration of the request.
+        let stream = Rc::new(stream);
+        let client_shell = {
+            let stream = Rc::clone(&stream);
+            move || {
+                Shell::from_writes(
+                    Box::new(FrameWriter {
+                        stream: Rc::clone(&stream),
+                        tag: FRAME_STDOUT,
+                    }),
+                    Box::new(FrameWriter {
+                        stream: Rc::clone(&stream),
+                        tag: FRAME_STDERR,
+                    }),
+                )
+            }
+        };
+        let mut shell = client_shell();
+        shell.set_verbosity(verbosity);
+        let server_shell = std::mem::replace(&mut *gctx.shell(), shell);
+        state.set_client_shell(Some(Box::new(client_shell)));
+        gctx.reset_creation_time();
+        let code = match serve_request(gctx, &request, &mut state) {
+            Ok(()) => 0,
+            Err(e) => {
+                if let Some(error) = &e.error {
+                    cargo::display_error(error, &mut gctx.shell());
+                }
+                e.exit_code
+            }
+        };
+        state.set_client_shell(None);
+        *gctx.shell() = server_shell;
+        let _ = write_frame(&*stream, FRAME_EXIT, &code.to_be_bytes());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn serve(_gctx: &GlobalContext, _global_args: &ArgMatches, _socket: &Path) -> CliResult {
+    Err(anyhow::format_err!("`cargo-batch serve` is only supported on Unix").into())
+}
+
+/// Runs a single request to `cargo-batch serve`.
+fn serve_request<'gctx>(
+    gctx: &'gctx GlobalContext,
+    request: &str,
+    state: &mut BatchState<'gctx>,
+) -> CliResult {
+    let args = shell_words::split(request)
+        .with_context(|| format!("failed to parse request `{}`", request.trim()))?;
+    let Some((cwd, args)) = args.split_first() else {
+        return Err(anyhow::format_err!("empty request").into());
+    };
+    let cwd = Path::new(cwd);
+    if !cwd.is_absolute() {
+        return Err(anyhow::format_err!(
+            "the directory of a request must be absolute, got `{}`",
+            cwd.display()
+        )
+        .into());
+    }
+    state.client_cwd = (cwd != gctx.cwd()).then(|| cwd.to_path_buf());
+    let mut subargs = args.split(|x| *x == "---");
+    let global_args = build_global_cli().try_get_matches_from(
+        ["cargo-batch".to_string()]
+            .into_iter()
+            .chain(subargs.next().unwrap().iter().cloned()),
+    )?;
+    for (id, flag) in SERVE_FIXED_ARGS {
+        if global_args.value_source(id) == Some(clap::parser::ValueSource::CommandLine) {
+            return Err(anyhow::format_err!(
+                "`{}` cannot be changed by a request, pass it to `cargo-batch serve`",
+                flag
+            )
+            .into());
+        }
+    }
+    if global_args.subcommand().is_some() {
+        return Err(anyhow::format_err!("requests cannot start another server").into());
+    }
+    if global_args.verbose() > 0 {
+        gctx.shell().set_verbosity(Verbosity::Verbose);
+    } else if global_args.flag("quiet") {
+        gctx.shell().set_verbosity(Verbosity::Quiet);
+    }
+
+    if global_args.flag("watch") {
+        return Err(anyhow::format_err!("requests cannot watch for changes").into());
+    }
+
+    let inputs = subargs.map(|x| (None, x.to_vec())).collect();
+    run_batch(gctx, &global_args, inputs, state)
+}
+
+/// Sends the batch given by `args`, the arguments of this process, to the
+/// `cargo-batch serve` listening on `socket` and prints its output.
+#[cfg(unix)]
+fn connect(socket: &Path, args: &[String]) -> CliResult {
+    use std::io::{BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    let cwd = env::current_dir().context("couldn't get the current directory of the process")?;
+    let mut request = vec![cwd.to_str().map(str::to_string).ok_or_else(|| {
+        anyhow::format_err!(
+            "the current directory `{}` is not valid UTF-8",
+            cwd.display()
+        )
+    })?];
+    let mut read_stdin = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "---" {
+            request.push(arg.clone());
+            request.extend(args.by_ref().cloned());
+        } else if arg == "--connect" {
+            args.next();
+        } else if arg == "--stdin" {
+            read_stdin = true;
+        } else if !arg.starts_with("--connect=") {
+            request.push(arg.clone());
+        }
+    }
+    // The server can't read our stdin, so send its commands as arguments.
+    if read_stdin {
+        for (_, cmd) in read_stdin_commands()? {
+            request.push("---".to_string());
+            request.extend(cmd);
+        }
+    }
+
+    let mut stream = UnixStream::connect(socket)
+        .with_context(|| format!("failed to connect to `{}`", socket.display()))?;
+    writeln!(stream, "{}", shell_words::join(&request))?;
+    let mut stream = BufReader::new(stream);
+    while let Some((tag, data)) = read_frame(&mut stream)? {
+        match tag {
+            FRAME_STDOUT => io::stdout().write_all(&data)?,
+            FRAME_STDERR => io::stderr().write_all(&data)?,
+            FRAME_EXIT => {
+                let code = <[u8; 4]>::try_from(data)
+                    .map(i32::from_be_bytes)
+                    .unwrap_or(101);
+                return if code == 0 {
+                    Ok(())
+                } else {
+                    Err(CliError::code(code))
+                };
+            }
+            _ => {
+                return Err(
+                    anyhow::format_err!("unexpected response from `cargo-batch serve`").into(),
+                )
+            }
+        }
+    }
+    Err(anyhow::format_err!("`cargo-batch serve` closed the connection").into())
+}
+
+#[cfg(not(unix))]
+fn connect(_socket: &Path, _args: &[String]) -> CliResult {
+    Err(anyhow::format_err!("`--connect` is only supported on Unix").into())
+}
+
+/// Builds the batch given by the batch-level `global_args` and the commands
+/// in `inputs`, the `---` separated ones or those read from stdin, along with
+/// their names.
+fn run_batch<'gctx>(
+    gctx: &'gctx GlobalContext,
+    global_args: &ArgMatches,
+    inputs: Vec<(Option<String>, Vec<String>)>,
+    state: &mut BatchState<'gctx>,
+) -> CliResult {
+    if state.invalidate_changed() {
+        gctx.shell()
+            .status("Reloading", "manifests or lockfiles changed")?;
+    }
+
+    let unit_graph = global_args.flag("unit-graph");
+    let dedup_report = global_args.flag("dedup-report");
+
+    let mut subargs = inputs;
+    if let Some(path) = state.value_of_path(global_args, "batch-file") {
+        let mut file_args = read_batch_file(&path)?;
+        file_args.append(&mut subargs);
+        subargs = file_args;
+    }
+
+    let mut cmds = Vec::new();
+    let mut runs = Vec::new();
+    for (name, args) in subargs {
+        let line = shell_words::join(&args);
+        let cli = build_cli();
+        let args = cli.try_get_matches_from([String::new()].into_iter().chain(args.into_iter()))?;
+        let (subcmd, args) = args.subcommand().unwrap();
+
+        let config_args = values(args, "config");
+        let env = parse_env_args(&values(args, "env"))?;
+        let gctx: &GlobalContext = if config_args.is_empty() && state.client_cwd.is_none() {
+            gctx
+        } else {
+            state.cmd_gctx(config_args)?
+        };
+
+        match Matrix::from_args(args)? {
+            None => {
+                let (mut cmd, run) = parse_command(gctx, subcmd, args, name, line)?;
+                cmd.compile_opts.build_config.env = env;
+                cmds.push(cmd);
+                runs.push(run);
+            }
+            Some(matrix) => {
+                let (base, _) = parse_command(gctx, subcmd, args, None, String::new())?;
+                for cell in matrix.expand(&base.ws, &base.compile_opts.spec)? {
+                    let (mut cmd, run) =
+                        parse_command(gctx, subcmd, args, name.clone(), line.clone())?;
+                    cell.apply(&mut cmd, matrix.feature_powerset, gctx)?;
+                    cmd.compile_opts.build_config.env = env.clone();
+                    cmds.push(cmd);
+                    runs.push(run);
+                }
+            }
+        }
+    }
+
+    if cmds.is_empty() {
+        return Err(anyhow::format_err!("no commands to build").into());
+    }
+    if state.serving
+        && runs
+            .iter()
+            .any(|run| run.tests.as_ref().is_some_and(|t| !t.no_run))
+    {
+        return Err(anyhow::format_err!(
+            "`cargo-batch serve` does not run tests, pass `--no-run` to only build them"
+        )
+        .into());
+    }
+
+    // Batch-level options apply to every command.
+    if let Some(message_format) = global_args.message_format()? {
+        for cmd in &mut cmds {
+            cmd.compile_opts.build_config.message_format = message_format;
+        }
+    }
+
+    let interner = UnitInterner::new();
+    let mut batch = Batch::new(gctx, cmds);
+    batch
+        .cache(state.cache.clone())
+        .keep_going(global_args.flag("keep-going"))
+        .timing_outputs(global_args.timing_outputs(gctx)?)
+        .uplift(global_args.flag("uplift"))
+        .collect_sources(global_args.flag("watch"))
+        .log_dir(state.value_of_path(global_args, "log-dir"))
+        .link_method(
+            match global_args
+                .get_one::<String>("link-method")
+                .map(String::as_str)
+            {
+                Some("symlink") => LinkMethod::Symlink,
+                Some("copy") => LinkMethod::Copy,
+                _ => LinkMethod::Hardlink,
+            },
+        );
+
+    if let Some(shard) = global_args.get_one::<String>("shard") {
+        let shard = Shard::parse(shard)?;
+        let costs = match state.value_of_path(global_args, "shard-timings") {
+            Some(path) => Some(read_unit_costs(&path)?),
+            None => None,
+        };
+        let keys: Vec<&str> = batch.commands().iter().map(BatchCommand::label).collect();
+        let shards = assign_shards(
+            &keys,
+            &batch.command_units(&interner),
+            shard.count,
+            |unit| match &costs {
+                Some(costs) => costs.cost(unit),
+                None => 1.0,
+            },
+        );
+        let total = batch.commands().len();
+        batch.retain(|i, _| shards[i] == shard.index);
+        runs = runs
+            .into_iter()
+            .zip(&shards)
+            .filter(|&(_, &i)| i == shard.index)
+            .map(|(run, _)| run)
+            .collect();
+        gctx.shell().status(
+            "Shard",
+            format!(
+                "building {} of {} commands in shard {}/{}",
+                runs.len(),
+                total,
+                shard.index + 1,
+                shard.count
+            ),
+        )?;
+        if runs.is_empty() {
+            return Ok(());
+        }
+    }
+
+    // `--watch` only rebuilds the commands affected by the last change.
+    state.commands = batch.command_indices().to_vec();
+    if let Some(only) = state.only.take() {
+        runs = runs
+            .into_iter()
+            .zip(batch.command_indices())
+            .filter(|(_, i)| only.contains(i))
+            .map(|(run, _)| run)
+            .collect();
+        batch.retain(|i, _| only.contains(&i));
+    }
+    let indices = batch.command_indices().to_vec();
+
+    let batch_bcx = batch.create_bcx(&interner)?;
+    for cmd in batch.commands() {
+        state.watch(cmd.ws.root_manifest());
+        state.watch(&cmd.ws.lock_root().as_path_unlocked().join("Cargo.lock"));
+    }
+    if let Some(bcx) = &batch_bcx.bcx {
+        for pkg in bcx.packages.packages() {
+            if pkg.package_id().source_id().is_path() {
+                state.watch(pkg.manifest_path());
+            }
+        }
+    }
+    let build_config = batch.build_config()?;
+
+    if let Some(bcx) = &batch_bcx.bcx {
+        if unit_graph {
+            unit_graph::emit_serialized_unit_graph(&bcx.roots, &bcx.unit_graph, bcx.ws.gctx())?;
+            return Ok(());
+        }
+        if dedup_report {
+            emit_dedup_report(gctx, build_config, runs.len(), &batch_bcx.unit_uses)?;
+            return Ok(());
+        }
+    }
+
+    if global_args.flag("gc-unused") {
+        let mut clean_ctx = CleanContext::new(gctx);
+        clean_ctx.dry_run = global_args.dry_run();
+        clean_ctx.remove_paths(&batch_bcx.unused_paths()?)?;
+        clean_ctx.display_summary()?;
+        if clean_ctx.dry_run {
+            return Ok(());
+        }
+    }
+
+    // util::profile disappeared between cargo 1.76 and cargo 1.78
+    // let _p = cargo::util::profile::start("compiling");
+    let command_roots = batch_bcx.command_roots.clone();
+    let exec: Arc<dyn Executor> = Arc::new(DefaultExecutor);
+    let mut compiled = batch_bcx.compile(&exec);
+    for (i, sources) in indices
+        .into_iter()
+        .zip(std::mem::take(&mut compiled.command_sources))
+    {
+        state.sources.insert(i, sources);
+    }
+    report_results(
+        gctx,
+        build_config,
+        batch.commands(),
+        batch.command_indices(),
+        &compiled.results,
+        &compiled.command_logs,
+    )?;
+    let compilations = compiled.compilations?;
+
+    if let Some(path) = state.value_of_path(global_args, "artifact-manifest") {
+        write_artifact_manifest(
+            &path,
+            batch.commands(),
+            batch.command_indices(),
+            &command_roots,
+            &compilations,
+        )?;
+    }
+
+    if let Some(path) = state.value_of_path(global_args, "size-report") {
+        let report = write_size_report(&path, batch.commands(), &command_roots, &compilations)?;
+        if let Some(baseline) = state.value_of_path(global_args, "size-baseline") {
+            let baseline: SizeReport = serde_json::from_str(&paths::read(&baseline)?)
+                .with_context(|| format!("failed to parse `{}`", baseline.display()))?;
+            let max_growth = match global_args.get_one::<String>("size-max-growth") {
+                Some(max_growth) => Some(SizeGrowth::parse(max_growth)?),
+                None => None,
+            };
+            compare_sizes(gctx, build_config, &report, &baseline, max_growth)?;
+        }
+    }
+
+    for (i, cmd) in batch.commands().iter().enumerate() {
+        let Some(compilation) = &compilations[i] else {
+            continue;
+        };
+        if !runs[i].open_docs {
+            continue;
+        }
+        let root = command_roots[i]
+            .iter()
+            .find(|unit| unit.mode.is_doc())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "cannot open specified crate's documentation: no documentation generated"
+                )
+            })?;
+        let doc_dir = match &cmd.export_dir {
+            Some(export_dir) => export_dir.clone(),
+            None => compilation.root_output[&root.kind].with_file_name("doc"),
+        };
+        let path = doc_dir.join(root.target.crate_name()).join("index.html");
+        if path.exists() {
+            ops::open_doc(&cmd.ws, &path)?;
+        }
+    }
+
+    // Run the tests of each command against its own roots, in command order.
+    let commands = batch.into_commands();
+    for ((cmd, run), compilation) in commands.into_iter().zip(runs).zip(compilations) {
+        let (Some(tests), Some(mut compilation)) = (run.tests, compilation) else {
+            continue;
+        };
+        compilation.tests.sort();
+
+        let test_args = tests.args.iter().map(String::as_str).collect::<Vec<_>>();
+        let opts = TestOptions {
+            compile_opts: cmd.compile_opts,
+            no_run: tests.no_run,
+            no_fail_fast: tests.no_fail_fast,
+        };
+        if tests.bench {
+            ops::run_compiled_benches(&cmd.ws, &opts, &test_args, &compilation)?;
+        } else {
+            ops::run_compiled_tests(&cmd.ws, &opts, &test_args, &compilation)?;
+        }
+    }
+
+    let failed = compiled
+        .results
+        .iter()
+        .filter(|r| **r == CommandResult::Failed)
+        .count();
+    if failed > 0 {
+        return Err(anyhow::format_err!(
+            "{} of {} commands failed",
+            failed,
+            compiled.results.len()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Parses the `--export-format`s of a command.
+fn parse_export_formats(args: &ArgMatches) -> CargoResult<Vec<ExportFormat>> {
+    let family_id = match args.get_one::<String>("uf2-family") {
+        Some(id) => {
+            let parsed = match id.strip_prefix("0x").or_else(|| id.strip_prefix("0X")) {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => id.parse(),
+            };
+            Some(parsed.map_err(|_| {
+                anyhow::format_err!("invalid UF2 family ID `{id}`, expected a 32-bit number")
+            })?)
+        }
+        None => None,
+    };
+    values(args, "export-format")
+        .iter()
+        .map(|format| match format.as_str() {
+            "bin" => Ok(ExportFormat::Bin),
+            "hex" => Ok(ExportFormat::Hex),
+            "uf2" => match family_id {
+                Some(family_id) => Ok(ExportFormat::Uf2 { family_id }),
+                None => anyhow::bail!("`--export-format uf2` requires `--uf2-family`"),
+            },
+            _ => unreachable!("checked by clap"),
+        })
+        .collect()
+}
+
+/// Parses a single command of the batch, given its subcommand and arguments.
+fn parse_command<'gctx>(
+    gctx: &'gctx GlobalContext,
+    subcmd: &str,
+    args: &ArgMatches,
+    name: Option<String>,
+    line: String,
+) -> CargoResult<(BatchCommand<'gctx>, CommandRun)> {
+    let command = match subcmd {
+        "build" => {
+            let ws = args.workspace(gctx)?;
+
+            let compile_opts =
+                args.compile_options(gctx, CompileMode::Build, Some(&ws), ProfileChecking::Custom)?;
+            let export_dir = args.value_of_path("artifact-dir", gctx);
+            let export_formats = parse_export_formats(args)?;
+
+            //println!("compile opts: {:#?}", compile_opts);
+            (
+                BatchCommand {
+                    name,
+                    line,
+                    ws,
+                    compile_opts,
+                    export_dir,
+                    export_formats,
+                },
+                CommandRun {
+                    tests: None,
+                    open_docs: false,
+                },
+            )
+        }
+        // There is no `clippy` command: clippy runs as the workspace wrapper of
+        // `rustc`, which is the same for every unit of the merged build, and its
+        // check units would otherwise be shared with those of `check` commands.
+        "check" => {
+            let ws = args.workspace(gctx)?;
+            // This is a legacy behavior that causes `cargo check` to pass `--test`.
+            let test = matches!(
+                args.get_one::<String>("profile").map(String::as_str),
+                Some("test")
+            );
+            let mode = CompileMode::Check { test };
+            let compile_opts =
+                args.compile_options(gctx, mode, Some(&ws), ProfileChecking::LegacyTestOnly)?;
+
+            (
+                BatchCommand {
+                    name,
+                    line,
+                    ws,
+                    compile_opts,
+                    export_dir: None,
+                    export_formats: Vec::new(),
+                },
+                CommandRun {
+                    tests: None,
+                    open_docs: false,
+                },
+            )
+        }
+        "test" | "bench" => {
+            let ws = args.workspace(gctx)?;
+            let bench = subcmd == "bench";
+            let (mode, profile, name_arg) = if bench {
+                (CompileMode::Bench, "bench", "BENCHNAME")
+            } else {
+                (CompileMode::Test, "test", "TESTNAME")
+            };
+
+            let mut compile_opts =
+                args.compile_options(gctx, mode, Some(&ws), ProfileChecking::Custom)?;
+            compile_opts.build_config.requested_profile =
+                args.get_profile_name(profile, ProfileChecking::Custom)?;
+
+            // `TESTNAME` is actually an argument of the test binary, but it's
+            // important, so we explicitly mention it and reconfigure.
+            let test_name = args.get_one::<String>(name_arg);
+            let test_args = test_name
+                .into_iter()
+                .chain(args.get_many::<String>("args").unwrap_or_default())
+                .cloned()
+                .collect();
+
+            let no_run = args.flag("no-run");
+            if !bench && args.flag("doc") {
+                if compile_opts.filter.is_specific() {
+                    anyhow::bail!("Can't mix --doc with other target selecting options");
+                }
+                if no_run {
+                    anyhow::bail!("Can't skip running doc tests with --no-run");
+                }
+                compile_opts.build_config.mode = CompileMode::Doctest;
+                compile_opts.filter = CompileFilter::lib_only();
+            } else if !bench && test_name.is_some() && !compile_opts.filter.is_specific() {
+                compile_opts.filter = CompileFilter::all_test_targets();
+            }
+
+            (
+                BatchCommand {
+                    name,
+                    line,
+                    ws,
+                    compile_opts,
+                    export_dir: None,
+                    export_formats: Vec::new(),
+                },
+                CommandRun {
+                    tests: Some(TestRun {
+                        bench,
+                        no_run,
+                        no_fail_fast: args.flag("no-fail-fast"),
+                        args: test_args,
+                    }),
+                    open_docs: false,
+                },
+            )
+        }
+        "doc" => {
+            let ws = args.workspace(gctx)?;
+            let mode = CompileMode::Doc {
+                deps: !args.flag("no-deps"),
+                json: false,
+            };
+            let mut compile_opts =
+                args.compile_options(gctx, mode, Some(&ws), ProfileChecking::Custom)?;
+            compile_opts.rustdoc_document_private_items = args.flag("document-private-items");
+            let export_dir = args.value_of_path("artifact-dir", gctx);
+
+            (
+                BatchCommand {
+                    name,
+                    line,
+                    ws,
+                    compile_opts,
+                    export_dir,
+                    export_formats: Vec::new(),
+                },
+                CommandRun {
+                    tests: None,
+                    open_docs: args.flag("open"),
+                },
+            )
+        }
+        "rustdoc" => {
+            let ws = args.workspace(gctx)?;
+            let output_format = if let Some(output_format) = args._value_of("output-format") {
+                gctx.cli_unstable()
+                    .fail_if_stable_opt("--output-format", 12103)?;
+                output_format.parse()?
+            } else {
+                OutputFormat::Html
+            };
+
+            //panic!("output-format {output_format:?}");
+
+            let mut compile_opts = args.compile_options(
+                gctx,
+                CompileMode::Doc {
+                    deps: false,
+                    json: matches!(output_format, OutputFormat::Json),
+                },
+                Some(&ws),
+                ProfileChecking::Custom,
+            )?;
+            let export_dir = args.value_of_path("artifact-dir", gctx);
+            let target_args = values(args, "args");
+            compile_opts.target_rustdoc_args = if target_args.is_empty() {
+                None
+            } else {
+                Some(target_args)
+            };
+
+            //println!("compile opts: {:#?}", compile_opts);
+            (
+                BatchCommand {
+                    name,
+                    line,
+                    ws,
+                    compile_opts,
+                    export_dir,
+                    export_formats: Vec::new(),
+                },
+                CommandRun {
+                    tests: None,
+                    open_docs: false,
+                },
+            )
+        }
+        _ => unreachable!(),
+    };
+    Ok(command)
+}
+
+/// What to do with a command of the batch once the merged build is done.
+struct CommandRun {
+    tests: Option<TestRun>,
+    /// `cargo doc --open`.
+    open_docs: bool,
+}
+
+/// Tests or benchmarks to run once the merged build is done.
+struct TestRun {
+    bench: bool,
+    no_run: bool,
+    no_fail_fast: bool,
+    args: Vec<String>,
+}
+
+/// The most features a package can have for `--feature-powerset`, which
+/// builds every subset of them: up to 2^12 = 4096 combinations.
+const FEATURE_POWERSET_MAX_FEATURES: usize = 12;
+
+/// The `--matrix-*` and `--feature-powerset` options of a command, which
+/// expand it into one command per combination of packages, targets and
+/// features.
+struct Matrix {
+    packages: Vec<String>,
+    targets: Vec<String>,
+    features: Vec<BTreeSet<String>>,
+    /// Build every subset of the package's features, without its default
+    /// features.
+    feature_powerset: bool,
+    /// Combinations built in addition to the product of the axes.
+    include: Vec<MatrixCell>,
+    /// Combinations of the product of the axes that are not built.
+    exclude: Vec<MatrixCell>,
+}
+
+impl Matrix {
+    /// Returns the matrix of a command, or `None` if it has no matrix
+    /// options.
+    fn from_args(args: &ArgMatches) -> CargoResult<Option<Matrix>> {
+        let matrix = Matrix {
+            packages: values(args, "matrix-package"),
+            targets: values(args, "matrix-target"),
+            features: values(args, "matrix-features")
+                .iter()
+                .map(|features| split_features(features))
+                .collect(),
+            feature_powerset: args.flag("feature-powerset"),
+            include: values(args, "matrix-include")
+                .iter()
+                .map(|rule| MatrixCell::parse(rule))
+                .collect::<CargoResult<_>>()?,
+            exclude: values(args, "matrix-exclude")
+                .iter()
+                .map(|rule| MatrixCell::parse(rule))
+                .collect::<CargoResult<_>>()?,
+        };
+        let is_empty = matrix.packages.is_empty()
+            && matrix.targets.is_empty()
+            && matrix.features.is_empty()
+            && !matrix.feature_powerset
+            && matrix.include.is_empty()
+            && matrix.exclude.is_empty();
+        Ok((!is_empty).then_some(matrix))
+    }
+
+    /// Returns the combinations to build: the product of the axes, in
+    /// package, target and features order, without the excluded ones and
+    /// followed by the included ones. Axes without values keep the options
+    /// of the command as written.
+    ///
+    /// `ws` and `spec` are the workspace and packages of the command, used to
+    /// look up the features of `--feature-powerset`.
+    fn expand(&self, ws: &Workspace<'_>, spec: &Packages) -> CargoResult<Vec<MatrixCell>> {
+        self.cells(|package| {
+            let spec = match package {
+                Some(package) => Packages::Packages(vec![package.to_string()]),
+                None => spec.clone(),
+            };
+            feature_powerset(ws, &spec)
+        })
+    }
+
+    /// Like [`Matrix::expand`], with `powerset` returning the feature
+    /// combinations of `--feature-powerset` for a package of the matrix, or
+    /// the command's own packages for `None`.
+    fn cells(
+        &self,
+        mut powerset: impl FnMut(Option<&str>) -> CargoResult<Vec<BTreeSet<String>>>,
+    ) -> CargoResult<Vec<MatrixCell>> {
+        fn axis<T: Clone>(values: &[T]) -> Vec<Option<T>> {
+            if values.is_empty() {
+                vec![None]
+            } else {
+                values.iter().cloned().map(Some).collect()
+            }
+        }
+
+        let mut cells = Vec::new();
+        for package in axis(&self.packages) {
+            let features = if self.feature_powerset {
+                powerset(package.as_deref())?
+                    .into_iter()
+                    .map(Some)
+                    .collect()
+            } else {
+                axis(&self.features)
+            };
+            for target in axis(&self.targets) {
+                for features in &features {
+                    let cell = MatrixCell {
+                        package: package.clone(),
+                        target: target.clone(),
+                        features: features.clone(),
+                    };
+                    if !self.exclude.iter().any(|rule| rule.matches(&cell)) {
+                        cells.push(cell);
+                    }
+                }
+            }
+        }
+        for cell in &self.include {
+            if !cells.contains(cell) {
+                cells.push(cell.clone());
+            }
+        }
+        Ok(cells)
+    }
+}
+
+/// Returns every subset of the features declared by the single package
+/// selected by `spec`, smallest first.
+///
+/// The `default` feature and the implicit features of optional dependencies
+/// are left out, optional dependencies are only enabled through the features
+/// that name them.
+fn feature_powerset(ws: &Workspace<'_>, spec: &Packages) -> CargoResult<Vec<BTreeSet<String>>> {
+    let packages = spec.get_packages(ws)?;
+    let [package] = packages.as_slice() else {
+        anyhow::bail!(
+            "`--feature-powerset` needs a single package, but {} are selected\n\
+             Select one with `--package` or `--matrix-package`",
+            packages.len()
+        );
+    };
+    let features: Vec<String> = package
+        .summary()
+        .features()
+        .iter()
+        .filter(|(name, values)| {
+            let implicit = matches!(
+                values.as_slice(),
+                [FeatureValue::Dep { dep_name }] if dep_name == *name
+            );
+            name.as_str() != "default" && !implicit
+        })
+        .map(|(name, _)| name.to_string())
+        .collect();
+    if features.len() > FEATURE_POWERSET_MAX_FEATURES {
+        anyhow::bail!(
+            "package `{}` has {} features, `--feature-powerset` supports at most {}\n\
+             List the combinations to build with `--matrix-features` instead",
+            package.name(),
+            features.len(),
+            FEATURE_POWERSET_MAX_FEATURES
+        );
+    }
+    Ok(powerset(&features))
+}
+
+/// Returns every subset of `features`, smallest first.
+fn powerset(features: &[String]) -> Vec<BTreeSet<String>> {
+    let mut sets: Vec<BTreeSet<String>> = (0..1usize << features.len())
+        .map(|mask| {
+            features
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| mask & (1 << i) != 0)
+                .map(|(_, feature)| feature.clone())
+                .collect()
+        })
+        .collect();
+    sets.sort_by_key(|set| set.len());
+    sets
+}
+
+/// Splits a list of features, like `--features` does.
+fn split_features(features: &str) -> BTreeSet<String> {
+    features
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|feature| !feature.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// A combination of the axes of a [`Matrix`], or an include or exclude
+/// rule. Axes that are `None` keep the command's own options, or match
+/// anything in an exclude rule.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct MatrixCell {
+    package: Option<String>,
+    target: Option<String>,
+    features: Option<BTreeSet<String>>,
+}
+
+impl MatrixCell {
+    /// Parses a `--matrix-include` or `--matrix-exclude` rule, like
+    /// `target=thumbv7em-none-eabihf features=defmt,log`.
+    fn parse(rule: &str) -> CargoResult<MatrixCell> {
+        let mut cell = MatrixCell::default();
+        for pair in rule.split_whitespace() {
+            match pair.split_once('=') {
+                Some(("package", package)) => cell.package = Some(package.to_string()),
+                Some(("target", target)) => cell.target = Some(target.to_string()),
+                Some(("features", features)) => cell.features = Some(split_features(features)),
+                _ => anyhow::bail!(
+                    "invalid matrix rule `{}`, expected `package=`, `target=` or `features=` \
+                     values separated by spaces",
+                    rule
+                ),
+            }
+        }
+        Ok(cell)
+    }
+
+    /// Whether this exclude rule matches `cell`.
+    fn matches(&self, cell: &MatrixCell) -> bool {
+        (self.package.is_none() || self.package == cell.package)
+            && (self.target.is_none() || self.target == cell.target)
+            && (self.features.is_none() || self.features == cell.features)
+    }
+
+    /// Applies this combination to a command parsed from the matrix's
+    /// arguments. Features are added to the command's own `--features`,
+    /// unless they come from `--feature-powerset`.
+    fn apply(
+        &self,
+        cmd: &mut BatchCommand<'_>,
+        feature_powerset: bool,
+        gctx: &GlobalContext,
+    ) -> CargoResult<()> {
+        let opts = &mut cmd.compile_opts;
+        if let Some(package) = &self.package {
+            opts.spec = Packages::Packages(vec![package.clone()]);
+        }
+        if let Some(target) = &self.target {
+            opts.build_config.requested_kinds =
+                CompileKind::from_requested_targets(gctx, &[target.clone()])?;
+        }
+        if let Some(features) = &self.features {
+            let mut cli_features = (*opts.cli_features.features).clone();
+            if feature_powerset {
+                cli_features.clear();
+                opts.cli_features.uses_default_features = false;
+            }
+            cli_features.extend(
+                features
+                    .iter()
+                    .map(|f| FeatureValue::new(f.as_str().into())),
+            );
+            opts.cli_features.features = Rc::new(cli_features);
+        }
+
+        let values = self.values();
+        if !values.is_empty() {
+            cmd.name = cmd.name.take().map(|mut name| {
+                for (_, value) in &values {
+                    if !value.is_empty() {
+                        name.push('-');
+                        // Names are used as file names, see `BatchCommand::name`.
+                        name.push_str(&value.replace(',', "+").replace('/', "."));
+                    }
+                }
+                name
+            });
+            let described: Vec<_> = values
+                .iter()
+                .map(|(axis, value)| format!("{axis}={value}"))
+                .collect();
+            cmd.line = format!("{} [{}]", cmd.line, described.join(" "));
+        }
+        Ok(())
+    }
+
+    /// The axes of the combination that are set, and their values.
+    fn values(&self) -> Vec<(&'static str, String)> {
+        let mut values = Vec::new();
+        if let Some(package) = &self.package {
+            values.push(("package", package.clone()));
+        }
+        if let Some(target) = &self.target {
+            values.push(("target", target.clone()));
+        }
+        if let Some(features) = &self.features {
+            values.push((
+                "features",
+                Vec::from_iter(features.iter().cloned()).join(","),
+            ));
+        }
+        values
+    }
+}
+
+/// The files produced by the root units of every command, written by
+/// `--artifact-manifest`.
+#[derive(Serialize)]
+struct ArtifactManifest<'a> {
+    commands: Vec<ManifestCommand<'a>>,
+}
+
+#[derive(Serialize)]
+struct ManifestCommand<'a> {
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    command: &'a str,
+    units: Vec<ManifestUnit<'a>>,
+}
+
+#[derive(Serialize)]
+struct ManifestUnit<'a> {
+    package_id: PackageIdSpec,
+    target: &'a Target,
+    mode: CompileMode,
+    kind: CompileKind,
+    files: Vec<ManifestFile<'a>>,
+}
+
+#[derive(Serialize)]
+struct ManifestFile<'a> {
+    /// Where the compiler put the file.
+    path: &'a Path,
+    /// The copy in `--artifact-dir`, if any.
+    export_path: Option<&'a Path>,
+    /// The copy in `target/batch/<command>/` with `--uplift`, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uplift_path: Option<&'a Path>,
+    /// Conversions of the copy in `--artifact-dir` with `--export-format`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    converted_paths: Vec<&'a Path>,
+    flavor: &'static str,
+}
+
+fn write_artifact_manifest(
+    path: &Path,
+    cmds: &[BatchCommand<'_>],
+    indices: &[usize],
+    cmd_roots: &[Vec<Unit>],
+    compilations: &[Option<Compilation<'_>>],
+) -> CargoResult<()> {
+    let commands = cmds
+        .iter()
+        .zip(cmd_roots.iter().zip(compilations))
+        .enumerate()
+        .map(|(index, (cmd, (roots, compilation)))| ManifestCommand {
+            index: indices[index],
+            name: cmd.name.as_deref(),
+            command: &cmd.line,
+            units: roots
+                .iter()
+                .filter_map(|unit| {
+                    let outputs = compilation.as_ref()?.root_outputs.get(unit)?;
+                    Some(ManifestUnit {
+                        package_id: unit.pkg.package_id().to_spec(),
+                        target: &unit.target,
+                        mode: unit.mode,
+                        kind: unit.kind,
+                        files: outputs
+                            .iter()
+                            .map(|output| ManifestFile {
+                                path: &output.path,
+                                export_path: output.export_path.as_deref(),
+                                uplift_path: output
+                                    .command_paths
+                                    .iter()
+                                    .find(|(i, _)| *i == index)
+                                    .map(|(_, path)| path.as_path()),
+                                converted_paths: output
+                                    .export_formats
+                                    .iter()
+                                    .map(|(_, path)| path.as_path())
+                                    .collect(),
+                                flavor: match output.flavor {
+                                    FileFlavor::Normal => "normal",
+                                    FileFlavor::Auxiliary => "auxiliary",
+                                    FileFlavor::Linkable => "linkable",
+                                    FileFlavor::Rmeta => "rmeta",
+                                    FileFlavor::DebugInfo => "debuginfo",
+                                    FileFlavor::Sbom => "sbom",
+                                },
+                            })
+                            .collect(),
+                    })
+                })
+                .collect(),
+        })
+        .collect();
+    let manifest = serde_json::to_string_pretty(&ArtifactManifest { commands })?;
+    paths::write(path, manifest)
+}
+
+/// Sections recorded by `--size-report`. Each also counts the sections
+/// whose names start with it, like `.text.main`.
+const SIZE_REPORT_SECTIONS: &[&str] = &[".text", ".rodata", ".data", ".bss"];
+
+/// Section sizes of the ELF binaries of every command, written by
+/// `--size-report` and read back by `--size-baseline`.
+#[derive(Serialize, Deserialize)]
+struct SizeReport {
+    commands: Vec<SizeCommand>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SizeCommand {
+    /// The name of the command, or its command line if it has none.
+    command: String,
+    binaries: Vec<SizeBinary>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SizeBinary {
+    /// The name of the executable target.
+    target: String,
+    path: PathBuf,
+    /// Size in bytes of each of the [`SIZE_REPORT_SECTIONS`].
+    sections: BTreeMap<String, u64>,
+}
+
+impl SizeBinary {
+    fn section(&self, name: &str) -> u64 {
+        self.sections.get(name).copied().unwrap_or(0)
+    }
+
+    /// Bytes stored in flash: code, constants and the initial values of
+    /// statics.
+    fn flash(&self) -> u64 {
+        self.section(".text") + self.section(".rodata") + self.section(".data")
+    }
+
+    /// Bytes of RAM used by statics.
+    fn ram(&self) -> u64 {
+        self.section(".data") + self.section(".bss")
+    }
+}
+
+/// A `--size-max-growth` limit.
+#[derive(Clone, Copy)]
+enum SizeGrowth {
+    Bytes(u64),
+    Percent(f64),
+}
+
+impl SizeGrowth {
+    fn parse(s: &str) -> CargoResult<SizeGrowth> {
+        let parsed = match s.strip_suffix('%') {
+            Some(percent) => percent
+                .parse()
+                .ok()
+                .filter(|percent: &f64| percent.is_finite() && *percent >= 0.0)
+                .map(SizeGrowth::Percent),
+            None => s.parse().ok().map(SizeGrowth::Bytes),
+        };
+        parsed.ok_or_else(|| {
+            anyhow::format_err!(
+                "invalid `--size-max-growth` value `{s}`, expected bytes like `1024` or a \
+                 percentage like `2%`"
+            )
+        })
+    }
+
+    fn exceeded(self, old: u64, new: u64) -> bool {
+        let growth = new.saturating_sub(old);
+        match self {
+            SizeGrowth::Bytes(max) => growth > max,
+            SizeGrowth::Percent(max) => growth as f64 > old as f64 * max / 100.0,
+        }
+    }
+}
+
+impl fmt::Display for SizeGrowth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SizeGrowth::Bytes(bytes) => write!(f, "{bytes} bytes"),
+            SizeGrowth::Percent(percent) => write!(f, "{percent}%"),
+        }
+    }
+}
+
+/// Writes the section sizes of the executables built by the root units of
+/// every command that built to `path`. Outputs that aren't ELF files are
+/// skipped.
+fn write_size_report(
+    path: &Path,
+    cmds: &[BatchCommand<'_>],
+    cmd_roots: &[Vec<Unit>],
+    compilations: &[Option<Compilation<'_>>],
+) -> CargoResult<SizeReport> {
+    let mut commands = Vec::new();
+    for (index, (cmd, (roots, compilation))) in cmds
+        .iter()
+        .zip(cmd_roots.iter().zip(compilations))
+        .enumerate()
+    {
+        let mut binaries = Vec::new();
+        for unit in roots {
+            if !unit.mode.generates_executable() || !unit.target.is_executable() {
+                continue;
+            }
+            let Some(outputs) = compilation.as_ref().and_then(|c| c.root_outputs.get(unit)) else {
+                continue;
+            };
+            for output in outputs.iter() {
+                if output.flavor != FileFlavor::Normal {
+                    continue;
+                }
+                // Report the binary the user gets to see.
+                let path = output
+                    .export_path
+                    .as_ref()
+                    .or_else(|| {
+                        output
+                            .command_paths
+                            .iter()
+                            .find(|(i, _)| *i == index)
+                            .map(|(_, path)| path)
+                    })
+                    .unwrap_or(&output.path);
+                let data = paths::read_bytes(path)?;
+                if let Some(sections) = elf_section_sizes(&data) {
+                    binaries.push(SizeBinary {
+                        target: unit.target.name().to_string(),
+                        path: path.clone(),
+                        sections,
+                    });
+                }
+            }
+        }
+        commands.push(SizeCommand {
+            command: cmd.label().to_string(),
+            binaries,
+        });
+    }
+    let report = SizeReport { commands };
+    paths::write(path, serde_json::to_string_pretty(&report)?)?;
+    Ok(report)
+}
+
+/// Sums the sizes of the [`SIZE_REPORT_SECTIONS`] of an ELF file, or returns
+/// `None` if `data` isn't one.
+fn elf_section_sizes(data: &[u8]) -> Option<BTreeMap<String, u64>> {
+    let sections = Elf::parse(data)?.sections()?;
+    let mut sizes: BTreeMap<String, u64> = SIZE_REPORT_SECTIONS
+        .iter()
+        .map(|name| (name.to_string(), 0))
+        .collect();
+    for section in sections {
+        for &prefix in SIZE_REPORT_SECTIONS {
+            let matches = section
+                .name
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'));
+            if matches {
+                *sizes.get_mut(prefix).unwrap() += section.size;
+            }
+        }
+    }
+    Some(sizes)
+}
+
+/// The binaries of `baseline`, by the label of their command and the name of
+/// their target.
+fn baseline_binaries(baseline: &SizeReport) -> HashMap<(&str, &str), &SizeBinary> {
+    baseline
+        .commands
+        .iter()
+        .flat_map(|cmd| {
+            cmd.binaries
+                .iter()
+                .map(move |bin| ((cmd.command.as_str(), bin.target.as_str()), bin))
+        })
+        .collect()
+}
+
+/// Prints how the sizes in `report` changed since `baseline`, either as a
+/// table on stderr or as a JSON message, and fails if the flash or RAM use of
+/// a binary grew by more than `max_growth`.
+fn compare_sizes(
+    gctx: &GlobalContext,
+    build_config: &BuildConfig,
+    report: &SizeReport,
+    baseline: &SizeReport,
+    max_growth: Option<SizeGrowth>,
+) -> CargoResult<()> {
+    let old_binaries = baseline_binaries(baseline);
+    let column = |new: u64, old: Option<u64>| match old {
+        Some(old) if old == new => format!("{new}"),
+        Some(old) => format!("{new} ({:+})", new as i64 - old as i64),
+        None => format!("{new} (new)"),
+    };
+
+    let mut header = vec!["command".to_string(), "binary".to_string()];
+    header.extend(SIZE_REPORT_SECTIONS.iter().map(|s| s.to_string()));
+    header.extend(["flash".to_string(), "ram".to_string()]);
+    let mut rows = vec![header];
+    let mut sizes = Vec::new();
+    let mut exceeded = Vec::new();
+    for cmd in &report.commands {
+        for bin in &cmd.binaries {
+            let old = old_binaries
+                .get(&(cmd.command.as_str(), bin.target.as_str()))
+                .copied();
+            let mut row = vec![cmd.command.clone(), bin.target.clone()];
+            for &section in SIZE_REPORT_SECTIONS {
+                row.push(column(
+                    bin.section(section),
+                    old.map(|old| old.section(section)),
+                ));
+            }
+            row.push(column(bin.flash(), old.map(SizeBinary::flash)));
+            row.push(column(bin.ram(), old.map(SizeBinary::ram)));
+            rows.push(row);
+
+            let bin_exceeded = match (max_growth, old) {
+                (Some(max_growth), Some(old)) => {
+                    max_growth.exceeded(old.flash(), bin.flash())
+                        || max_growth.exceeded(old.ram(), bin.ram())
+                }
+                _ => false,
+            };
+            if bin_exceeded {
+                exceeded.push(format!("{} ({})", bin.target, cmd.command));
+            }
+            sizes.push(machine_message::BinarySize {
+                command: &cmd.command,
+                target: &bin.target,
+                flash: bin.flash(),
+                ram: bin.ram(),
+                baseline_flash: old.map(SizeBinary::flash),
+                baseline_ram: old.map(SizeBinary::ram),
+                exceeded: bin_exceeded,
+            });
+        }
+    }
+
+    let mut shell = gctx.shell();
+    if build_config.emit_json() {
+        let msg = machine_message::BatchSizeComparison { binaries: sizes }.to_json_string();
+        writeln!(shell.out(), "{}", msg)?;
+    } else {
+        let mut widths = vec![0; rows[0].len()];
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+        // On stderr like cargo's other reports, stdout is left to the
+        // commands.
+        let err = shell.err();
+        for row in &rows {
+            let mut line = String::new();
+            for (i, (cell, width)) in row.iter().zip(&widths).enumerate() {
+                if i < 2 {
+                    line.push_str(&format!("{cell:<width$}  "));
+                } else {
+                    line.push_str(&format!("{cell:>width$}  "));
+                }
+            }
+            writeln!(err, "{}", line.trim_end())?;
+        }
+    }
+    drop(shell);
+
+    if let Some(max_growth) = max_growth {
+        if !exceeded.is_empty() {
+            anyhow::bail!(
+                "flash or RAM use grew by more than {} for:\n    {}",
+                max_growth,
+                exceeded.join("\n    ")
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Prints the result of every command, either as a summary on the console or
+/// as one JSON message per command.
+///
+/// With `--log-dir`, commands whose log has warnings or errors are listed
+/// with their counts and log file.
+fn report_results(
+    gctx: &GlobalContext,
+    build_config: &BuildConfig,
+    cmds: &[BatchCommand<'_>],
+    indices: &[usize],
+    results: &[CommandResult],
+    logs: &[CommandLog],
+) -> CargoResult<()> {
+    if build_config.emit_json() {
+        let mut shell = gctx.shell();
+        for ((cmd, &index), result) in cmds.iter().zip(indices).zip(results) {
+            let msg = machine_message::BatchCommandFinished {
+                index,
+                name: cmd.name.as_deref(),
+                command: &cmd.line,
+                result: result.as_str(),
+            }
+            .to_json_string();
+            writeln!(shell.out(), "{}", msg)?;
+        }
+        return Ok(());
+    }
+
+    let count = |result| results.iter().filter(|r| **r == result).count();
+    let mut shell = gctx.shell();
+    for (i, (cmd, result)) in cmds.iter().zip(results).enumerate() {
+        let log = logs
+            .get(i)
+            .filter(|log| log.warnings > 0 || log.errors > 0 || *result == CommandResult::Failed);
+        let label = match log {
+            Some(log) => format!(
+                "{} ({}, {}, see `{}`)",
+                cmd.label(),
+                plural(log.errors, "error"),
+                plural(log.warnings, "warning"),
+                log.path.display()
+            ),
+            None => cmd.label().to_string(),
+        };
+        match result {
+            CommandResult::Failed => shell.status_with_color("Failed", label, &style::ERROR)?,
+            CommandResult::Skipped => shell.status_with_color("Skipped", label, &style::WARN)?,
+            CommandResult::Built if log.is_some() => {
+                shell.status_with_color("Built", label, &style::WARN)?
+            }
+            CommandResult::Fresh if log.is_some() => {
+                shell.status_with_color("Fresh", label, &style::WARN)?
+            }
+            CommandResult::Built => shell.verbose(|s| s.status("Built", &label))?,
+            CommandResult::Fresh => shell.verbose(|s| s.status("Fresh", &label))?,
+        }
+    }
+    shell.status(
+        "Summary",
+        format!(
+            "{} commands: {} built, {} fresh, {} failed, {} skipped",
+            results.len(),
+            count(CommandResult::Built),
+            count(CommandResult::Fresh),
+            count(CommandResult::Failed),
+            count(CommandResult::Skipped),
+        ),
+    )
+}
+
+/// Formats `count` of `noun`, like `1 error` or `2 warnings`.
+fn plural(count: usize, noun: &str) -> String {
+    match count {
+        1 => format!("1 {noun}"),
+        n => format!("{n} {noun}s"),
+    }
+}
+
+/// Number of most-shared units listed by `--dedup-report`.
+const DEDUP_REPORT_TOP_UNITS: usize = 20;
+
+/// Prints how much merging the commands into one build saves, given how many
+/// commands need each unit.
+fn emit_dedup_report(
+    gctx: &GlobalContext,
+    build_config: &BuildConfig,
+    commands: usize,
+    unit_uses: &HashMap<Unit, usize>,
+) -> CargoResult<()> {
+    let total_units: usize = unit_uses.values().sum();
+    let unique_units = unit_uses.len();
+
+    let mut shared: Vec<(&Unit, usize)> = unit_uses
+        .iter()
+        .map(|(unit, &uses)| (unit, uses))
+        .filter(|&(_, uses)| uses > 1)
+        .collect();
+    shared.sort_by(|(a, a_uses), (b, b_uses)| {
+        b_uses
+            .cmp(a_uses)
+            .then_with(|| a.pkg.package_id().cmp(&b.pkg.package_id()))
+            .then_with(|| a.target.name().cmp(b.target.name()))
+    });
+    shared.truncate(DEDUP_REPORT_TOP_UNITS);
+
+    let mut shell = gctx.shell();
+    if build_config.emit_json() {
+        let msg = machine_message::BatchDedupReport {
+            commands,
+            total_units,
+            unique_units,
+            shared_units: shared
+                .iter()
+                .map(|(unit, uses)| machine_message::SharedUnit {
+                    package_id: unit.pkg.package_id().to_spec(),
+                    target: &unit.target,
+                    mode: unit.mode,
+                    commands: *uses,
+                })
+                .collect(),
+        }
+        .to_json_string();
+        writeln!(shell.out(), "{}", msg)?;
+        return Ok(());
+    }
+
+    let saved = total_units - unique_units;
+    let percent = if total_units == 0 {
+        0.0
+    } else {
+        saved as f64 * 100.0 / total_units as f64
+    };
+    let out = shell.out();
+    writeln!(
+        out,
+        "{commands} commands need {total_units} units, {unique_units} after merging \
+         ({saved} deduplicated, {percent:.1}%)"
+    )?;
+    if !shared.is_empty() {
+        writeln!(out, "most shared units:")?;
+        for (unit, uses) in shared {
+            let kind = match unit.kind {
+                CompileKind::Host => "host",
+                CompileKind::Target(ref target) => target.short_name(),
+            };
+            writeln!(
+                out,
+                "  {uses:>4} commands  {} v{} {} ({}, {kind})",
+                unit.pkg.name(),
+                unit.pkg.version(),
+                unit.target.description_named(),
+                serde_json::to_value(unit.mode)?
+                    .as_str()
+                    .unwrap_or_default(),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// A `--shard N/M` selection.
+struct Shard {
+    /// Zero-based index of the shard to build.
+    index: usize,
+    /// Number of shards the batch is split into.
+    count: usize,
+}
+
+impl Shard {
+    fn parse(s: &str) -> CargoResult<Shard> {
+        let parsed = s
+            .split_once('/')
+            .and_then(|(n, m)| Some((n.parse::<usize>().ok()?, m.parse::<usize>().ok()?)));
+        match parsed {
+            Some((n, m)) if 1 <= n && n <= m => Ok(Shard {
+                index: n - 1,
+                count: m,
+            }),
+            _ => anyhow::bail!("invalid shard `{}`, expected `N/M` with 1 <= N <= M", s),
+        }
+    }
+}
+
+/// Build durations of units from an earlier `--timings=json` run.
+struct UnitCosts {
+    /// Durations keyed by package ID, target name and mode.
+    durations: HashMap<(String, String, String), f64>,
+    /// Used for units that weren't built in the earlier run.
+    default: f64,
+}
+
+impl UnitCosts {
+    fn cost(&self, unit: &Unit) -> f64 {
+        let key = (
+            unit.pkg.package_id().to_spec().to_string(),
+            unit.target.name().to_string(),
+            serde_json::to_value(unit.mode)
+                .ok()
+                .and_then(|mode| mode.as_str().map(str::to_string))
+                .unwrap_or_default(),
+        );
+        self.durations.get(&key).copied().unwrap_or(self.default)
+    }
+}
+
+/// Reads the `timing-info` messages of a `--timings=json` output. Any other
+/// line is ignored.
+fn read_unit_costs(path: &Path) -> CargoResult<UnitCosts> {
+    let contents = paths::read(path)?;
+    let mut durations = HashMap::new();
+    for line in contents.lines() {
+        let Ok(msg) = serde_json::from_str::<serde_json::Value>(line) else {
+            continue;
+        };
+        if msg["reason"] != "timing-info" {
+            continue;
+        }
+        let (Some(package_id), Some(target), Some(mode), Some(duration)) = (
+            msg["package_id"].as_str(),
+            msg["target"]["name"].as_str(),
+            msg["mode"].as_str(),
+            msg["duration"].as_f64(),
+        ) else {
+            continue;
+        };
+        durations.insert(
+            (package_id.to_string(), target.to_string(), mode.to_string()),
+            duration,
+        );
+    }
+    let default = if durations.is_empty() {
+        1.0
+    } else {
+        durations.values().sum::<f64>() / durations.len() as f64
+    };
+    Ok(UnitCosts { durations, default })
+}
+
+/// How much more than an even share of the total cost a shard may get before
+/// commands that prefer it go elsewhere, see [`assign_shards`].
+const SHARD_SLACK: f64 = 0.25;
+
+/// Splits commands into `count` shards, given the key identifying each
+/// command and the units it needs.
+///
+/// Each command prefers the shards in the order of a hash of its key and the
+/// shard index (rendezvous hashing), so its shard mostly depends on its own
+/// key. Reordering the commands moves none of them.
+///
+/// To keep shards balanced, a command goes to its most preferred shard whose
+/// cost stays within [`SHARD_SLACK`] of an even share. Units a shard already
+/// builds for other commands are free. If no shard has room, the command goes
+/// to the one it adds the least to. Adding or removing commands only moves
+/// the commands that this limit pushes out of their preferred shard.
+fn assign_shards<U: Eq + Hash>(
+    cmd_keys: &[&str],
+    cmd_units: &[HashSet<U>],
+    count: usize,
+    cost: impl Fn(&U) -> f64,
+) -> Vec<usize> {
+    let total: f64 = cmd_units
+        .iter()
+        .flatten()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .map(&cost)
+        .sum();
+    let limit = total / count as f64 * (1.0 + SHARD_SLACK);
+    let cmd_costs: Vec<f64> = cmd_units
+        .iter()
+        .map(|units| units.iter().map(&cost).sum())
+        .collect();
+    let mut order: Vec<usize> = (0..cmd_units.len()).collect();
+    order.sort_by(|&a, &b| {
+        cmd_costs[b]
+            .total_cmp(&cmd_costs[a])
+            .then_with(|| cmd_keys[a].cmp(cmd_keys[b]))
+            .then(a.cmp(&b))
+    });
+
+    let mut shard_units: Vec<HashSet<&U>> = (0..count).map(|_| HashSet::new()).collect();
+    let mut shard_costs = vec![0.0; count];
+    let mut shards = vec![0; cmd_units.len()];
+    for i in order {
+        let added = |shard: usize| -> f64 {
+            cmd_units[i]
+                .iter()
+                .filter(|unit| !shard_units[shard].contains(unit))
+                .map(&cost)
+                .sum()
+        };
+        let mut preferred: Vec<usize> = (0..count).collect();
+        preferred.sort_by_key(|&shard| std::cmp::Reverse(util::hash_u64((cmd_keys[i], shard))));
+        let shard = preferred
+            .iter()
+            .copied()
+            .find(|&shard| shard_costs[shard] + added(shard) <= limit)
+            .unwrap_or_else(|| {
+                (0..count)
+                    .min_by(|&a, &b| {
+                        (shard_costs[a] + added(a))
+                            .total_cmp(&(shard_costs[b] + added(b)))
+                            .then(a.cmp(&b))
+                    })
+                    .unwrap()
+            });
+        shard_costs[shard] += added(shard);
+        shard_units[shard].extend(&cmd_units[i]);
+        shards[i] = shard;
+    }
+    shards
+}
+
+/// Reads one command per line from stdin. Blank lines and lines starting
+/// with `#` are skipped.
+fn read_stdin_commands() -> CargoResult<Vec<(Option<String>, Vec<String>)>> {
+    read_command_lines(io::stdin().lock())
+}
+
+/// Reads one command per line from `input`, see [`read_stdin_commands`].
+fn read_command_lines(input: impl io::BufRead) -> CargoResult<Vec<(Option<String>, Vec<String>)>> {
+    let mut commands = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line.with_context(|| format!("failed to read line {} of stdin", i + 1))?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let args = shell_words::split(line)
+            .with_context(|| format!("failed to parse line {} of stdin: `{}`", i + 1, line))?;
+        commands.push((None, args));
+    }
+    Ok(commands)
+}
+
+/// A batch of commands read from `--batch-file`.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct BatchFile {
+    #[serde(default)]
+    command: Vec<BatchFileCommand>,
+}
+
+/// A single `[[command]]` entry of a batch file.
+///
+/// Entries are turned back into command lines, so that they go through the
+/// exact same argument parsing as commands given on the command line.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct BatchFileCommand {
+    name: Option<String>,
+    subcommand: String,
+    manifest_path: Option<PathBuf>,
+    package: Option<StringOrVec>,
+    #[serde(default)]
+    features: Vec<String>,
+    #[serde(default)]
+    all_features: bool,
+    #[serde(default)]
+    no_default_features: bool,
+    target: Option<StringOrVec>,
+    profile: Option<String>,
+    artifact_dir: Option<PathBuf>,
+    #[serde(default)]
+    export_format: Vec<String>,
+    uf2_family: Option<String>,
+    #[serde(default)]
+    config: Vec<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    #[serde(default)]
+    feature_powerset: bool,
+    matrix: Option<BatchFileMatrix>,
+    /// Any other arguments of the subcommand, passed through verbatim.
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+impl BatchFileCommand {
+    /// Returns the command line equivalent to this entry. Relative paths are
+    /// resolved against `base`, the directory of the batch file.
+    fn to_args(&self, base: &Path) -> Vec<String> {
+        let mut args = vec![self.subcommand.clone()];
+        if let Some(path) = &self.manifest_path {
+            args.push("--manifest-path".to_string());
+            args.push(base.join(path).display().to_string());
+        }
+        for package in self.package.iter().flat_map(StringOrVec::iter) {
+            args.push("--package".to_string());
+            args.push(package.clone());
+        }
+        if !self.features.is_empty() {
+            args.push("--features".to_string());
+            args.push(self.features.join(","));
+        }
+        if self.all_features {
+            args.push("--all-features".to_string());
+        }
+        if self.no_default_features {
+            args.push("--no-default-features".to_string());
+        }
+        for target in self.target.iter().flat_map(StringOrVec::iter) {
+            args.push("--target".to_string());
+            args.push(target.clone());
+        }
+        if let Some(profile) = &self.profile {
+            args.push("--profile".to_string());
+            args.push(profile.clone());
+        }
+        if let Some(path) = &self.artifact_dir {
+            args.push("--artifact-dir".to_string());
+            args.push(base.join(path).display().to_string());
+        }
+        for format in &self.export_format {
+            args.push("--export-format".to_string());
+            args.push(format.clone());
+        }
+        if let Some(family) = &self.uf2_family {
+            args.push("--uf2-family".to_string());
+            args.push(family.clone());
+        }
+        for config in &self.config {
+            args.push("--config".to_string());
+            args.push(config.clone());
+        }
+        for (key, value) in &self.env {
+            args.push("--env".to_string());
+            args.push(format!("{key}={value}"));
+        }
+        if self.feature_powerset {
+            args.push("--feature-powerset".to_string());
+        }
+        if let Some(matrix) = &self.matrix {
+            matrix.push_args(&mut args);
+        }
+        args.extend(self.args.iter().cloned());
+        args
+    }
+}
+
+/// The `matrix` table of a batch file command, see [`Matrix`].
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct BatchFileMatrix {
+    #[serde(default)]
+    package: Vec<String>,
+    #[serde(default)]
+    target: Vec<String>,
+    #[serde(default)]
+    features: Vec<Vec<String>>,
+    #[serde(default)]
+    include: Vec<BatchFileMatrixRule>,
+    #[serde(default)]
+    exclude: Vec<BatchFileMatrixRule>,
+}
+
+/// An `include` or `exclude` entry of a batch file matrix.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct BatchFileMatrixRule {
+    package: Option<String>,
+    target: Option<String>,
+    features: Option<Vec<String>>,
+}
+
+impl BatchFileMatrix {
+    /// Appends the `--matrix-*` arguments equivalent to this table.
+    fn push_args(&self, args: &mut Vec<String>) {
+        for package in &self.package {
+            args.push("--matrix-package".to_string());
+            args.push(package.clone());
+        }
+        for target in &self.target {
+            args.push("--matrix-target".to_string());
+            args.push(target.clone());
+        }
+        for features in &self.features {
+            args.push("--matrix-features".to_string());
+            args.push(features.join(","));
+        }
+        for rule in &self.include {
+            args.push("--matrix-include".to_string());
+            args.push(rule.to_arg());
+        }
+        for rule in &self.exclude {
+            args.push("--matrix-exclude".to_string());
+            args.push(rule.to_arg());
+        }
+    }
+}
+
+impl BatchFileMatrixRule {
+    /// Returns the rule in the syntax of `--matrix-include`.
+    fn to_arg(&self) -> String {
+        let mut pairs = Vec::new();
+        if let Some(package) = &self.package {
+            pairs.push(format!("package={package}"));
+        }
+        if let Some(target) = &self.target {
+            pairs.push(format!("target={target}"));
+        }
+        if let Some(features) = &self.features {
+            pairs.push(format!("features={}", features.join(",")));
+        }
+        pairs.join(" ")
+    }
+}
+
+/// Reads the commands of a batch file. Files ending in `.json` are parsed as
+/// JSON, everything else as TOML.
+fn read_batch_file(path: &Path) -> CargoResult<Vec<(Option<String>, Vec<String>)>> {
+    parse_batch_file(path, &paths::read(path)?)
+}
+
+/// Parses the `contents` of the batch file at `path`, see
+/// [`read_batch_file`].
+fn parse_batch_file(
+    path: &Path,
+    contents: &str,
+) -> CargoResult<Vec<(Option<String>, Vec<String>)>> {
+    let file: BatchFile = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(contents)
+            .with_context(|| format!("failed to parse batch file `{}`", path.display()))?
+    } else {
+        toml::from_str(contents)
+            .with_context(|| format!("failed to parse batch file `{}`", path.display()))?
+    };
+
+    let base = path.parent().unwrap_or(Path::new(""));
+    let mut names = HashSet::new();
+    let mut commands = Vec::new();
+    for cmd in file.command {
+        if let Some(name) = &cmd.name {
+            if !names.insert(name.clone()) {
+                anyhow::bail!(
+                    "duplicate command name `{}` in batch file `{}`",
+                    name,
+                    path.display()
+                );
+            }
+        }
+        let args = cmd.to_args(base);
+        commands.push((cmd.name, args));
+    }
+    Ok(commands)
+}
+
+/// Parses the `--env KEY=VALUE` arguments of a command.
+fn parse_env_args(args: &[String]) -> CargoResult<BTreeMap<String, String>> {
+    args.iter()
+        .map(|arg| match arg.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+            _ => anyhow::bail!("invalid `--env` value `{}`, expected `KEY=VALUE`", arg),
+        })
+        .collect()
+}
+
+/// Configures `config` from the batch-level arguments. `extra_config` are
+/// `--config` arguments of a single command, which override the batch-level
+/// ones.
+fn config_configure(
+    config: &mut GlobalContext,
+    args: &ArgMatches,
+    extra_config: &[String],
+    cwd: &Path,
+) -> CliResult {
+    // Relative to `cwd` rather than the context's own directory, which
+    // differs for the contexts of `cargo-batch serve` clients.
+    let arg_target_dir = &args
+        .get_one::<String>("target-dir")
+        .map(|path| cwd.join(path));
+    let verbose = args.verbose();
+    // quiet is unusual because it is redefined in some subcommands in order
+    // to provide custom help text.
+    let quiet = args.flag("quiet");
+    let color = args.get_one::<String>("color").map(String::as_str);
+    let frozen = args.flag("frozen");
+    let locked = args.flag("locked");
+    let offline = args.flag("offline");
+    let mut unstable_flags = vec![];
+    if let Some(values) = args.get_many::<String>("unstable-features") {
+        unstable_flags.extend(values.cloned());
+    }
+    let mut config_args = vec![];
+    if let Some(values) = args.get_many::<String>("config") {
+        config_args.extend(values.cloned());
+    }
+    config_args.extend(extra_config.iter().cloned());
+    config.configure(
+        verbose,
+        quiet,
+        color,
+        frozen,
+        locked,
+        offline,
+        arg_target_dir,
+        &unstable_flags,
+        &config_args,
+    )?;
+    Ok(())
+}
+
+/// The batch-level options, given before the first `---`.
+fn build_global_cli() -> Command {
+    Command::new("cargo-batch")
         .arg_unit_graph()
         .arg_target_dir()
         .arg(
             opt(
-                "verbose",
-                "Use verbose output (-vv very verbose/build.rs output)",
+                "verbose",
+                "Use verbose output (-vv very verbose/build.rs output)",
+            )
+            .short('v')
+            .action(ArgAction::Count)
+            .global(true),
+        )
+        .arg_silent_suggestion()
+        .arg(flag("stdin", "Collect arguments from stdin").global(true))
+        .arg(
+            opt(
+                "artifact-manifest",
+                "Write the files produced by each command to a JSON file",
+            )
+            .value_name("PATH")
+            .global(true),
+        )
+        .arg(
+            opt(
+                "size-report",
+                "Write the ELF section sizes of each command's binaries to a JSON file",
+            )
+            .value_name("PATH")
+            .global(true),
+        )
+        .arg(
+            opt(
+                "size-baseline",
+                "Print how binary sizes changed since an earlier `--size-report`",
+            )
+            .value_name("PATH")
+            .requires("size-report")
+            .global(true),
+        )
+        .arg(
+            opt(
+                "size-max-growth",
+                "Fail if flash or RAM use grew by more than BYTES or PERCENT%",
+            )
+            .value_name("LIMIT")
+            .requires("size-baseline")
+            .global(true),
+        )
+        .arg(
+            opt("shard", "Only build the N-th of M groups of commands")
+                .value_name("N/M")
+                .global(true),
+        )
+        .arg(
+            opt(
+                "shard-timings",
+                "Balance shards by the durations in a `--timings=json` output",
+            )
+            .value_name("PATH")
+            .requires("shard")
+            .global(true),
+        )
+        .arg(
+            flag(
+                "dedup-report",
+                "Report how many units the batch shares between commands, without building",
+            )
+            .global(true),
+        )
+        .arg(
+            opt("batch-file", "Read commands from a TOML or JSON batch file")
+                .value_name("PATH")
+                .global(true),
+        )
+        .arg(
+            flag(
+                "gc-unused",
+                "Remove files in the build directories that the batch does not produce",
+            )
+            .conflicts_with("shard")
+            .global(true),
+        )
+        .arg(
+            flag(
+                "dry-run",
+                "Display what `--gc-unused` would remove without removing it or building",
+            )
+            .short('n')
+            .requires("gc-unused")
+            .global(true),
+        )
+        .arg(
+            flag(
+                "keep-going",
+                "Do not abort the batch as soon as one command fails",
+            )
+            .global(true),
+        )
+        .arg(
+            flag(
+                "uplift",
+                "Link the final artifacts of each command into `target/batch/<command>/`",
+            )
+            .global(true),
+        )
+        .arg(
+            opt(
+                "link-method",
+                "How uplifted and `--artifact-dir` artifacts refer to the build outputs",
             )
-            .short('v')
-            .action(ArgAction::Count)
+            .value_name("METHOD")
+            .value_parser(["hardlink", "symlink", "copy"])
             .global(true),
         )
-        .arg_silent_suggestion()
-        .arg(flag("stdin", "Collect arguments from stdin").global(true))
+        .arg(
+            opt(
+                "log-dir",
+                "Write the diagnostics of each command to `<DIR>/<command>.log`",
+            )
+            .value_name("DIR")
+            .global(true),
+        )
+        .arg_message_format()
+        .arg_timings()
         .arg(
             opt("color", "Coloring: auto, always, never")
                 .value_name("WHEN")
@@ -86,192 +2588,31 @@ fn main2(gctx: &mut GlobalContext) -> CliResult {
                 .action(ArgAction::Append)
                 .global(true),
         )
-        .try_get_matches_from(global_args)?;
-
-    config_configure(gctx, &global_args)?;
-    init_git_transports(gctx);
-
-    let unit_graph = global_args.flag("unit-graph");
-
-    struct CommandState<'a> {
-        ws: Workspace<'a>,
-        compile_opts: CompileOptions,
-    }
-
-    let subargs: Vec<Vec<String>> = if global_args.get_flag("stdin") {
-        io::stdin()
-            .lines()
-            .filter_map(|x| x.ok())
-            .map(|l| shell_words::split(&l).unwrap())
-            .collect()
-    } else {
-        subargs
-            .map(|x| x.iter().map(|x| x.clone()).collect::<Vec<_>>())
-            .collect()
-    };
-
-    let mut cmds = Vec::new();
-    for args in subargs {
-        let cli = build_cli();
-        let args = cli.try_get_matches_from([String::new()].into_iter().chain(args.into_iter()))?;
-        let (subcmd, args) = args.subcommand().unwrap();
-        match subcmd {
-            "build" => {
-                let ws = args.workspace(gctx)?;
-
-                let mut compile_opts = args.compile_options(
-                    gctx,
-                    CompileMode::Build,
-                    Some(&ws),
-                    ProfileChecking::Custom,
-                )?;
-                if let Some(out_dir) = args.value_of_path("artifact-dir", gctx) {
-                    compile_opts.build_config.export_dir = Some(out_dir);
-                }
-
-                //println!("compile opts: {:#?}", compile_opts);
-                cmds.push(CommandState { ws, compile_opts });
-            }
-            "check" => {
-                let ws = args.workspace(gctx)?;
-                // This is a legacy behavior that causes `cargo check` to pass `--test`.
-                let test = matches!(
-                    args.get_one::<String>("profile").map(String::as_str),
-                    Some("test")
-                );
-                let mode = CompileMode::Check { test };
-                let compile_opts =
-                    args.compile_options(gctx, mode, Some(&ws), ProfileChecking::LegacyTestOnly)?;
-
-                cmds.push(CommandState { ws, compile_opts });
-            }
-            "rustdoc" => {
-                let ws = args.workspace(gctx)?;
-                let output_format = if let Some(output_format) = args._value_of("output-format") {
-                    gctx.cli_unstable()
-                        .fail_if_stable_opt("--output-format", 12103)?;
-                    output_format.parse()?
-                } else {
-                    OutputFormat::Html
-                };
-
-                //panic!("output-format {output_format:?}");
-
-                let mut compile_opts = args.compile_options(
-                    gctx,
-                    CompileMode::Doc {
-                        deps: false,
-                        json: matches!(output_format, OutputFormat::Json),
-                    },
-                    Some(&ws),
-                    ProfileChecking::Custom,
-                )?;
-                if let Some(out_dir) = args.value_of_path("artifact-dir", gctx) {
-                    compile_opts.build_config.export_dir = Some(out_dir);
-                }
-                let target_args = values(args, "args");
-                compile_opts.target_rustdoc_args = if target_args.is_empty() {
-                    None
-                } else {
-                    Some(target_args)
-                };
-
-                //println!("compile opts: {:#?}", compile_opts);
-                cmds.push(CommandState { ws, compile_opts });
-            }
-            _ => unreachable!(),
-        }
-    }
-
-    let interner = UnitInterner::new();
-    let mut merged_bcx: Option<BuildContext<'_, '_>> = None;
-
-    for cmd in &mut cmds {
-        let export_dir = cmd.compile_opts.build_config.export_dir.take();
-
-        let mut bcx = cargo::ops::create_bcx(&cmd.ws, &cmd.compile_opts, &interner).unwrap();
-        if let Some(export_dir) = export_dir {
-            for root in &bcx.roots {
-                bcx.unit_export_dirs
-                    .insert(root.clone(), export_dir.clone());
-            }
-        }
-
-        if let Some(merged_bcx) = &mut merged_bcx {
-            // merge!!!
-            merged_bcx.unit_graph.extend(bcx.unit_graph);
-            merged_bcx.roots.extend(bcx.roots);
-            merged_bcx.unit_export_dirs.extend(bcx.unit_export_dirs);
-            merged_bcx.all_kinds.extend(bcx.all_kinds);
-            merged_bcx
-                .target_data
-                .target_config
-                .extend(bcx.target_data.target_config);
-            merged_bcx
-                .target_data
-                .target_info
-                .extend(bcx.target_data.target_info);
-            merged_bcx.packages.packages.extend(bcx.packages.packages);
-            merged_bcx
-                .packages
-                .sources
-                .borrow_mut()
-                .add_source_map(bcx.packages.sources.into_inner());
-            merged_bcx
-                .extra_compiler_args
-                .extend(bcx.extra_compiler_args);
-        } else {
-            merged_bcx = Some(bcx)
-        }
-    }
-
-    let mut bcx = merged_bcx.unwrap();
-    bcx.do_uplift = false;
-
-    if unit_graph {
-        unit_graph::emit_serialized_unit_graph(&bcx.roots, &bcx.unit_graph, bcx.ws.gctx())?;
-        return Ok(());
-    }
-
-    // util::profile disappeared between cargo 1.76 and cargo 1.78
-    // let _p = cargo::util::profile::start("compiling");
-    let cx = BuildRunner::new(&bcx)?;
-    let exec: Arc<dyn Executor> = Arc::new(DefaultExecutor);
-    cx.compile(&exec)?;
-
-    Ok(())
-}
-
-fn config_configure(config: &mut GlobalContext, args: &ArgMatches) -> CliResult {
-    let arg_target_dir = &args.value_of_path("target-dir", config);
-    let verbose = args.verbose();
-    // quiet is unusual because it is redefined in some subcommands in order
-    // to provide custom help text.
-    let quiet = args.flag("quiet");
-    let color = args.get_one::<String>("color").map(String::as_str);
-    let frozen = args.flag("frozen");
-    let locked = args.flag("locked");
-    let offline = args.flag("offline");
-    let mut unstable_flags = vec![];
-    if let Some(values) = args.get_many::<String>("unstable-features") {
-        unstable_flags.extend(values.cloned());
-    }
-    let mut config_args = vec![];
-    if let Some(values) = args.get_many::<String>("config") {
-        config_args.extend(values.cloned());
-    }
-    config.configure(
-        verbose,
-        quiet,
-        color,
-        frozen,
-        locked,
-        offline,
-        arg_target_dir,
-        &unstable_flags,
-        &config_args,
-    )?;
-    Ok(())
+        .arg(
+            opt(
+                "connect",
+                "Send the batch to the `cargo-batch serve` listening on this socket",
+            )
+            .value_name("SOCKET"),
+        )
+        .arg(
+            flag(
+                "watch",
+                "Rebuild the commands affected by changes to their sources until interrupted",
+            )
+            // Declared here rather than on `gc-unused`, which is global and
+            // would then refer to an argument `serve` doesn't have.
+            .conflicts_with_all(["connect", "unit-graph", "dedup-report", "gc-unused"]),
+        )
+        .subcommand(
+            Command::new("serve")
+                .about("Build batches requested on a Unix socket, keeping their state in memory")
+                .arg(
+                    opt("socket", "Path of the Unix socket to listen on")
+                        .value_name("PATH")
+                        .required(true),
+                ),
+        )
 }
 
 pub fn build_cli() -> Command {
@@ -303,6 +2644,24 @@ pub fn build_cli() -> Command {
                 .arg_profile("Build artifacts with the specified profile")
                 .arg_target_triple("Build for the target triple")
                 .arg_artifact_dir()
+                .arg(
+                    multi_opt(
+                        "export-format",
+                        "FORMAT",
+                        "Also export executables as raw binary, Intel HEX or UF2",
+                    )
+                    .value_parser(["bin", "hex", "uf2"])
+                    .requires("artifact-dir")
+                    .help_heading(heading::COMPILATION_OPTIONS),
+                )
+                .arg(
+                    opt("uf2-family", "Family ID of `--export-format uf2` files")
+                        .value_name("ID")
+                        .help_heading(heading::COMPILATION_OPTIONS),
+                )
+                .arg(arg_command_config())
+                .arg(arg_command_env())
+                .args(arg_matrix())
                 .arg_manifest_path()
                 .arg_lockfile_path()
                 .arg_ignore_rust_version()
@@ -310,6 +2669,45 @@ pub fn build_cli() -> Command {
                     "Run `<cyan,bold>cargo help build</>` for more detailed information.\n"
                 )),
         )
+        .subcommand(
+            subcommand("doc")
+                .about("Build a package's documentation")
+                .arg(flag(
+                    "open",
+                    "Opens the docs in a browser after the operation",
+                ))
+                .arg(flag(
+                    "no-deps",
+                    "Don't build documentation for dependencies",
+                ))
+                .arg(flag("document-private-items", "Document private items"))
+                .arg_package_spec(
+                    "Package to document",
+                    "Document all packages in the workspace",
+                    "Exclude packages from the build",
+                )
+                .arg_features()
+                .arg_targets_lib_bin_example(
+                    "Document only this package's library",
+                    "Document only the specified binary",
+                    "Document all binaries",
+                    "Document only the specified example",
+                    "Document all examples",
+                )
+                .arg_release("Build artifacts in release mode, with optimizations")
+                .arg_profile("Build artifacts with the specified profile")
+                .arg_target_triple("Build for the target triple")
+                .arg_artifact_dir()
+                .arg(arg_command_config())
+                .arg(arg_command_env())
+                .args(arg_matrix())
+                .arg_manifest_path()
+                .arg_lockfile_path()
+                .arg_ignore_rust_version()
+                .after_help(color_print::cstr!(
+                    "Run `<cyan,bold>cargo help doc</>` for more detailed information.\n"
+                )),
+        )
         .subcommand(
             subcommand("rustdoc")
                 .about("Build a package's documentation, using specified custom flags.")
@@ -343,6 +2741,9 @@ pub fn build_cli() -> Command {
                         .value_parser(OutputFormat::POSSIBLE_VALUES),
                 )
                 .arg_artifact_dir()
+                .arg(arg_command_config())
+                .arg(arg_command_env())
+                .args(arg_matrix())
                 .arg_manifest_path()
                 .arg_lockfile_path()
                 .arg_ignore_rust_version()
@@ -350,6 +2751,111 @@ pub fn build_cli() -> Command {
                     "Run `<cyan,bold>cargo help rustdoc</>` for more detailed information.\n"
                 )),
         )
+        .subcommand(
+            subcommand("test")
+                .about(
+                    "Execute all unit and integration tests and build examples of a local package",
+                )
+                .arg(
+                    Arg::new("TESTNAME")
+                        .action(ArgAction::Set)
+                        .help("If specified, only run tests containing this string in their names"),
+                )
+                .arg(
+                    Arg::new("args")
+                        .value_name("ARGS")
+                        .help("Arguments for the test binary")
+                        .num_args(0..)
+                        .last(true),
+                )
+                .arg(flag("no-run", "Compile, but don't run tests"))
+                .arg(flag("no-fail-fast", "Run all tests regardless of failure"))
+                .arg_future_incompat_report()
+                .arg_package_spec(
+                    "Package to run tests for",
+                    "Test all packages in the workspace",
+                    "Exclude packages from the test",
+                )
+                .arg_targets_all(
+                    "Test only this package's library",
+                    "Test only the specified binary",
+                    "Test all binaries",
+                    "Test only the specified example",
+                    "Test all examples",
+                    "Test only the specified test target",
+                    "Test all targets that have `test = true` set",
+                    "Test only the specified bench target",
+                    "Test all targets that have `bench = true` set",
+                    "Test all targets (does not include doctests)",
+                )
+                .arg(
+                    flag("doc", "Test only this library's documentation")
+                        .help_heading(heading::TARGET_SELECTION),
+                )
+                .arg_features()
+                .arg_release("Build artifacts in release mode, with optimizations")
+                .arg_profile("Build artifacts with the specified profile")
+                .arg_target_triple("Build for the target triple")
+                .arg(arg_command_config())
+                .arg(arg_command_env())
+                .args(arg_matrix())
+                .arg_manifest_path()
+                .arg_lockfile_path()
+                .arg_ignore_rust_version()
+                .after_help(color_print::cstr!(
+                    "Run `<cyan,bold>cargo help test</>` for more detailed information.\n"
+                )),
+        )
+        .subcommand(
+            subcommand("bench")
+                .about("Execute all benchmarks of a local package")
+                .arg(
+                    Arg::new("BENCHNAME").action(ArgAction::Set).help(
+                        "If specified, only run benches containing this string in their names",
+                    ),
+                )
+                .arg(
+                    Arg::new("args")
+                        .value_name("ARGS")
+                        .help("Arguments for the bench binary")
+                        .num_args(0..)
+                        .last(true),
+                )
+                .arg(flag("no-run", "Compile, but don't run benchmarks"))
+                .arg(flag(
+                    "no-fail-fast",
+                    "Run all benchmarks regardless of failure",
+                ))
+                .arg_package_spec(
+                    "Package to run benchmarks for",
+                    "Benchmark all packages in the workspace",
+                    "Exclude packages from the benchmark",
+                )
+                .arg_targets_all(
+                    "Benchmark only this package's library",
+                    "Benchmark only the specified binary",
+                    "Benchmark all binaries",
+                    "Benchmark only the specified example",
+                    "Benchmark all examples",
+                    "Benchmark only the specified test target",
+                    "Benchmark all targets that have `test = true` set",
+                    "Benchmark only the specified bench target",
+                    "Benchmark all targets that have `bench = true` set",
+                    "Benchmark all targets",
+                )
+                .arg_features()
+                .arg_profile("Build artifacts with the specified profile")
+                .arg_target_triple("Build for the target triple")
+                .arg(arg_command_config())
+                .arg(arg_command_env())
+                .args(arg_matrix())
+                .arg_manifest_path()
+                .arg_lockfile_path()
+                .arg_ignore_rust_version()
+                .after_help(color_print::cstr!(
+                    "Run `<cyan,bold>cargo help bench</>` for more detailed information.\n"
+                )),
+        )
         .subcommand(
             subcommand("check")
                 // subcommand aliases are handled in aliased_command()
@@ -383,6 +2889,9 @@ pub fn build_cli() -> Command {
                 .arg_target_dir()
                 .arg_unit_graph()
                 .arg_timings()
+                .arg(arg_command_config())
+                .arg(arg_command_env())
+                .args(arg_matrix())
                 .arg_manifest_path()
                 .arg_lockfile_path()
                 .arg_ignore_rust_version()
@@ -392,6 +2901,69 @@ pub fn build_cli() -> Command {
         )
 }
 
+/// `--config` of a single command, on top of the batch-level `--config`s.
+fn arg_command_config() -> Arg {
+    multi_opt(
+        "config",
+        "KEY=VALUE",
+        "Override a configuration value for this command",
+    )
+}
+
+/// `--env` of a single command, see [`BuildConfig::env`].
+fn arg_command_env() -> Arg {
+    multi_opt(
+        "env",
+        "KEY=VALUE",
+        "Set an environment variable for the compiler and build scripts of this command",
+    )
+}
+
+/// Help heading of the options that expand a single command into a [`Matrix`].
+const MATRIX_OPTIONS: &str = "Matrix Options";
+
+/// The options that expand a single command into a [`Matrix`].
+fn arg_matrix() -> Vec<Arg> {
+    vec![
+        multi_opt(
+            "matrix-package",
+            "SPEC",
+            "Build the command once for each of these packages",
+        )
+        .help_heading(MATRIX_OPTIONS),
+        multi_opt(
+            "matrix-target",
+            "TRIPLE",
+            "Build the command once for each of these target triples",
+        )
+        .help_heading(MATRIX_OPTIONS),
+        multi_opt(
+            "matrix-features",
+            "FEATURES",
+            "Build the command once for each of these space or comma separated lists of features",
+        )
+        .help_heading(MATRIX_OPTIONS),
+        flag(
+            "feature-powerset",
+            "Build the command once for each subset of the package's features",
+        )
+        .conflicts_with("matrix-features")
+        .help_heading(MATRIX_OPTIONS),
+        multi_opt(
+            "matrix-include",
+            "RULE",
+            "Also build this combination, like `target=TRIPLE features=A,B`",
+        )
+        .help_heading(MATRIX_OPTIONS),
+        multi_opt(
+            "matrix-exclude",
+            "RULE",
+            "Do not build the combinations matching this rule, like `target=TRIPLE`",
+        )
+        .help_heading(MATRIX_OPTIONS),
+    ]
+}
+
 fn setup_logger() {
     let env = tracing_subscriber::EnvFilter::from_env("CARGO_LOG");
 
@@ -434,3 +3006,374 @@ fn init_git_transports(config: &GlobalContext) {
         git2_curl::register(handle);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        assign_shards, baseline_binaries, parse_batch_file, powerset, read_command_lines,
+        read_frame, write_frame, Matrix, MatrixCell, SizeBinary, SizeCommand, SizeGrowth,
+        SizeReport,
+    };
+    use std::collections::{BTreeSet, HashSet};
+    use std::path::Path;
+
+    fn units(units: &[u32]) -> HashSet<u32> {
+        units.iter().copied().collect()
+    }
+
+    #[test]
+    fn shards_stay_when_other_commands_change() {
+        let keys: Vec<String> = (0..20).map(|i| format!("build -p p{i}")).collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let cmd_units: Vec<HashSet<u32>> = (0..20).map(|i| units(&[0, i + 1])).collect();
+        let shards = assign_shards(&keys, &cmd_units, 4, |_| 1.0);
+
+        // Reordering the commands moves none of them.
+        let reversed = assign_shards(
+            &keys.iter().rev().copied().collect::<Vec<_>>(),
+            &cmd_units.iter().rev().cloned().collect::<Vec<_>>(),
+            4,
+            |_| 1.0,
+        );
+        assert!(shards.iter().eq(reversed.iter().rev()));
+
+        // Dropping a command only moves commands near the cost limit.
+        let rest = assign_shards(&keys[1..], &cmd_units[1..], 4, |_| 1.0);
+        let moved = shards[1..]
+            .iter()
+            .zip(&rest)
+            .filter(|(a, b)| a != b)
+            .count();
+        assert!(moved <= 2, "{moved} commands moved");
+    }
+
+    #[test]
+    fn shards_are_balanced() {
+        let keys: Vec<String> = (0..40).map(|i| format!("build -p p{i}")).collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let cmd_units: Vec<HashSet<u32>> = (0..40).map(|i| units(&[i])).collect();
+        let shards = assign_shards(&keys, &cmd_units, 4, |_| 1.0);
+        for shard in 0..4 {
+            let count = shards.iter().filter(|&&s| s == shard).count();
+            // An even share is 10 commands, plus `SHARD_SLACK`.
+            assert!(count <= 12, "shard {shard} has {count} commands");
+        }
+    }
+
+    fn size_report(commands: &[(&str, &[(&str, u64)])]) -> SizeReport {
+        SizeReport {
+            commands: commands
+                .iter()
+                .map(|&(command, binaries)| SizeCommand {
+                    command: command.to_string(),
+                    binaries: binaries
+                        .iter()
+                        .map(|&(target, text)| SizeBinary {
+                            target: target.to_string(),
+                            path: format!("target/{target}").into(),
+                            sections: [(".text".to_string(), text)].into_iter().collect(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn size_growth_parses_bytes_and_percentages() {
+        assert!(matches!(
+            SizeGrowth::parse("1024"),
+            Ok(SizeGrowth::Bytes(1024))
+        ));
+        assert!(matches!(SizeGrowth::parse("2.5%"), Ok(SizeGrowth::Percent(p)) if p == 2.5));
+        for invalid in ["", "%", "-1", "-1%", "inf%", "NaN%", "2 %", "1kb", "x%"] {
+            assert!(SizeGrowth::parse(invalid).is_err(), "`{invalid}` parsed");
+        }
+    }
+
+    #[test]
+    fn size_growth_limits() {
+        let bytes = SizeGrowth::Bytes(100);
+        assert!(!bytes.exceeded(1000, 1100));
+        assert!(bytes.exceeded(1000, 1101));
+        assert!(!bytes.exceeded(1000, 500));
+
+        let percent = SizeGrowth::Percent(10.0);
+        assert!(!percent.exceeded(1000, 1100));
+        assert!(percent.exceeded(1000, 1101));
+        assert!(!percent.exceeded(1000, 900));
+        // Any growth of an empty binary exceeds a percentage.
+        assert!(!percent.exceeded(0, 0));
+        assert!(percent.exceeded(0, 1));
+    }
+
+    #[test]
+    fn baseline_binaries_by_command_and_target() {
+        let baseline = size_report(&[
+            ("build", &[("app", 10), ("tool", 20)]),
+            ("release", &[("app", 30)]),
+        ]);
+        let binaries = baseline_binaries(&baseline);
+        assert_eq!(binaries.len(), 3);
+        assert_eq!(binaries[&("build", "app")].flash(), 10);
+        assert_eq!(binaries[&("build", "tool")].flash(), 20);
+        assert_eq!(binaries[&("release", "app")].flash(), 30);
+        assert!(!binaries.contains_key(&("release", "tool")));
+    }
+
+    #[test]
+    fn frames_roundtrip() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b'o', b"out\n").unwrap();
+        write_frame(&mut stream, b'e', b"").unwrap();
+        write_frame(&mut stream, b'x', &101i32.to_be_bytes()).unwrap();
+
+        let mut stream = &stream[..];
+        assert_eq!(
+            read_frame(&mut stream).unwrap(),
+            Some((b'o', b"out\n".to_vec()))
+        );
+        assert_eq!(read_frame(&mut stream).unwrap(), Some((b'e', Vec::new())));
+        assert_eq!(
+            read_frame(&mut stream).unwrap(),
+            Some((b'x', 101i32.to_be_bytes().to_vec()))
+        );
+        assert_eq!(read_frame(&mut stream).unwrap(), None);
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b'o', b"output").unwrap();
+        stream.pop();
+        assert!(read_frame(&stream[..]).is_err());
+        // Also within the header.
+        assert!(read_frame(&stream[..3]).is_err());
+    }
+
+    fn features(features: &[&str]) -> BTreeSet<String> {
+        features.iter().map(|f| f.to_string()).collect()
+    }
+
+    fn matrix(packages: &[&str], targets: &[&str]) -> Matrix {
+        Matrix {
+            packages: packages.iter().map(|p| p.to_string()).collect(),
+            targets: targets.iter().map(|t| t.to_string()).collect(),
+            features: Vec::new(),
+            feature_powerset: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+
+    fn cell(rule: &str) -> MatrixCell {
+        MatrixCell::parse(rule).unwrap()
+    }
+
+    #[test]
+    fn powerset_is_every_subset_smallest_first() {
+        let sets = powerset(&["a".to_string(), "b".to_string(), "c".to_string()]);
+        assert_eq!(
+            sets,
+            [
+                features(&[]),
+                features(&["a"]),
+                features(&["b"]),
+                features(&["c"]),
+                features(&["a", "b"]),
+                features(&["a", "c"]),
+                features(&["b", "c"]),
+                features(&["a", "b", "c"]),
+            ]
+        );
+        assert_eq!(powerset(&[]), [features(&[])]);
+        let twelve: Vec<_> = (0..12).map(|i| format!("f{i}")).collect();
+        assert_eq!(powerset(&twelve).len(), 4096);
+    }
+
+    #[test]
+    fn matrix_is_the_product_of_its_axes() {
+        let cells = matrix(&["p", "q"], &["t1", "t2"])
+            .cells(|_| unreachable!())
+            .unwrap();
+        assert_eq!(
+            cells,
+            [
+                cell("package=p target=t1"),
+                cell("package=p target=t2"),
+                cell("package=q target=t1"),
+                cell("package=q target=t2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn matrix_excludes_and_includes_cells() {
+        let mut m = matrix(&["p", "q"], &["t1", "t2"]);
+        m.features = vec![features(&[]), features(&["x"])];
+        // Rules match on the axes they set.
+        m.exclude = vec![cell("package=q target=t2"), cell("features=x")];
+        // Included cells are only added once.
+        m.include = vec![cell("package=p target=t1"), cell("package=r features=x,y")];
+        let cells = m.cells(|_| unreachable!()).unwrap();
+        assert_eq!(
+            cells,
+            [
+                cell("package=p target=t1 features="),
+                cell("package=p target=t2 features="),
+                cell("package=q target=t1 features="),
+                cell("package=p target=t1"),
+                cell("package=r features=x,y"),
+            ]
+        );
+    }
+
+    #[test]
+    fn matrix_feature_powerset_is_per_package() {
+        let mut m = matrix(&["p", "q"], &[]);
+        m.feature_powerset = true;
+        m.exclude = vec![cell("package=q features=")];
+        let cells = m
+            .cells(|package| {
+                Ok(match package {
+                    Some("p") => vec![features(&[]), features(&["a"])],
+                    _ => vec![features(&[]), features(&["b"]), features(&["c"])],
+                })
+            })
+            .unwrap();
+        assert_eq!(
+            cells,
+            [
+                cell("package=p features="),
+                cell("package=p features=a"),
+                cell("package=q features=b"),
+                cell("package=q features=c"),
+            ]
+        );
+    }
+
+    #[test]
+    fn matrix_rules_must_name_an_axis() {
+        assert_eq!(
+            cell("package=p  features=a,b"),
+            MatrixCell {
+                package: Some("p".to_string()),
+                target: None,
+                features: Some(features(&["a", "b"])),
+            }
+        );
+        assert!(MatrixCell::parse("profile=release").is_err());
+        assert!(MatrixCell::parse("target").is_err());
+    }
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn batch_file_commands_become_command_lines() {
+        let commands = parse_batch_file(
+            Path::new("ci/batch.toml"),
+            r#"
+                [[command]]
+                name = "fw"
+                subcommand = "build"
+                package = ["fw", "boot"]
+                features = ["defmt", "log"]
+                no-default-features = true
+                target = "thumbv7em-none-eabihf"
+                profile = "release"
+                artifact-dir = "out"
+                config = ["opt-level=2"]
+                env = { B = "2", A = "1" }
+                args = ["--locked"]
+
+                [[command]]
+                subcommand = "check"
+                manifest-path = "../Cargo.toml"
+                feature-powerset = true
+
+                [command.matrix]
+                target = ["t1", "t2"]
+                features = [["a"], ["a", "b"]]
+                exclude = [{ target = "t2", features = ["a"] }]
+            "#,
+        )
+        .unwrap();
+        let out = Path::new("ci").join("out").display().to_string();
+        let manifest = Path::new("ci").join("../Cargo.toml").display().to_string();
+        let mut fw = args(
+            "build --package fw --package boot --features defmt,log --no-default-features \
+             --target thumbv7em-none-eabihf --profile release --artifact-dir",
+        );
+        fw.push(out);
+        fw.extend(args("--config opt-level=2 --env A=1 --env B=2 --locked"));
+        let mut check = args("check --manifest-path");
+        check.push(manifest);
+        check.extend(args(
+            "--feature-powerset --matrix-target t1 --matrix-target t2 \
+             --matrix-features a --matrix-features a,b",
+        ));
+        check.extend([
+            "--matrix-exclude".to_string(),
+            "target=t2 features=a".to_string(),
+        ]);
+        assert_eq!(commands, [(Some("fw".to_string()), fw), (None, check)]);
+    }
+
+    #[test]
+    fn batch_files_can_be_json() {
+        let commands = parse_batch_file(
+            Path::new("batch.json"),
+            r#"{ "command": [{ "subcommand": "test", "package": "a" }] }"#,
+        )
+        .unwrap();
+        assert_eq!(commands, [(None, args("test --package a"))]);
+    }
+
+    #[test]
+    fn invalid_batch_files() {
+        let path = Path::new("batch.toml");
+        let err = |contents| parse_batch_file(path, contents).unwrap_err().to_string();
+        assert_eq!(
+            parse_batch_file(path, "").unwrap(),
+            Vec::<(Option<String>, Vec<String>)>::new()
+        );
+        assert_eq!(
+            err("[[command]]\nsubcommand = 'build'\nbogus = 1"),
+            "failed to parse batch file `batch.toml`"
+        );
+        assert_eq!(
+            err("[[command]]\nname = 'a'\nsubcommand = 'build'\n\
+                 [[command]]\nname = 'a'\nsubcommand = 'check'"),
+            "duplicate command name `a` in batch file `batch.toml`"
+        );
+    }
+
+    #[test]
+    fn command_lines_are_split_like_a_shell() {
+        let input = "build -p a\n\n  # comment\n\tcheck -p 'b c' --features \"x y\"  \n";
+        let commands = read_command_lines(input.as_bytes()).unwrap();
+        assert_eq!(
+            commands,
+            [
+                (None, args("build -p a")),
+                (
+                    None,
+                    ["check", "-p", "b c", "--features", "x y"]
+                        .map(str::to_string)
+                        .to_vec()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn unbalanced_quotes_name_the_line() {
+        let err = read_command_lines("build\ncheck -p 'a\n".as_bytes()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to parse line 2 of stdin: `check -p 'a`"
+        );
+    }
+}
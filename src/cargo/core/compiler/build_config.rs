@@ -6,6 +6,7 @@ use anyhow::{bail, Context as _};
 use cargo_util::ProcessBuilder;
 use serde::ser;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::rc::Rc;
 use std::thread::available_parallelism;
@@ -50,6 +51,11 @@ pub struct BuildConfig {
     pub timing_outputs: Vec<TimingOutput>,
     /// Output SBOM precursor files.
     pub sbom: bool,
+    /// Extra environment variables for the compiler and build scripts of
+    /// every unit, see [`Unit::env`](crate::core::compiler::Unit).
+    ///
+    /// Only set by the `--env` option of `cargo-batch` commands.
+    pub env: BTreeMap<String, String>,
 }
 
 fn default_parallelism() -> CargoResult<u32> {
@@ -129,6 +135,7 @@ impl BuildConfig {
             future_incompat_report: false,
             timing_outputs: Vec::new(),
             sbom,
+            env: BTreeMap::new(),
         })
     }
 
@@ -286,3 +293,38 @@ pub enum TimingOutput {
     /// Machine-readable JSON (unstable)
     Json,
 }
+
+/// How uplifted and exported files refer to the files the compiler produced.
+#[derive(Clone, Copy, PartialEq, Debug, Eq, Default)]
+pub enum LinkMethod {
+    /// Hard link, falling back to a copy where that is not possible.
+    #[default]
+    Hardlink,
+    /// Symbolic link to the compiler's output.
+    Symlink,
+    /// Independent copy, which later builds never modify in place.
+    Copy,
+}
+
+/// A format that exported executables are converted to, next to the
+/// exported file itself.
+#[derive(Clone, Copy, PartialEq, Debug, Eq, Hash)]
+pub enum ExportFormat {
+    /// Raw memory image, like `objcopy -O binary`.
+    Bin,
+    /// Intel HEX, like `objcopy -O ihex`.
+    Hex,
+    /// UF2 for USB mass storage bootloaders, tagged with a family ID.
+    Uf2 { family_id: u32 },
+}
+
+impl ExportFormat {
+    /// The file extension of the converted file.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Bin => "bin",
+            ExportFormat::Hex => "hex",
+            ExportFormat::Uf2 { .. } => "uf2",
+        }
+    }
+}
@@ -1,16 +1,18 @@
 //! [`BuildContext`] is a (mostly) static information about a build task.
 
 use crate::core::compiler::unit_graph::UnitGraph;
-use crate::core::compiler::{BuildConfig, CompileKind, Unit};
+use crate::core::compiler::{BuildConfig, CompileKind, ExportFormat, LinkMethod, Unit};
 use crate::core::profiles::Profiles;
 use crate::core::PackageSet;
 use crate::core::Workspace;
 use crate::util::context::GlobalContext;
 use crate::util::errors::CargoResult;
 use crate::util::interning::InternedString;
+use crate::util::machine_message::BatchCommand;
 use crate::util::Rustc;
 use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::rc::Rc;
 
 mod target_info;
 pub use self::target_info::{
@@ -82,6 +84,83 @@ pub struct BuildContext<'a, 'gctx> {
 
     pub do_uplift: bool,
     pub unit_export_dirs: HashMap<Unit, PathBuf>,
+    /// Formats the exported executables of a unit are converted to, see
+    /// [`OutputFile::export_formats`](crate::core::compiler::OutputFile::export_formats).
+    pub unit_export_formats: HashMap<Unit, Vec<ExportFormat>>,
+    /// Documentation units that merged commands want in another docs
+    /// directory than the one they are documented into: an `--artifact-dir`,
+    /// or the unit's `target/doc` if `None`.
+    ///
+    /// Their docs are copied there after the build, see
+    /// [`BuildContext::unit_export_dirs`].
+    pub unit_doc_copies: HashMap<Unit, Vec<Option<PathBuf>>>,
+
+    /// Uplift the root units of each merged command into
+    /// `target/batch/<command>/`, named after [`BuildContext::command_names`]
+    /// or the index of the command.
+    pub uplift_commands: bool,
+
+    /// How uplifted and exported files are linked to the compiler's outputs.
+    pub link_method: LinkMethod,
+
+    /// Record the source files of every local unit after the build, see
+    /// [`BuildRunner::unit_sources`](crate::core::compiler::BuildRunner::unit_sources).
+    pub collect_unit_sources: bool,
+
+    /// Write the diagnostics and build script output of each merged command
+    /// to `<dir>/<command>.log` instead of the console, see
+    /// [`CommandLogs`](crate::core::compiler::CommandLogs).
+    pub command_log_dir: Option<PathBuf>,
+
+    /// Workspace, profiles and build configuration of units that were
+    /// requested with a different configuration than [`BuildContext::ws`],
+    /// [`BuildContext::profiles`] and [`BuildContext::build_config`].
+    ///
+    /// This is only populated when several builds are merged into one, like
+    /// `cargo-batch` does. Use [`BuildContext::ws_for`],
+    /// [`BuildContext::profiles_for`] and [`BuildContext::build_config_for`]
+    /// to look up a unit's configuration.
+    ///
+    /// A unit shared by several commands gets the configuration of the first
+    /// one. Their profiles have the same directory name, see
+    /// [`Profile::dir_name`](crate::core::profiles::Profile::dir_name), so
+    /// the unit is built in the same place whichever command comes first.
+    pub unit_configs: HashMap<Unit, Rc<UnitConfig<'a, 'gctx>>>,
+
+    /// The command lines of the commands merged into this build, if any.
+    pub command_lines: Vec<String>,
+
+    /// For each root unit, the indices into [`BuildContext::command_lines`]
+    /// of the commands that requested it.
+    pub root_commands: HashMap<Unit, Vec<usize>>,
+
+    /// The names of the merged commands, parallel to
+    /// [`BuildContext::command_lines`].
+    pub command_names: Vec<Option<String>>,
+
+    /// The index of each merged command in the whole batch, parallel to
+    /// [`BuildContext::command_lines`]. It differs from the position when
+    /// only some commands of a batch are built, see
+    /// [`Batch::command_indices`](crate::ops::batch::Batch::command_indices).
+    pub command_indices: Vec<usize>,
+
+    /// Indices into [`BuildContext::command_lines`] of the commands that
+    /// failed to set up with `--keep-going`, which have no units.
+    pub failed_commands: HashSet<usize>,
+
+    /// For every unit, the indices into [`BuildContext::command_lines`] of the
+    /// commands whose roots depend on it.
+    pub unit_commands: HashMap<Unit, Vec<usize>>,
+}
+
+/// The workspace, profiles and build configuration a [`Unit`] was requested
+/// with.
+///
+/// See [`BuildContext::unit_configs`].
+pub struct UnitConfig<'a, 'gctx> {
+    pub ws: &'a Workspace<'gctx>,
+    pub profiles: Profiles,
+    pub build_config: &'a BuildConfig,
 }
 
 impl<'a, 'gctx> BuildContext<'a, 'gctx> {
@@ -117,6 +196,19 @@ impl<'a, 'gctx> BuildContext<'a, 'gctx> {
             all_kinds,
             do_uplift: true,
             unit_export_dirs: HashMap::new(),
+            unit_export_formats: HashMap::new(),
+            unit_doc_copies: HashMap::new(),
+            uplift_commands: false,
+            link_method: LinkMethod::default(),
+            collect_unit_sources: false,
+            command_log_dir: None,
+            unit_configs: HashMap::new(),
+            command_lines: Vec::new(),
+            root_commands: HashMap::new(),
+            command_names: Vec::new(),
+            command_indices: Vec::new(),
+            failed_commands: HashSet::new(),
+            unit_commands: HashMap::new(),
         })
     }
 
@@ -140,6 +232,78 @@ impl<'a, 'gctx> BuildContext<'a, 'gctx> {
         self.build_config.jobs
     }
 
+    /// Workspace the given unit was requested from.
+    ///
+    /// Paths and metadata of workspace members are relative to its root.
+    pub fn ws_for(&self, unit: &Unit) -> &'a Workspace<'gctx> {
+        match self.unit_configs.get(unit) {
+            Some(config) => config.ws,
+            None => self.ws,
+        }
+    }
+
+    /// Profiles used to build the given unit.
+    pub fn profiles_for(&self, unit: &Unit) -> &Profiles {
+        match self.unit_configs.get(unit) {
+            Some(config) => &config.profiles,
+            None => &self.profiles,
+        }
+    }
+
+    /// Build configuration the given unit was requested with.
+    pub fn build_config_for(&self, unit: &Unit) -> &BuildConfig {
+        match self.unit_configs.get(unit) {
+            Some(config) => config.build_config,
+            None => self.build_config,
+        }
+    }
+
+    /// The command lines that requested the given root unit.
+    ///
+    /// This is empty unless several commands were merged into this build.
+    pub fn command_lines_for(&self, unit: &Unit) -> Vec<&str> {
+        self.root_commands
+            .get(unit)
+            .into_iter()
+            .flatten()
+            .map(|&i| self.command_lines[i].as_str())
+            .collect()
+    }
+
+    /// The directory name of the given merged command below `target/batch/`,
+    /// see [`BuildContext::uplift_commands`].
+    pub fn command_dir_name(&self, index: usize) -> String {
+        match self.command_names.get(index).cloned().flatten() {
+            Some(name) => name,
+            None => self.command_indices[index].to_string(),
+        }
+    }
+
+    /// How the given merged command is referred to in output: its name if it
+    /// has one, otherwise its command line.
+    pub fn command_label(&self, index: usize) -> &str {
+        match &self.command_names[index] {
+            Some(name) => name,
+            None => &self.command_lines[index],
+        }
+    }
+
+    /// The merged commands whose roots depend on the given unit, used to tag
+    /// JSON messages.
+    ///
+    /// This is empty unless several commands were merged into this build.
+    pub fn batch_commands_for(&self, unit: &Unit) -> Vec<BatchCommand> {
+        self.unit_commands
+            .get(unit)
+            .into_iter()
+            .flatten()
+            .map(|&index| BatchCommand {
+                index: self.command_indices[index],
+                name: self.command_names.get(index).cloned().flatten(),
+            })
+            .collect()
+    }
+
     /// Extra compiler args for either `rustc` or `rustdoc`.
     ///
     /// As of now, these flags come from the trailing args of either
@@ -19,6 +19,7 @@ use cargo_util::{paths, ProcessBuilder};
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::collections::hash_map::{Entry, HashMap};
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::str::{self, FromStr};
@@ -905,9 +906,10 @@ fn rustflags_from_build(gctx: &GlobalContext, flag: Flags) -> CargoResult<Option
 }
 
 /// Collection of information about `rustc` and the host and target.
+#[derive(Clone)]
 pub struct RustcTargetData<'gctx> {
     /// Information about `rustc` itself.
-    pub rustc: Rustc,
+    pub rustc: Rc<Rustc>,
 
     /// Config
     pub gctx: &'gctx GlobalContext,
@@ -927,6 +929,11 @@ pub struct RustcTargetData<'gctx> {
     pub target_config: HashMap<CompileTarget, TargetConfig>,
     /// Information about the target platform that we're building for.
     pub target_info: HashMap<CompileTarget, TargetInfo>,
+
+    /// Extra environment variables for every unit built with this data.
+    ///
+    /// See [`BuildConfig::env`](crate::core::compiler::BuildConfig::env).
+    pub env: Rc<BTreeMap<String, String>>,
 }
 
 impl<'gctx> RustcTargetData<'gctx> {
@@ -974,15 +981,27 @@ impl<'gctx> RustcTargetData<'gctx> {
         };
 
         let mut res = RustcTargetData {
-            rustc,
+            rustc: Rc::new(rustc),
             gctx,
             requested_kinds: requested_kinds.into(),
             host_config,
             host_info,
             target_config,
             target_info,
+            env: Rc::default(),
         };
 
+        res.merge_workspace_kinds(ws)?;
+
+        Ok(res)
+    }
+
+    /// Makes sure information is available for every kind the members of `ws`
+    /// may be built for.
+    ///
+    /// This lets target data probed for one workspace be reused for another
+    /// one with the same requested kinds.
+    pub fn merge_workspace_kinds(&mut self, ws: &Workspace<'gctx>) -> CargoResult<()> {
         // Get all kinds we currently know about.
         //
         // For now, targets can only ever come from the root workspace
@@ -997,21 +1016,22 @@ impl<'gctx> RustcTargetData<'gctx> {
                 .iter()
                 .filter_map(|d| d.artifact()?.target()?.to_compile_kind())
         }
-        let all_kinds = requested_kinds
-            .iter()
-            .copied()
+        let all_kinds = self
+            .requested_kinds
+            .clone()
+            .into_iter()
             .chain(ws.members().flat_map(|p| {
                 p.manifest()
                     .default_kind()
                     .into_iter()
                     .chain(p.manifest().forced_kind())
                     .chain(artifact_targets(p))
-            }));
+            }))
+            .collect::<Vec<_>>();
         for kind in all_kinds {
-            res.merge_compile_kind(kind)?;
+            self.merge_compile_kind(kind)?;
         }
-
-        Ok(res)
+        Ok(())
     }
 
     /// Insert `kind` into our `target_info` and `target_config` members if it isn't present yet.
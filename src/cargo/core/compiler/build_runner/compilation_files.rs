@@ -10,8 +10,9 @@ use lazycell::LazyCell;
 use tracing::debug;
 
 use super::{BuildContext, BuildRunner, CompileKind, FileFlavor, Layout};
-use crate::core::compiler::{CompileMode, CompileTarget, CrateType, FileType, Unit};
+use crate::core::compiler::{CompileMode, CompileTarget, CrateType, ExportFormat, FileType, Unit};
 use crate::core::{Target, TargetKind, Workspace};
+use crate::util::interning::InternedString;
 use crate::util::{self, CargoResult, StableHasher};
 
 /// This is a generic version number that can be changed to make
@@ -119,16 +120,34 @@ pub struct CompilationFiles<'a, 'gctx> {
     pub(super) host: Layout,
     /// The target directory layout for the target (if different from then host).
     pub(super) target: HashMap<CompileTarget, Layout>,
+    /// Layouts for units whose profile uses another directory name than the
+    /// requested profile of the build, keyed by directory name and kind.
+    pub(super) profile_layouts: HashMap<(InternedString, CompileKind), Layout>,
+    /// The profile directory name of units that use one of `profile_layouts`.
+    unit_dir_names: HashMap<Unit, InternedString>,
     /// do uplift in target/. true for regular cargo, false for cargo-batch.
     do_uplift: bool,
     /// Additional directory to include a copy of the outputs.
     export_dir: Option<PathBuf>,
     /// Per-unit export dirs
     unit_export_dirs: HashMap<Unit, PathBuf>,
+    /// Per-unit formats exported executables are converted to.
+    unit_export_formats: HashMap<Unit, Vec<ExportFormat>>,
+    /// For root units of merged commands, the commands that requested them
+    /// and the directories their outputs are uplifted to, see
+    /// [`BuildContext::uplift_commands`].
+    ///
+    /// [`BuildContext::uplift_commands`]: crate::core::compiler::BuildContext::uplift_commands
+    command_dirs: HashMap<Unit, Vec<(usize, PathBuf)>>,
     /// The root targets requested by the user on the command line (does not
     /// include dependencies).
     roots: Vec<Unit>,
     ws: &'a Workspace<'gctx>,
+    /// Workspaces of units that were requested from another workspace than
+    /// `ws`, see [`BuildContext::ws_for`].
+    ///
+    /// [`BuildContext::ws_for`]: crate::core::compiler::BuildContext::ws_for
+    unit_workspaces: HashMap<Unit, &'a Workspace<'gctx>>,
     /// Metadata hash to use for each unit.
     metas: HashMap<Unit, Metadata>,
     /// For each Unit, a list all files produced.
@@ -145,6 +164,12 @@ pub struct OutputFile {
     pub hardlink: Option<PathBuf>,
     /// If `--artifact-dir` is specified, the absolute path to the exported file.
     pub export_path: Option<PathBuf>,
+    /// With `cargo-batch --uplift`, the commands that requested the file and
+    /// where it is uplifted for each of them.
+    pub command_paths: Vec<(usize, PathBuf)>,
+    /// Conversions of the exported executable to other formats, and where
+    /// they are written, next to [`OutputFile::export_path`].
+    pub export_formats: Vec<(ExportFormat, PathBuf)>,
     /// Type of the file (library / debug symbol / else).
     pub flavor: FileFlavor,
 }
@@ -164,7 +189,16 @@ impl<'a, 'gctx: 'a> CompilationFiles<'a, 'gctx> {
         build_runner: &BuildRunner<'a, 'gctx>,
         host: Layout,
         target: HashMap<CompileTarget, Layout>,
+        profile_layouts: HashMap<(InternedString, CompileKind), Layout>,
     ) -> CompilationFiles<'a, 'gctx> {
+        let bcx = build_runner.bcx;
+        let dest = bcx.profiles.get_dir_name();
+        let unit_dir_names = bcx
+            .unit_configs
+            .iter()
+            .map(|(unit, config)| (unit.clone(), config.profiles.get_dir_name()))
+            .filter(|(_, dir_name)| *dir_name != dest)
+            .collect();
         let mut metas = HashMap::new();
         for unit in &build_runner.bcx.roots {
             metadata_of(unit, build_runner, &mut metas);
@@ -174,12 +208,38 @@ impl<'a, 'gctx: 'a> CompilationFiles<'a, 'gctx> {
             .cloned()
             .map(|unit| (unit, LazyCell::new()))
             .collect();
+        let command_dirs = if bcx.uplift_commands {
+            let batch_dir = host.root().join("batch");
+            bcx.root_commands
+                .iter()
+                .map(|(unit, commands)| {
+                    let dirs = commands
+                        .iter()
+                        .map(|&i| (i, batch_dir.join(bcx.command_dir_name(i))))
+                        .collect();
+                    (unit.clone(), dirs)
+                })
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        let unit_workspaces = build_runner
+            .bcx
+            .unit_configs
+            .iter()
+            .map(|(unit, config)| (unit.clone(), config.ws))
+            .collect();
         CompilationFiles {
             ws: build_runner.bcx.ws,
+            unit_workspaces,
             host,
             target,
+            profile_layouts,
+            unit_dir_names,
             export_dir: build_runner.bcx.build_config.export_dir.clone(),
             unit_export_dirs: build_runner.bcx.unit_export_dirs.clone(),
+            unit_export_formats: build_runner.bcx.unit_export_formats.clone(),
+            command_dirs,
             do_uplift: build_runner.bcx.do_uplift,
             roots: build_runner.bcx.roots.clone(),
             metas,
@@ -195,6 +255,20 @@ impl<'a, 'gctx: 'a> CompilationFiles<'a, 'gctx> {
         }
     }
 
+    /// Returns the directory layout the given unit is built in.
+    pub fn unit_layout(&self, unit: &Unit) -> &Layout {
+        self.layout_for(unit, unit.kind)
+    }
+
+    /// Returns the directory layout of the given kind for the profile the
+    /// given unit is built with.
+    fn layout_for(&self, unit: &Unit, kind: CompileKind) -> &Layout {
+        match self.unit_dir_names.get(unit) {
+            Some(dir_name) => &self.profile_layouts[&(*dir_name, kind)],
+            None => self.layout(kind),
+        }
+    }
+
     /// Gets the metadata for the given unit.
     ///
     /// See [`Metadata`] and [`fingerprint`] module for more.
@@ -207,7 +281,8 @@ impl<'a, 'gctx: 'a> CompilationFiles<'a, 'gctx> {
     /// Gets the short hash based only on the `PackageId`.
     /// Used for the metadata when `metadata` returns `None`.
     pub fn target_short_hash(&self, unit: &Unit) -> String {
-        let hashable = unit.pkg.package_id().stable_hash(self.ws.root());
+        let ws = self.unit_workspaces.get(unit).copied().unwrap_or(self.ws);
+        let hashable = unit.pkg.package_id().stable_hash(ws.root());
         util::short_hash(&(METADATA_VERSION, hashable))
     }
 
@@ -220,13 +295,13 @@ impl<'a, 'gctx: 'a> CompilationFiles<'a, 'gctx> {
             if let Some(dir) = self.unit_export_dirs.get(unit) {
                 return dir.clone();
             }
-            self.layout(unit.kind).doc().to_path_buf()
+            self.unit_layout(unit).doc().to_path_buf()
         } else if unit.mode.is_doc_test() {
             panic!("doc tests do not have an out dir");
         } else if unit.target.is_custom_build() {
             self.build_script_dir(unit)
         } else if unit.target.is_example() {
-            self.layout(unit.kind).build_examples().to_path_buf()
+            self.unit_layout(unit).build_examples().to_path_buf()
         } else if unit.artifact.is_true() {
             self.artifact_dir(unit)
         } else {
@@ -268,16 +343,28 @@ impl<'a, 'gctx: 'a> CompilationFiles<'a, 'gctx> {
         self.host.deps()
     }
 
+    /// Returns the final artifact path for the host (`/…/target/debug`) in
+    /// the profile the given unit is built with.
+    pub fn host_dest_for(&self, unit: &Unit) -> &Path {
+        self.layout_for(unit, CompileKind::Host).dest()
+    }
+
+    /// Returns the host `deps` directory path in the profile the given unit
+    /// is built with.
+    pub fn host_deps_for(&self, unit: &Unit) -> &Path {
+        self.layout_for(unit, CompileKind::Host).deps()
+    }
+
     /// Returns the directories where Rust crate dependencies are found for the
     /// specified unit.
     pub fn deps_dir(&self, unit: &Unit) -> &Path {
-        self.layout(unit.kind).deps()
+        self.unit_layout(unit).deps()
     }
 
     /// Directory where the fingerprint for the given unit should go.
     pub fn fingerprint_dir(&self, unit: &Unit) -> PathBuf {
         let dir = self.pkg_dir(unit);
-        self.layout(unit.kind).fingerprint().join(dir)
+        self.unit_layout(unit).fingerprint().join(dir)
     }
 
     /// Returns the path for a file in the fingerprint directory.
@@ -312,7 +399,7 @@ impl<'a, 'gctx: 'a> CompilationFiles<'a, 'gctx> {
         assert!(!unit.mode.is_run_custom_build());
         assert!(self.metas.contains_key(unit));
         let dir = self.pkg_dir(unit);
-        self.layout(CompileKind::Host).build().join(dir)
+        self.layout_for(unit, CompileKind::Host).build().join(dir)
     }
 
     /// Returns the directory for compiled artifacts files.
@@ -336,7 +423,7 @@ impl<'a, 'gctx: 'a> CompilationFiles<'a, 'gctx> {
                 invalid
             ),
         };
-        self.layout(unit.kind).artifact().join(dir).join(kind)
+        self.unit_layout(unit).artifact().join(dir).join(kind)
     }
 
     /// Returns the directory where information about running a build script
@@ -346,7 +433,7 @@ impl<'a, 'gctx: 'a> CompilationFiles<'a, 'gctx> {
         assert!(unit.target.is_custom_build());
         assert!(unit.mode.is_run_custom_build());
         let dir = self.pkg_dir(unit);
-        self.layout(unit.kind).build().join(dir)
+        self.unit_layout(unit).build().join(dir)
     }
 
     /// Returns the "`OUT_DIR`" directory for running a build script.
@@ -432,11 +519,11 @@ impl<'a, 'gctx: 'a> CompilationFiles<'a, 'gctx> {
         let filename = file_type.uplift_filename(&unit.target);
         let uplift_path = if unit.target.is_example() {
             // Examples live in their own little world.
-            self.layout(unit.kind).examples().join(filename)
+            self.unit_layout(unit).examples().join(filename)
         } else if unit.target.is_custom_build() {
             self.build_script_dir(unit).join(filename)
         } else {
-            self.layout(unit.kind).dest().join(filename)
+            self.unit_layout(unit).dest().join(filename)
         };
         if from_path == uplift_path {
             // This can happen with things like examples that reside in the
@@ -470,6 +557,8 @@ impl<'a, 'gctx: 'a> CompilationFiles<'a, 'gctx> {
                     path,
                     hardlink: None,
                     export_path: None,
+                    command_paths: Vec::new(),
+                    export_formats: Vec::new(),
                     flavor: FileFlavor::Normal,
                 }]
             }
@@ -498,6 +587,8 @@ impl<'a, 'gctx: 'a> CompilationFiles<'a, 'gctx> {
                     path,
                     hardlink: None,
                     export_path: None,
+                    command_paths: Vec::new(),
+                    export_formats: Vec::new(),
                     flavor: FileFlavor::Normal,
                 }]
             }
@@ -506,7 +597,7 @@ impl<'a, 'gctx: 'a> CompilationFiles<'a, 'gctx> {
             | CompileMode::Bench
             | CompileMode::Check { .. } => {
                 let mut outputs = self.calc_outputs_rustc(unit, bcx)?;
-                if bcx.build_config.sbom && bcx.gctx.cli_unstable().sbom {
+                if bcx.build_config_for(unit).sbom && bcx.gctx.cli_unstable().sbom {
                     let sbom_files: Vec<_> = outputs
                         .iter()
                         .filter(|o| matches!(o.flavor, FileFlavor::Normal | FileFlavor::Linkable))
@@ -514,6 +605,12 @@ impl<'a, 'gctx: 'a> CompilationFiles<'a, 'gctx> {
                             path: Self::append_sbom_suffix(&output.path),
                             hardlink: output.hardlink.as_ref().map(Self::append_sbom_suffix),
                             export_path: output.export_path.as_ref().map(Self::append_sbom_suffix),
+                            command_paths: output
+                                .command_paths
+                                .iter()
+                                .map(|(i, path)| (*i, Self::append_sbom_suffix(path)))
+                                .collect(),
+                            export_formats: Vec::new(),
                             flavor: FileFlavor::Sbom,
                         })
                         .collect();
@@ -591,6 +688,30 @@ impl<'a, 'gctx: 'a> CompilationFiles<'a, 'gctx> {
                             .map(|hardlink| export_dir.join(hardlink.file_name().unwrap()))
                     })
             };
+            let command_paths = match (&hardlink, self.command_dirs.get(unit)) {
+                (Some(hardlink), Some(dirs)) => {
+                    // Keep the layout below `target/`, so that outputs of
+                    // different kinds and profiles don't clash.
+                    let relative = hardlink
+                        .strip_prefix(self.host.root())
+                        .unwrap_or_else(|_| Path::new(hardlink.file_name().unwrap()));
+                    dirs.iter()
+                        .map(|(i, dir)| (*i, dir.join(relative)))
+                        .collect()
+                }
+                _ => Vec::new(),
+            };
+            let export_formats = match (&export_path, self.unit_export_formats.get(unit)) {
+                (Some(export_path), Some(formats))
+                    if file_type.flavor == FileFlavor::Normal && unit.target.is_executable() =>
+                {
+                    formats
+                        .iter()
+                        .map(|format| (*format, export_path.with_extension(format.extension())))
+                        .collect()
+                }
+                _ => Vec::new(),
+            };
             if !self.do_uplift && !unit.target.is_custom_build() {
                 hardlink = None
             }
@@ -598,6 +719,8 @@ impl<'a, 'gctx: 'a> CompilationFiles<'a, 'gctx> {
                 path,
                 hardlink,
                 export_path,
+                command_paths,
+                export_formats,
                 flavor: file_type.flavor,
             });
         }
@@ -649,7 +772,7 @@ fn compute_metadata(
         // This avoids the metadata hash changing depending on where the user installed rustc.
         &bcx.target_data.get_info(unit.kind).unwrap().sysroot
     } else {
-        bcx.ws.root()
+        bcx.ws_for(unit).root()
     };
 
     // Unique metadata per (name, source, version) triple. This'll allow us
@@ -684,7 +807,7 @@ fn compute_metadata(
 
     hash_rustc_version(bcx, &mut shared_hasher, unit);
 
-    if build_runner.bcx.ws.is_member(&unit.pkg) {
+    if build_runner.bcx.ws_for(unit).is_member(&unit.pkg) {
         // This is primarily here for clippy. This ensures that the clippy
         // artifacts are separate from the `check` ones.
         if let Some(path) = &build_runner.bcx.rustc().workspace_wrapper {
@@ -765,6 +888,11 @@ fn compute_metadata(
             unit.rustflags.hash(&mut c_extra_filename_hasher);
         }
     }
+    // Units of batch commands that only differ by their extra environment
+    // variables must not overwrite each other's outputs.
+    if !unit.env.is_empty() {
+        unit.env.hash(&mut c_extra_filename_hasher);
+    }
 
     let c_metadata = UnitHash(Hasher::finish(&c_metadata_hasher));
     let c_extra_filename = UnitHash(Hasher::finish(&c_extra_filename_hasher));
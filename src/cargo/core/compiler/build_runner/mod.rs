@@ -1,15 +1,18 @@
 //! [`BuildRunner`] is the mutable state used during the build process.
 
+use std::cell::RefCell;
 use std::collections::{BTreeSet, HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 
 use crate::core::compiler::compilation::{self, UnitOutput};
 use crate::core::compiler::{self, artifact, Unit};
 use crate::core::PackageId;
 use crate::util::cache_lock::CacheLockMode;
-use crate::util::errors::CargoResult;
+use crate::util::errors::{AlreadyPrintedError, CargoResult};
 use anyhow::{bail, Context as _};
+use cargo_util::paths;
 use filetime::FileTime;
 use itertools::Itertools;
 use jobserver::Client;
@@ -17,12 +20,13 @@ use jobserver::Client;
 use super::build_plan::BuildPlan;
 use super::custom_build::{self, BuildDeps, BuildScriptOutputs, BuildScripts};
 use super::fingerprint::{Checksum, Fingerprint};
-use super::job_queue::JobQueue;
+use super::job_queue::{JobQueue, UnitResult};
 use super::layout::Layout;
 use super::lto::Lto;
 use super::unit_graph::UnitDep;
 use super::{
-    BuildContext, Compilation, CompileKind, CompileMode, Executor, FileFlavor, RustDocFingerprint,
+    BuildContext, CommandLogs, Compilation, CompileKind, CompileMode, Executor, FileFlavor,
+    RustDocFingerprint,
 };
 
 mod compilation_files;
@@ -87,6 +91,29 @@ pub struct BuildRunner<'a, 'gctx> {
     /// because the target has a type error. This is in an Arc<Mutex<..>>
     /// because it is continuously updated as the job progresses.
     pub failed_scrape_units: Arc<Mutex<HashSet<UnitHash>>>,
+
+    /// The outcome of every unit that finished running, whether fresh, built
+    /// or failed. Units that never ran, for example because the build stopped
+    /// after an error, are missing.
+    ///
+    /// This is shared so that it can still be inspected when the build fails.
+    pub unit_results: Rc<RefCell<HashMap<Unit, UnitResult>>>,
+
+    /// The files every local unit was built from, according to its dep-info
+    /// and the `rerun-if-changed` paths of its build script. Units that were
+    /// never built only list the root source file of their target.
+    ///
+    /// This is only collected if [`BuildContext::collect_unit_sources`] is
+    /// set, and is shared so that it can still be inspected when the build
+    /// fails.
+    pub unit_sources: Rc<RefCell<HashMap<Unit, BTreeSet<PathBuf>>>>,
+
+    /// The log files of the merged commands, created when the build starts
+    /// if [`BuildContext::command_log_dir`] is set.
+    ///
+    /// This is shared so that it can still be inspected when the build
+    /// fails.
+    pub command_logs: Rc<RefCell<CommandLogs>>,
 }
 
 impl<'a, 'gctx> BuildRunner<'a, 'gctx> {
@@ -126,6 +153,9 @@ impl<'a, 'gctx> BuildRunner<'a, 'gctx> {
             lto: HashMap::new(),
             metadata_for_doc_units: HashMap::new(),
             failed_scrape_units: Arc::new(Mutex::new(HashSet::new())),
+            unit_results: Rc::new(RefCell::new(HashMap::new())),
+            unit_sources: Rc::new(RefCell::new(HashMap::new())),
+            command_logs: Rc::new(RefCell::new(CommandLogs::default())),
         })
     }
 
@@ -150,6 +180,106 @@ impl<'a, 'gctx> BuildRunner<'a, 'gctx> {
         Ok(self.compilation)
     }
 
+    /// Lists the entries of the build directories of this build that it
+    /// would not produce, like the artifacts of units with another metadata
+    /// hash, without building anything.
+    ///
+    /// Only the profile and target directories used by this build are
+    /// looked at. Documentation, incremental compilation state and artifacts
+    /// uplifted by regular builds are kept, since their names don't tell
+    /// which unit they belong to.
+    pub fn unused_paths(mut self) -> CargoResult<Vec<PathBuf>> {
+        self.lto = super::lto::generate(self.bcx)?;
+        self.prepare_units()?;
+
+        // Entries are kept if they are produced by a unit, or, in `deps/`
+        // and `examples/`, if they carry the metadata hash of a unit, which
+        // covers the dep-info and other files rustc emits alongside.
+        let mut keep = HashSet::new();
+        let mut hashes = HashSet::new();
+        for unit in self.bcx.unit_graph.keys() {
+            keep.insert(self.files().fingerprint_dir(unit));
+            if unit.mode.is_run_custom_build() {
+                keep.insert(self.files().build_script_run_dir(unit));
+                continue;
+            }
+            if unit.target.is_custom_build() {
+                keep.insert(self.files().build_script_dir(unit));
+            }
+            if let Some(hash) = self.files().metadata(unit).c_extra_filename() {
+                hashes.insert(hash.to_string());
+            }
+            for output in self.outputs(unit)?.iter() {
+                keep.insert(output.path.with_extension("d"));
+                keep.insert(output.path.clone());
+                keep.extend(output.hardlink.clone());
+                keep.extend(output.command_paths.iter().map(|(_, path)| path.clone()));
+            }
+        }
+
+        let files = self.files();
+        let mut layouts = vec![&files.host];
+        layouts.extend(files.target.values());
+        layouts.extend(files.profile_layouts.values());
+        let mut unused = BTreeSet::new();
+        let mut seen_dirs = HashSet::new();
+        let mut read_dir = |dir: &Path| -> Vec<PathBuf> {
+            if !seen_dirs.insert(dir.to_path_buf()) {
+                return Vec::new();
+            }
+            match std::fs::read_dir(dir) {
+                Ok(entries) => entries.filter_map(|e| Some(e.ok()?.path())).collect(),
+                Err(_) => Vec::new(),
+            }
+        };
+        for layout in &layouts {
+            for dir in [layout.deps(), layout.examples(), layout.build_examples()] {
+                for path in read_dir(dir) {
+                    if path.is_dir() || keep.contains(&path) {
+                        continue;
+                    }
+                    let hash = path
+                        .file_name()
+                        .and_then(|name| name.to_str())
+                        .and_then(|name| name.split('.').next())
+                        .and_then(|stem| stem.rsplit_once('-'))
+                        .map(|(_, hash)| hash);
+                    if !hash.is_some_and(|hash| hashes.contains(hash)) {
+                        unused.insert(path);
+                    }
+                }
+            }
+            for dir in [layout.fingerprint(), layout.build()] {
+                for path in read_dir(dir) {
+                    if !keep.contains(&path) {
+                        unused.insert(path);
+                    }
+                }
+            }
+        }
+
+        // Commands uplifted into `target/batch/` only keep what they
+        // produce.
+        let batch_dir = files.host.root().join("batch");
+        if batch_dir.exists() {
+            for entry in walkdir::WalkDir::new(&batch_dir).min_depth(1) {
+                let path = entry?.into_path();
+                if path.ancestors().skip(1).any(|dir| unused.contains(dir)) {
+                    continue;
+                }
+                let used = if path.is_dir() {
+                    keep.iter().any(|kept| kept.starts_with(&path))
+                } else {
+                    keep.contains(&path)
+                };
+                if !used {
+                    unused.insert(path);
+                }
+            }
+        }
+        Ok(unused.into_iter().collect())
+    }
+
     /// Starts compilation, waits for it to finish, and returns information
     /// about the result of compilation.
     ///
@@ -183,12 +313,17 @@ impl<'a, 'gctx> BuildRunner<'a, 'gctx> {
         // any versioning (See https://github.com/rust-lang/cargo/issues/8461).
         // Therefore, we can end up with weird bugs and behaviours if we mix different
         // versions of these files.
-        if self.bcx.build_config.mode.is_doc() {
+        if self
+            .bcx
+            .roots
+            .iter()
+            .any(|unit| self.bcx.build_config_for(unit).mode.is_doc())
+        {
             RustDocFingerprint::check_rustdoc_fingerprint(&self)?
         }
 
         for unit in &self.bcx.roots {
-            let force_rebuild = self.bcx.build_config.force_rebuild;
+            let force_rebuild = self.bcx.build_config_for(unit).force_rebuild;
             super::compile(&mut self, &mut queue, &mut plan, unit, exec, force_rebuild)?;
         }
 
@@ -202,8 +337,25 @@ impl<'a, 'gctx> BuildRunner<'a, 'gctx> {
             fingerprint.clear_memoized();
         }
 
+        *self.command_logs.borrow_mut() = CommandLogs::create(self.bcx)?;
+
         // Now that we've figured out everything that we're going to do, do it!
-        queue.execute(&mut self, &mut plan)?;
+        let result = queue.execute(&mut self, &mut plan);
+        if self.bcx.collect_unit_sources {
+            self.collect_unit_sources()?;
+        }
+        let copied = self.copy_unit_docs();
+        match result {
+            // With `--keep-going`, a merged build goes on to collect the
+            // outputs of the commands that did build. The failed ones are
+            // told apart by `unit_results`, their errors were printed.
+            Err(e)
+                if self.bcx.build_config.keep_going
+                    && !self.bcx.command_lines.is_empty()
+                    && e.is::<AlreadyPrintedError>() => {}
+            result => result?,
+        }
+        copied?;
 
         if build_plan {
             plan.set_inputs(self.build_plan_inputs()?);
@@ -239,6 +391,8 @@ impl<'a, 'gctx> BuildRunner<'a, 'gctx> {
         // Collect the result of the build into `self.compilation`.
         for unit in &self.bcx.roots {
             self.collect_tests_and_executables(unit)?;
+            let outputs = self.outputs(unit)?;
+            self.compilation.root_outputs.insert(unit.clone(), outputs);
 
             // Collect information for `rustdoc --test`.
             if unit.mode.is_doc_test() {
@@ -272,7 +426,7 @@ impl<'a, 'gctx> BuildRunner<'a, 'gctx> {
                 args.extend(unit.rustdocflags.iter().map(Into::into));
 
                 use super::MessageFormat;
-                let format = match self.bcx.build_config.message_format {
+                let format = match self.bcx.build_config_for(unit).message_format {
                     MessageFormat::Short => "short",
                     MessageFormat::Human => "human",
                     MessageFormat::Json { .. } => "json",
@@ -309,6 +463,69 @@ impl<'a, 'gctx> BuildRunner<'a, 'gctx> {
         Ok(self.compilation)
     }
 
+    /// Fills [`BuildRunner::unit_sources`] from the dep-info files of the
+    /// local units in the unit graph.
+    fn collect_unit_sources(&mut self) -> CargoResult<()> {
+        let unit_sources = Rc::clone(&self.unit_sources);
+        let mut unit_sources = unit_sources.borrow_mut();
+        for unit in self.bcx.unit_graph.keys().filter(|unit| unit.is_local()) {
+            let mut sources = BTreeSet::new();
+            if !unit.mode.is_run_custom_build() {
+                sources.extend(unit.target.src_path().path().map(Path::to_path_buf));
+                let dep_info_loc = super::fingerprint::dep_info_loc(self, unit);
+                if let Some(dep_info) = super::fingerprint::parse_dep_info(
+                    unit.pkg.root(),
+                    self.files().host_root(),
+                    &dep_info_loc,
+                )? {
+                    sources.extend(dep_info.files.into_keys());
+                }
+            }
+            if let Some(metadata) = self.find_build_script_metadata(unit) {
+                if let Some(output) = self.build_script_outputs.lock().unwrap().get(metadata) {
+                    sources.extend(
+                        output
+                            .rerun_if_changed
+                            .iter()
+                            .map(|path| unit.pkg.root().join(path)),
+                    );
+                }
+            }
+            unit_sources.insert(unit.clone(), sources);
+        }
+        Ok(())
+    }
+
+    /// Copies the docs of [`BuildContext::unit_doc_copies`] into the docs
+    /// directories of the merged commands that also want them.
+    ///
+    /// Only the pages and sources of each crate are copied. The search index
+    /// and crate list that rustdoc shares between crates are not merged, so
+    /// searching an exported docs tree only finds its own crates.
+    ///
+    /// Crates without docs, because documenting them failed, are skipped.
+    fn copy_unit_docs(&self) -> CargoResult<()> {
+        for (unit, doc_dirs) in &self.bcx.unit_doc_copies {
+            let src = self.files().out_dir(unit);
+            let crate_name = unit.target.crate_name();
+            for doc_dir in doc_dirs {
+                let dst = match doc_dir {
+                    Some(dir) => dir.clone(),
+                    None => self.files().unit_layout(unit).doc().to_path_buf(),
+                };
+                for dir in [
+                    PathBuf::from(&crate_name),
+                    Path::new("src").join(&crate_name),
+                ] {
+                    if src.join(&dir).is_dir() {
+                        copy_dir(&src.join(&dir), &dst.join(&dir))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn collect_tests_and_executables(&mut self, unit: &Unit) -> CargoResult<()> {
         for output in self.outputs(unit)?.iter() {
             if matches!(
@@ -364,6 +581,22 @@ impl<'a, 'gctx> BuildRunner<'a, 'gctx> {
                 targets.insert(target, layout);
             }
         }
+        // Units from merged builds may use a profile with another directory.
+        let mut profile_layouts = HashMap::new();
+        for config in self.bcx.unit_configs.values() {
+            let dir_name = config.profiles.get_dir_name();
+            if dir_name == dest || profile_layouts.contains_key(&(dir_name, CompileKind::Host)) {
+                continue;
+            }
+            let layout = Layout::new(self.bcx.ws, None, &dir_name)?;
+            profile_layouts.insert((dir_name, CompileKind::Host), layout);
+            for kind in self.bcx.all_kinds.iter() {
+                if let CompileKind::Target(target) = *kind {
+                    let layout = Layout::new(self.bcx.ws, Some(target), &dir_name)?;
+                    profile_layouts.insert((dir_name, *kind), layout);
+                }
+            }
+        }
         self.primary_packages
             .extend(self.bcx.roots.iter().map(|u| u.pkg.package_id()));
         self.compilation
@@ -372,7 +605,7 @@ impl<'a, 'gctx> BuildRunner<'a, 'gctx> {
 
         self.record_units_requiring_metadata();
 
-        let files = CompilationFiles::new(self, host_layout, targets);
+        let files = CompilationFiles::new(self, host_layout, targets, profile_layouts);
         self.files = Some(files);
         Ok(())
     }
@@ -392,6 +625,11 @@ impl<'a, 'gctx> BuildRunner<'a, 'gctx> {
                 .prepare()
                 .context("couldn't prepare build directories")?;
         }
+        for layout in self.files.as_mut().unwrap().profile_layouts.values_mut() {
+            layout
+                .prepare()
+                .context("couldn't prepare build directories")?;
+        }
 
         let files = self.files.as_ref().unwrap();
         for &kind in self.bcx.all_kinds.iter() {
@@ -613,8 +851,28 @@ impl<'a, 'gctx> BuildRunner<'a, 'gctx> {
                         report_collision(unit, other_unit, hardlink, suggestion)?;
                     }
                 }
+                for (_, command_path) in &output.command_paths {
+                    if let Some(other_unit) = output_collisions.insert(command_path.clone(), unit) {
+                        report_collision(unit, other_unit, command_path, suggestion)?;
+                    }
+                }
                 if let Some(ref export_path) = output.export_path {
                     if let Some(other_unit) = output_collisions.insert(export_path.clone(), unit) {
+                        let commands = self.bcx.command_lines_for(unit);
+                        let other_commands = self.bcx.command_lines_for(other_unit);
+                        if !commands.is_empty() && !other_commands.is_empty() {
+                            // In a merged build one command would silently
+                            // overwrite the exported file of another.
+                            bail!(
+                                "`--artifact-dir` filename collision.\n\
+                                 {}\
+                                 The first target is requested by:\n    {}\n\
+                                 The second target is requested by:\n    {}",
+                                describe_collision(unit, other_unit, export_path),
+                                commands.join("\n    "),
+                                other_commands.join("\n    "),
+                            );
+                        }
                         self.bcx.gctx.shell().warn(format!(
                             "`--artifact-dir` filename collision.\n\
                              {}\
@@ -700,3 +958,18 @@ impl<'a, 'gctx> BuildRunner<'a, 'gctx> {
         }
     }
 }
+
+/// Recursively copies the contents of `src` into `dst`, overwriting files
+/// that already exist.
+fn copy_dir(src: &Path, dst: &Path) -> CargoResult<()> {
+    for entry in walkdir::WalkDir::new(src) {
+        let entry = entry?;
+        let path = dst.join(entry.path().strip_prefix(src)?);
+        if entry.file_type().is_dir() {
+            paths::create_dir_all(&path)?;
+        } else {
+            paths::copy(entry.path(), &path)?;
+        }
+    }
+    Ok(())
+}
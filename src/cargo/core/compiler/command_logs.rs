@@ -0,0 +1,121 @@
+//! Per-command log files of a merged build, see [`CommandLogs`].
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Write as _;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use cargo_util::paths;
+
+use super::BuildContext;
+use crate::util::errors::{AlreadyPrintedError, VerboseError};
+use crate::util::{indented_lines, CargoResult};
+
+/// The log files that the output of a merged build is written to instead of
+/// the console, one per merged command, see
+/// [`BuildContext::command_log_dir`].
+///
+/// The output of a unit goes to the log of every command whose roots depend
+/// on it, see [`BuildContext::unit_commands`], so each log is complete on its
+/// own. The default value logs nothing.
+#[derive(Default)]
+pub struct CommandLogs {
+    logs: Vec<CommandLog>,
+    files: Vec<File>,
+}
+
+/// The log file of one merged command, see [`CommandLogs`].
+#[derive(Clone, Debug)]
+pub struct CommandLog {
+    pub path: PathBuf,
+    /// Warnings written to the log, including those of shared units.
+    pub warnings: usize,
+    /// Errors written to the log, including those of shared units.
+    pub errors: usize,
+}
+
+impl CommandLogs {
+    /// Creates or truncates `<dir>/<command>.log` for every merged command,
+    /// if [`BuildContext::command_log_dir`] is set.
+    pub fn create(bcx: &BuildContext<'_, '_>) -> CargoResult<CommandLogs> {
+        let Some(dir) = &bcx.command_log_dir else {
+            return Ok(CommandLogs::default());
+        };
+        paths::create_dir_all(dir)?;
+        let mut logs = CommandLogs::default();
+        for index in 0..bcx.command_lines.len() {
+            let path = dir.join(format!("{}.log", bcx.command_dir_name(index)));
+            let file = File::create(&path)
+                .with_context(|| format!("failed to create log file `{}`", path.display()))?;
+            logs.files.push(file);
+            logs.logs.push(CommandLog {
+                path,
+                warnings: 0,
+                errors: 0,
+            });
+        }
+        Ok(logs)
+    }
+
+    /// Whether output of units reachable from `commands` goes to their logs
+    /// rather than the console.
+    pub fn logs_for(&self, commands: &[usize]) -> bool {
+        !self.files.is_empty() && !commands.is_empty()
+    }
+
+    /// The logs, in command order.
+    pub fn logs(&self) -> &[CommandLog] {
+        &self.logs
+    }
+
+    /// Appends `text` to the logs of `commands`, without color codes.
+    pub fn write(&mut self, commands: &[usize], text: &str) -> CargoResult<()> {
+        let text = anstream::adapter::strip_str(text).to_string();
+        for &i in commands {
+            writeln!(self.files[i], "{}", text.trim_end_matches('\n'))
+                .with_context(|| format!("failed to write to `{}`", self.logs[i].path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Appends a diagnostic of the given `level` to the logs of `commands`,
+    /// counting warnings and errors.
+    pub fn write_diag(&mut self, commands: &[usize], level: &str, diag: &str) -> CargoResult<()> {
+        for &i in commands {
+            match level {
+                "warning" => self.logs[i].warnings += 1,
+                "error" => self.logs[i].errors += 1,
+                _ => {}
+            }
+        }
+        self.write(commands, diag)
+    }
+
+    /// Appends `error` and its causes to the logs of `commands`, like
+    /// [`display_error`](crate::display_error) prints them, counting it as
+    /// an error.
+    pub fn write_error(
+        &mut self,
+        commands: &[usize],
+        error: &anyhow::Error,
+        verbose: bool,
+    ) -> CargoResult<()> {
+        for &i in commands {
+            self.logs[i].errors += 1;
+        }
+        let mut text = format!("error: {error}");
+        for cause in error.chain().skip(1) {
+            if (!verbose && cause.is::<VerboseError>()) || cause.is::<AlreadyPrintedError>() {
+                break;
+            }
+            write!(
+                text,
+                "\n\nCaused by:\n{}",
+                indented_lines(&cause.to_string())
+            )
+            .unwrap();
+        }
+        self.write(commands, &text)
+    }
+}
@@ -3,13 +3,14 @@
 use std::collections::{BTreeSet, HashMap};
 use std::ffi::{OsStr, OsString};
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use cargo_platform::CfgExpr;
 use cargo_util::{paths, ProcessBuilder};
 
 use crate::core::compiler::apply_env_config;
 use crate::core::compiler::BuildContext;
-use crate::core::compiler::{CompileKind, Unit, UnitHash};
+use crate::core::compiler::{CompileKind, OutputFile, Unit, UnitHash};
 use crate::core::Package;
 use crate::util::{context, CargoResult, GlobalContext};
 
@@ -33,6 +34,7 @@ impl ToolKind {
 }
 
 /// Structure with enough information to run `rustdoc --test`.
+#[derive(Clone)]
 pub struct Doctest {
     /// What's being doctested
     pub unit: Unit,
@@ -52,7 +54,7 @@ pub struct Doctest {
 }
 
 /// Information about the output of a unit.
-#[derive(Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq)]
 pub struct UnitOutput {
     /// The unit that generated this output.
     pub unit: Unit,
@@ -106,6 +108,9 @@ pub struct Compilation<'gctx> {
     /// Libraries to test with rustdoc.
     pub to_doc_test: Vec<Doctest>,
 
+    /// Files produced by each root unit.
+    pub root_outputs: HashMap<Unit, Arc<Vec<OutputFile>>>,
+
     /// The target host triple.
     pub host: String,
 
@@ -143,6 +148,7 @@ impl<'gctx> Compilation<'gctx> {
             root_crate_names: Vec::new(),
             extra_env: HashMap::new(),
             to_doc_test: Vec::new(),
+            root_outputs: HashMap::new(),
             gctx: bcx.gctx,
             host: bcx.host_triple().to_string(),
             rustc_process,
@@ -166,6 +172,59 @@ impl<'gctx> Compilation<'gctx> {
         })
     }
 
+    /// Returns the part of this compilation produced for the given root
+    /// units.
+    ///
+    /// This is used to split a build that merged several commands, like
+    /// `cargo-batch` does, back into one compilation per command. Information
+    /// that isn't tied to a root unit, like `native_dirs`, is kept as is.
+    pub fn for_roots(&self, roots: &[Unit]) -> Compilation<'gctx> {
+        let outputs = |outputs: &[UnitOutput]| {
+            outputs
+                .iter()
+                .filter(|output| roots.contains(&output.unit))
+                .cloned()
+                .collect()
+        };
+        let crate_names: Vec<_> = roots.iter().map(|unit| unit.target.crate_name()).collect();
+        Compilation {
+            tests: outputs(&self.tests),
+            binaries: outputs(&self.binaries),
+            cdylibs: outputs(&self.cdylibs),
+            root_crate_names: self
+                .root_crate_names
+                .iter()
+                .filter(|name| crate_names.contains(name))
+                .cloned()
+                .collect(),
+            native_dirs: self.native_dirs.clone(),
+            root_output: self.root_output.clone(),
+            deps_output: self.deps_output.clone(),
+            sysroot_target_libdir: self.sysroot_target_libdir.clone(),
+            extra_env: self.extra_env.clone(),
+            to_doc_test: self
+                .to_doc_test
+                .iter()
+                .filter(|doctest| roots.contains(&doctest.unit))
+                .cloned()
+                .collect(),
+            root_outputs: self
+                .root_outputs
+                .iter()
+                .filter(|(unit, _)| roots.contains(unit))
+                .map(|(unit, outputs)| (unit.clone(), Arc::clone(outputs)))
+                .collect(),
+            host: self.host.clone(),
+            gctx: self.gctx,
+            rustc_process: self.rustc_process.clone(),
+            rustc_workspace_wrapper_process: self.rustc_workspace_wrapper_process.clone(),
+            primary_rustc_process: self.primary_rustc_process.clone(),
+            target_runners: self.target_runners.clone(),
+            target_linkers: self.target_linkers.clone(),
+            warning_count: self.warning_count,
+        }
+    }
+
     /// Returns a [`ProcessBuilder`] for running `rustc`.
     ///
     /// `is_primary` is true if this is a "primary package", which means it
@@ -391,6 +450,9 @@ fn fill_rustc_tool_env(mut cmd: ProcessBuilder, unit: &Unit) -> ProcessBuilder {
         cmd.env("CARGO_BIN_NAME", name);
     }
     cmd.env("CARGO_CRATE_NAME", unit.target.crate_name());
+    for (key, value) in unit.env.iter() {
+        cmd.env(key, value);
+    }
     cmd
 }
 
@@ -379,6 +379,10 @@ fn build_work(build_runner: &mut BuildRunner<'_, '_>, unit: &Unit) -> CargoResul
         .env("RUSTDOC", &*bcx.gctx.rustdoc()?)
         .inherit_jobserver(&build_runner.jobserver);
 
+    for (key, value) in unit.env.iter() {
+        cmd.env(key, value);
+    }
+
     // Find all artifact dependencies and make their file and containing directory discoverable using environment variables.
     for (var, value) in artifact::get_env(build_runner, dependencies)? {
         cmd.env(&var, value);
@@ -437,7 +441,7 @@ fn build_work(build_runner: &mut BuildRunner<'_, '_>, unit: &Unit) -> CargoResul
         cmd.env_remove("RUSTC_WRAPPER");
     }
     cmd.env_remove("RUSTC_WORKSPACE_WRAPPER");
-    if build_runner.bcx.ws.is_member(&unit.pkg) {
+    if build_runner.bcx.ws_for(unit).is_member(&unit.pkg) {
         if let Some(wrapper) = bcx.rustc().workspace_wrapper.as_ref() {
             cmd.env("RUSTC_WORKSPACE_WRAPPER", wrapper);
         }
@@ -476,7 +480,7 @@ fn build_work(build_runner: &mut BuildRunner<'_, '_>, unit: &Unit) -> CargoResul
     let output_file = script_run_dir.join("output");
     let err_file = script_run_dir.join("stderr");
     let root_output_file = script_run_dir.join("root-output");
-    let host_target_root = build_runner.files().host_dest().to_path_buf();
+    let host_target_root = build_runner.files().host_dest_for(unit).to_path_buf();
     let all = (
         id,
         library_name.clone(),
@@ -486,7 +490,7 @@ fn build_work(build_runner: &mut BuildRunner<'_, '_>, unit: &Unit) -> CargoResul
         script_out_dir.clone(),
     );
     let build_scripts = build_runner.build_scripts.get(unit).cloned();
-    let json_messages = bcx.build_config.emit_json();
+    let json_messages = bcx.build_config_for(unit).emit_json();
     let extra_verbose = bcx.gctx.extra_verbose();
     let (prev_output, prev_script_out_dir) = prev_build_output(build_runner, unit);
     let metadata_hash = build_runner.get_run_build_script_metadata(unit);
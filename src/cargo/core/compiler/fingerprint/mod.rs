@@ -373,6 +373,7 @@ mod dep_info;
 mod dirty_reason;
 
 use std::collections::hash_map::{Entry, HashMap};
+use std::collections::BTreeMap;
 use std::env;
 use std::ffi::OsString;
 use std::fs;
@@ -839,6 +840,7 @@ impl LocalFingerprint {
         mtime_cache: &mut HashMap<PathBuf, FileTime>,
         checksum_cache: &mut HashMap<PathBuf, Checksum>,
         pkg: &Package,
+        unit_env: &BTreeMap<String, String>,
         build_root: &Path,
         cargo_exe: &Path,
         gctx: &GlobalContext,
@@ -870,6 +872,8 @@ impl LocalFingerprint {
                                 cargo_exe.display()
                             )
                         })?)
+                    } else if let Some(value) = unit_env.get(key) {
+                        Some(value.as_str())
                     } else {
                         if let Some(value) = gctx.env_config()?.get(key) {
                             value.to_str()
@@ -1153,6 +1157,7 @@ impl Fingerprint {
         mtime_cache: &mut HashMap<PathBuf, FileTime>,
         checksum_cache: &mut HashMap<PathBuf, Checksum>,
         pkg: &Package,
+        unit_env: &BTreeMap<String, String>,
         build_root: &Path,
         cargo_exe: &Path,
         gctx: &GlobalContext,
@@ -1261,6 +1266,7 @@ impl Fingerprint {
                 mtime_cache,
                 checksum_cache,
                 pkg,
+                unit_env,
                 build_root,
                 cargo_exe,
                 gctx,
@@ -1455,6 +1461,7 @@ fn calculate(build_runner: &mut BuildRunner<'_, '_>, unit: &Unit) -> CargoResult
         &mut build_runner.mtime_cache,
         &mut build_runner.checksum_cache,
         &unit.pkg,
+        &unit.env,
         &build_root,
         cargo_exe,
         build_runner.bcx.gctx,
@@ -1567,7 +1574,7 @@ fn calculate_normal(
         profile: profile_hash,
         // Note that .0 is hashed here, not .1 which is the cwd. That doesn't
         // actually affect the output artifact so there's no need to hash it.
-        path: util::hash_u64(path_args(build_runner.bcx.ws, unit).0),
+        path: util::hash_u64(path_args(build_runner.bcx.ws_for(unit), unit).0),
         features: format!("{:?}", unit.features),
         declared_features: format!("{declared_features:?}"),
         deps,
@@ -1722,7 +1729,14 @@ fn build_script_local_fingerprints(
     // obvious.
     let pkg_root = unit.pkg.root().to_path_buf();
     let build_dir = build_root(build_runner);
-    let env_config = Arc::clone(build_runner.bcx.gctx.env_config()?);
+    let mut env_config = Arc::clone(build_runner.bcx.gctx.env_config()?);
+    if !unit.env.is_empty() {
+        // Variables set by `--env` of a batch command take precedence, like
+        // they do when running the build script.
+        let mut vars = HashMap::clone(&env_config);
+        vars.extend(unit.env.iter().map(|(k, v)| (k.clone(), OsString::from(v))));
+        env_config = Arc::new(vars);
+    }
     let calculate =
         move |deps: &BuildDeps, pkg_fingerprint: Option<&dyn Fn() -> CargoResult<String>>| {
             if deps.rerun_if_changed.is_empty() && deps.rerun_if_env_changed.is_empty() {
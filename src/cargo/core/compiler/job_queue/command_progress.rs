@@ -0,0 +1,145 @@
+//! See [`CommandProgress`].
+
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+use super::JobId;
+
+/// Tracks how many of the commands merged into a build are done, for the
+/// progress bar of a batch.
+///
+/// A command is done once every unit its roots transitively depend on has
+/// finished, see [`BuildContext::unit_commands`]. Commands with a failed unit
+/// or that failed to set up are never done.
+///
+/// [`BuildContext::unit_commands`]: crate::core::compiler::BuildContext::unit_commands
+pub struct CommandProgress {
+    /// How many units each command still waits for.
+    remaining: Vec<usize>,
+    /// Whether each command failed to set up or has a failed unit.
+    failed: Vec<bool>,
+    /// When each active job started, to find the one running the longest.
+    started: HashMap<JobId, Instant>,
+}
+
+impl CommandProgress {
+    /// Tracks `commands` merged commands, none if this isn't a batch, of
+    /// which those in `setup_failed` have no units to wait for.
+    pub fn new(commands: usize, setup_failed: &HashSet<usize>) -> CommandProgress {
+        CommandProgress {
+            remaining: vec![0; commands],
+            failed: (0..commands).map(|i| setup_failed.contains(&i)).collect(),
+            started: HashMap::new(),
+        }
+    }
+
+    /// Whether there are commands to track.
+    pub fn is_enabled(&self) -> bool {
+        !self.remaining.is_empty()
+    }
+
+    /// Adds a unit to build for `commands`.
+    pub fn add_unit(&mut self, commands: &[usize]) {
+        for &i in commands {
+            self.remaining[i] += 1;
+        }
+    }
+
+    /// Records that job `id` started running.
+    pub fn start(&mut self, id: JobId) {
+        self.started.insert(id, Instant::now());
+    }
+
+    /// Records that job `id`, building a unit for `commands`, finished, and
+    /// returns the commands that are done now.
+    pub fn finish(&mut self, id: JobId, commands: &[usize], success: bool) -> Vec<usize> {
+        self.started.remove(&id);
+        if !success {
+            for &i in commands {
+                self.failed[i] = true;
+            }
+            return Vec::new();
+        }
+        commands
+            .iter()
+            .copied()
+            .filter(|&i| {
+                self.remaining[i] -= 1;
+                self.remaining[i] == 0 && !self.failed[i]
+            })
+            .collect()
+    }
+
+    /// The number of commands that are done.
+    pub fn done(&self) -> usize {
+        self.remaining
+            .iter()
+            .zip(&self.failed)
+            .filter(|&(&n, &failed)| n == 0 && !failed)
+            .count()
+    }
+
+    /// The number of commands tracked.
+    pub fn total(&self) -> usize {
+        self.remaining.len()
+    }
+
+    /// The active job that has been running the longest. The commands that
+    /// depend on it are blocked on it for at least as long as it still runs.
+    pub fn longest_running(&self) -> Option<JobId> {
+        self.started
+            .iter()
+            .min_by_key(|&(id, started)| (*started, id.0))
+            .map(|(&id, _)| id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::JobId;
+    use super::CommandProgress;
+    use std::collections::HashSet;
+
+    #[test]
+    fn commands_are_done_after_their_last_unit() {
+        let mut progress = CommandProgress::new(2, &HashSet::new());
+        progress.add_unit(&[0, 1]);
+        progress.add_unit(&[0]);
+        progress.add_unit(&[1]);
+        progress.start(JobId(0));
+        progress.start(JobId(1));
+        assert_eq!(progress.longest_running(), Some(JobId(0)));
+        assert_eq!(
+            progress.finish(JobId(0), &[0, 1], true),
+            Vec::<usize>::new()
+        );
+        assert_eq!(progress.longest_running(), Some(JobId(1)));
+        assert_eq!(progress.finish(JobId(1), &[0], true), [0]);
+        assert_eq!(progress.finish(JobId(2), &[1], false), Vec::<usize>::new());
+        assert_eq!(progress.done(), 1);
+        assert_eq!(progress.longest_running(), None);
+    }
+
+    #[test]
+    fn failed_commands_are_never_done() {
+        // Command 2 failed to set up and has no units.
+        let mut progress = CommandProgress::new(3, &HashSet::from([2]));
+        progress.add_unit(&[0, 1]);
+        progress.add_unit(&[0]);
+        progress.add_unit(&[1]);
+        assert_eq!(progress.done(), 0);
+        progress.start(JobId(0));
+        assert_eq!(progress.finish(JobId(0), &[0], false), Vec::<usize>::new());
+        assert_eq!(progress.done(), 0);
+        // Command 0 still waits for a unit with keep-going, but it failed.
+        progress.start(JobId(1));
+        assert_eq!(
+            progress.finish(JobId(1), &[0, 1], true),
+            Vec::<usize>::new()
+        );
+        progress.start(JobId(2));
+        assert_eq!(progress.finish(JobId(2), &[1], true), [1]);
+        assert_eq!(progress.done(), 1);
+        assert_eq!(progress.total(), 3);
+    }
+}
@@ -38,6 +38,10 @@ pub struct JobState<'a, 'gctx> {
     /// [`GlobalContext`]: crate::GlobalContext
     output: Option<&'a DiagDedupe<'gctx>>,
 
+    /// The merged commands whose roots depend on the unit, whose logs the
+    /// `output` goes to, see [`CommandLogs`](crate::core::compiler::CommandLogs).
+    commands: &'a [usize],
+
     /// The job id that this state is associated with, used when sending
     /// messages back to the main thread.
     id: JobId,
@@ -57,12 +61,14 @@ impl<'a, 'gctx> JobState<'a, 'gctx> {
         id: JobId,
         messages: Arc<Queue<Message>>,
         output: Option<&'a DiagDedupe<'gctx>>,
+        commands: &'a [usize],
         rmeta_required: bool,
     ) -> Self {
         Self {
             id,
             messages,
             output,
+            commands,
             rmeta_required: Cell::new(rmeta_required),
             _marker: marker::PhantomData,
         }
@@ -93,11 +99,9 @@ impl<'a, 'gctx> JobState<'a, 'gctx> {
 
     pub fn stderr(&self, stderr: String) -> CargoResult<()> {
         if let Some(dedupe) = self.output {
-            let mut shell = dedupe.gctx.shell();
-            shell.print_ansi_stderr(stderr.as_bytes())?;
-            shell.err().write_all(b"\n")?;
+            dedupe.emit_stderr(&stderr, self.commands)?;
         } else {
-            self.messages.push_bounded(Message::Stderr(stderr));
+            self.messages.push_bounded(Message::Stderr(self.id, stderr));
         }
         Ok(())
     }
@@ -105,7 +109,7 @@ impl<'a, 'gctx> JobState<'a, 'gctx> {
     /// See [`Message::Diagnostic`] and [`Message::WarningCount`].
     pub fn emit_diag(&self, level: &str, diag: String, fixable: bool) -> CargoResult<()> {
         if let Some(dedupe) = self.output {
-            let emitted = dedupe.emit_diag(&diag)?;
+            let emitted = dedupe.emit_diag(level, &diag, self.commands)?;
             if level == "warning" {
                 self.messages.push(Message::WarningCount {
                     id: self.id,
@@ -110,6 +110,7 @@
 //! [`push`]: Queue::push
 //! [`push_bounded`]: Queue::push_bounded
 
+mod command_progress;
 mod job;
 mod job_state;
 
@@ -118,29 +119,31 @@ use std::collections::{HashMap, HashSet};
 use std::fmt::Write as _;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::sync::Arc;
 use std::thread::{self, Scope};
 use std::time::Duration;
 
 use anyhow::{format_err, Context as _};
-use cargo_util::ProcessBuilder;
+use cargo_util::{paths, ProcessBuilder};
 use jobserver::{Acquired, HelperThread};
 use semver::Version;
 use tracing::{debug, trace};
 
+use self::command_progress::CommandProgress;
 pub use self::job::Freshness::{self, Dirty, Fresh};
 pub use self::job::{Job, Work};
 pub use self::job_state::JobState;
 use super::build_runner::OutputFile;
 use super::custom_build::Severity;
 use super::timings::Timings;
-use super::{BuildContext, BuildPlan, BuildRunner, CompileMode, Unit};
+use super::{BuildContext, BuildPlan, BuildRunner, CommandLogs, CompileMode, Unit};
 use crate::core::compiler::descriptive_pkg_name;
 use crate::core::compiler::future_incompat::{
     self, FutureBreakageItem, FutureIncompatReportPackage,
 };
 use crate::core::resolver::ResolveBehavior;
-use crate::core::{PackageId, Shell, TargetKind};
+use crate::core::{PackageId, Shell, TargetKind, Verbosity};
 use crate::util::context::WarningHandling;
 use crate::util::diagnostic_server::{self, DiagnosticPrinter};
 use crate::util::errors::AlreadyPrintedError;
@@ -157,6 +160,7 @@ pub struct JobQueue<'gctx> {
     queue: DependencyQueue<Unit, Artifact, Job>,
     counts: HashMap<PackageId, usize>,
     timings: Timings<'gctx>,
+    command_progress: CommandProgress,
 }
 
 /// This structure is backed by the `DependencyQueue` type and manages the
@@ -181,6 +185,8 @@ struct DrainState<'gctx> {
     scraped: HashSet<PackageId>,
     counts: HashMap<PackageId, usize>,
     progress: Progress<'gctx>,
+    /// Which merged commands are done, shown next to the progress bar.
+    command_progress: CommandProgress,
     next_id: u32,
     timings: Timings<'gctx>,
 
@@ -248,6 +254,19 @@ pub enum FixableWarnings {
     Positive(usize),
 }
 
+/// The outcome of a single unit of a build.
+///
+/// See [`BuildRunner::unit_results`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnitResult {
+    /// The unit was up to date and nothing was run.
+    Fresh,
+    /// The unit was built successfully.
+    Built,
+    /// The unit failed to build.
+    Failed,
+}
+
 pub struct ErrorsDuringDrain {
     pub count: usize,
 }
@@ -296,21 +315,32 @@ impl std::fmt::Display for JobId {
 struct DiagDedupe<'gctx> {
     seen: RefCell<HashSet<u64>>,
     gctx: &'gctx GlobalContext,
+    /// Where the output of units goes instead of the console, see
+    /// [`BuildRunner::command_logs`].
+    logs: Rc<RefCell<CommandLogs>>,
 }
 
 impl<'gctx> DiagDedupe<'gctx> {
-    fn new(gctx: &'gctx GlobalContext) -> Self {
+    fn new(gctx: &'gctx GlobalContext, logs: Rc<RefCell<CommandLogs>>) -> Self {
         DiagDedupe {
             seen: RefCell::new(HashSet::new()),
             gctx,
+            logs,
         }
     }
 
-    /// Emits a diagnostic message.
+    /// Emits a diagnostic message of a unit that the merged `commands`
+    /// depend on.
     ///
     /// Returns `true` if the message was emitted, or `false` if it was
-    /// suppressed for being a duplicate.
-    fn emit_diag(&self, diag: &str) -> CargoResult<bool> {
+    /// suppressed for being a duplicate. Messages written to the logs of
+    /// `commands` are never duplicates.
+    fn emit_diag(&self, level: &str, diag: &str, commands: &[usize]) -> CargoResult<bool> {
+        let mut logs = self.logs.borrow_mut();
+        if logs.logs_for(commands) {
+            logs.write_diag(commands, level, diag)?;
+            return Ok(true);
+        }
         let h = util::hash_u64(diag);
         if !self.seen.borrow_mut().insert(h) {
             return Ok(false);
@@ -320,6 +350,19 @@ impl<'gctx> DiagDedupe<'gctx> {
         shell.err().write_all(b"\n")?;
         Ok(true)
     }
+
+    /// Emits other output of a unit that the merged `commands` depend on,
+    /// like lines of the compiler's stderr that aren't diagnostics.
+    fn emit_stderr(&self, stderr: &str, commands: &[usize]) -> CargoResult<()> {
+        let mut logs = self.logs.borrow_mut();
+        if logs.logs_for(commands) {
+            return logs.write(commands, stderr);
+        }
+        let mut shell = self.gctx.shell();
+        shell.print_ansi_stderr(stderr.as_bytes())?;
+        shell.err().write_all(b"\n")?;
+        Ok(())
+    }
 }
 
 /// Possible artifacts that can be produced by compilations, used as edge values
@@ -346,7 +389,7 @@ enum Message {
     Run(JobId, String),
     BuildPlanMsg(String, ProcessBuilder, Arc<Vec<OutputFile>>),
     Stdout(String),
-    Stderr(String),
+    Stderr(JobId, String),
 
     // This is for general stderr output from subprocesses
     Diagnostic {
@@ -382,6 +425,7 @@ impl<'gctx> JobQueue<'gctx> {
             queue: DependencyQueue::new(),
             counts: HashMap::new(),
             timings: Timings::new(bcx, &bcx.roots),
+            command_progress: CommandProgress::new(bcx.command_lines.len(), &bcx.failed_commands),
         }
     }
 
@@ -461,6 +505,8 @@ impl<'gctx> JobQueue<'gctx> {
         // a smarter way using timing data from a previous compilation.
         self.queue.queue(unit.clone(), job, queue_deps, 100);
         *self.counts.entry(unit.pkg.package_id()).or_insert(0) += 1;
+        self.command_progress
+            .add_unit(unit_commands(build_runner, unit));
         Ok(())
     }
 
@@ -487,7 +533,10 @@ impl<'gctx> JobQueue<'gctx> {
             // typical messages. If you change this, please update the test
             // caching_large_output, too.
             messages: Arc::new(Queue::new(100)),
-            diag_dedupe: DiagDedupe::new(build_runner.bcx.gctx),
+            diag_dedupe: DiagDedupe::new(
+                build_runner.bcx.gctx,
+                Rc::clone(&build_runner.command_logs),
+            ),
             warning_count: HashMap::new(),
             active: HashMap::new(),
             compiled: HashSet::new(),
@@ -495,6 +544,7 @@ impl<'gctx> JobQueue<'gctx> {
             scraped: HashSet::new(),
             counts: self.counts,
             progress,
+            command_progress: self.command_progress,
             next_id: 0,
             timings: self.timings,
             tokens: Vec::new(),
@@ -582,7 +632,7 @@ impl<'gctx> DrainState<'gctx> {
                 // possible during an error.
                 self.note_working_on(
                     build_runner.bcx.gctx,
-                    build_runner.bcx.ws.root(),
+                    build_runner.bcx.ws_for(&unit).root(),
                     &unit,
                     job.freshness(),
                 )?;
@@ -619,10 +669,9 @@ impl<'gctx> DrainState<'gctx> {
             Message::Stdout(out) => {
                 writeln!(build_runner.bcx.gctx.shell().out(), "{}", out)?;
             }
-            Message::Stderr(err) => {
-                let mut shell = build_runner.bcx.gctx.shell();
-                shell.print_ansi_stderr(err.as_bytes())?;
-                shell.err().write_all(b"\n")?;
+            Message::Stderr(id, err) => {
+                let commands = unit_commands(build_runner, &self.active[&id]);
+                self.diag_dedupe.emit_stderr(&err, commands)?;
             }
             Message::Diagnostic {
                 id,
@@ -630,7 +679,8 @@ impl<'gctx> DrainState<'gctx> {
                 diag,
                 fixable,
             } => {
-                let emitted = self.diag_dedupe.emit_diag(&diag)?;
+                let commands = unit_commands(build_runner, &self.active[&id]);
+                let emitted = self.diag_dedupe.emit_diag(&level, &diag, commands)?;
                 if level == "warning" {
                     self.bump_warning_count(id, emitted, fixable);
                 }
@@ -641,7 +691,11 @@ impl<'gctx> DrainState<'gctx> {
                 }
             }
             Message::Warning { id, warning } => {
-                if warning_handling != WarningHandling::Allow {
+                let commands = unit_commands(build_runner, &self.active[&id]);
+                let mut logs = build_runner.command_logs.borrow_mut();
+                if logs.logs_for(commands) {
+                    logs.write_diag(commands, "warning", &format!("warning: {warning}"))?;
+                } else if warning_handling != WarningHandling::Allow {
                     build_runner.bcx.gctx.shell().warn(warning)?;
                 }
                 self.bump_warning_count(id, true, false);
@@ -668,7 +722,9 @@ impl<'gctx> DrainState<'gctx> {
                             id,
                             &build_runner.bcx.rustc().workspace_wrapper,
                         );
-                        self.active.remove(&id).unwrap()
+                        let unit = self.active.remove(&id).unwrap();
+                        self.note_commands_done(id, &unit, result.is_ok(), build_runner)?;
+                        unit
                     }
                     // ... otherwise if it hasn't finished we leave it
                     // in there as we'll get another `Finish` later on.
@@ -679,7 +735,16 @@ impl<'gctx> DrainState<'gctx> {
                 };
                 debug!("end ({:?}): {:?}", unit, result);
                 match result {
-                    Ok(()) => self.finish(id, &unit, artifact, build_runner)?,
+                    Ok(()) => {
+                        if let Artifact::All = artifact {
+                            build_runner
+                                .unit_results
+                                .borrow_mut()
+                                .entry(unit.clone())
+                                .or_insert(UnitResult::Built);
+                        }
+                        self.finish(id, &unit, artifact, build_runner)?
+                    }
                     Err(_) if build_runner.bcx.unit_can_fail_for_docscraping(&unit) => {
                         build_runner
                             .failed_scrape_units
@@ -689,9 +754,26 @@ impl<'gctx> DrainState<'gctx> {
                         self.queue.finish(&unit, &artifact);
                     }
                     Err(error) => {
+                        build_runner
+                            .unit_results
+                            .borrow_mut()
+                            .insert(unit.clone(), UnitResult::Failed);
                         let show_warnings = true;
                         self.emit_log_messages(&unit, build_runner, show_warnings)?;
                         self.back_compat_notice(build_runner, &unit)?;
+                        let commands = unit_commands(build_runner, &unit);
+                        let mut logs = build_runner.command_logs.borrow_mut();
+                        let error = if logs.logs_for(commands) {
+                            // Only the outermost message goes to the console,
+                            // the logs get the causes, like the output of a
+                            // failed build script.
+                            let verbose =
+                                build_runner.bcx.gctx.shell().verbosity() == Verbosity::Verbose;
+                            logs.write_error(commands, &error, verbose)?;
+                            format_err!("{error}")
+                        } else {
+                            error
+                        };
                         return Err(ErrorToHandle {
                             error,
                             print_always: true,
@@ -720,7 +802,7 @@ impl<'gctx> DrainState<'gctx> {
     }
 
     // This will also tick the progress bar as appropriate
-    fn wait_for_events(&mut self) -> Vec<Message> {
+    fn wait_for_events(&mut self, build_runner: &BuildRunner<'_, '_>) -> Vec<Message> {
         // Drain all events at once to avoid displaying the progress bar
         // unnecessarily. If there's no events we actually block waiting for
         // an event, but we keep a "heartbeat" going to allow `record_cpu`
@@ -730,7 +812,7 @@ impl<'gctx> DrainState<'gctx> {
         let mut events = self.messages.try_pop_all();
         if events.is_empty() {
             loop {
-                self.tick_progress();
+                self.tick_progress(build_runner);
                 self.tokens.truncate(self.active.len() - 1);
                 match self.messages.pop(Duration::from_millis(500)) {
                     Some(message) => {
@@ -791,7 +873,7 @@ impl<'gctx> DrainState<'gctx> {
             // jobserver interface is architected we may acquire a token that we
             // don't actually use, and if this happens just relinquish it back
             // to the jobserver itself.
-            for event in self.wait_for_events() {
+            for event in self.wait_for_events(build_runner) {
                 if let Err(event_err) = self.handle_event(build_runner, plan, event) {
                     self.handle_error(&mut build_runner.bcx.gctx.shell(), &mut errors, event_err);
                 }
@@ -881,7 +963,7 @@ impl<'gctx> DrainState<'gctx> {
     // This also records CPU usage and marks concurrency; we roughly want to do
     // this as often as we spin on the events receiver (at least every 500ms or
     // so).
-    fn tick_progress(&mut self) {
+    fn tick_progress(&mut self, build_runner: &BuildRunner<'_, '_>) {
         // Record some timing information if `--timings` is enabled, and
         // this'll end up being a noop if we're not recording this
         // information.
@@ -897,11 +979,64 @@ impl<'gctx> DrainState<'gctx> {
             .values()
             .map(|u| self.name_for_progress(u))
             .collect::<Vec<_>>();
-        let _ = self.progress.tick_now(
-            self.finished,
-            self.total_units,
-            &format!(": {}", active_names.join(", ")),
-        );
+        let mut msg = format!(": {}", active_names.join(", "));
+        if self.command_progress.is_enabled() {
+            msg = format!(
+                ", {}/{} commands{}{}",
+                self.command_progress.done(),
+                self.command_progress.total(),
+                self.blocked_commands(build_runner),
+                msg
+            );
+        }
+        let _ = self
+            .progress
+            .tick_now(self.finished, self.total_units, &msg);
+    }
+
+    /// Describes the commands blocked on the unit that has been running the
+    /// longest, if there is one, for the progress bar.
+    fn blocked_commands(&self, build_runner: &BuildRunner<'_, '_>) -> String {
+        let Some(id) = self.command_progress.longest_running() else {
+            return String::new();
+        };
+        let unit = &self.active[&id];
+        let labels = unit_commands(build_runner, unit)
+            .iter()
+            .map(|&i| build_runner.bcx.command_label(i))
+            .collect::<Vec<_>>();
+        if labels.is_empty() {
+            return String::new();
+        }
+        format!(
+            " ({} blocks {})",
+            self.name_for_progress(unit),
+            labels.join(", ")
+        )
+    }
+
+    /// Updates [`DrainState::command_progress`] after job `id` building
+    /// `unit` finished, listing the commands it completed with `-v`.
+    fn note_commands_done(
+        &mut self,
+        id: JobId,
+        unit: &Unit,
+        success: bool,
+        build_runner: &BuildRunner<'_, '_>,
+    ) -> CargoResult<()> {
+        let commands = unit_commands(build_runner, unit);
+        let done = self.command_progress.finish(id, commands, success);
+        let bcx = build_runner.bcx;
+        for i in done {
+            let msg = format!(
+                "{} ({}/{} commands)",
+                bcx.command_label(i),
+                self.command_progress.done(),
+                self.command_progress.total()
+            );
+            bcx.gctx.shell().verbose(|s| s.status("Completed", &msg))?;
+        }
+        Ok(())
     }
 
     fn name_for_progress(&self, unit: &Unit) -> String {
@@ -950,26 +1085,31 @@ impl<'gctx> DrainState<'gctx> {
         debug!("start {}: {:?}", id, unit);
 
         assert!(self.active.insert(id, unit.clone()).is_none());
+        self.command_progress.start(id);
 
         let messages = self.messages.clone();
         let is_fresh = job.freshness().is_fresh();
         let rmeta_required = build_runner.rmeta_required(unit);
 
-        let doit = move |diag_dedupe| {
-            let state = JobState::new(id, messages, diag_dedupe, rmeta_required);
+        let doit = move |diag_dedupe, commands| {
+            let state = JobState::new(id, messages, diag_dedupe, commands, rmeta_required);
             state.run_to_finish(job);
         };
 
         match is_fresh {
             true => {
                 self.timings.add_fresh();
+                build_runner
+                    .unit_results
+                    .borrow_mut()
+                    .insert(unit.clone(), UnitResult::Fresh);
                 // Running a fresh job on the same thread is often much faster than spawning a new
                 // thread to run the job.
-                doit(Some(&self.diag_dedupe));
+                doit(Some(&self.diag_dedupe), unit_commands(build_runner, unit));
             }
             false => {
                 self.timings.add_dirty();
-                scope.spawn(move || doit(None));
+                scope.spawn(move || doit(None, &[]));
             }
         }
     }
@@ -984,7 +1124,9 @@ impl<'gctx> DrainState<'gctx> {
         let Some(metadata) = build_runner.find_build_script_metadata(unit) else {
             return Ok(());
         };
-        let bcx = &mut build_runner.bcx;
+        let commands = unit_commands(build_runner, unit);
+        let mut logs = build_runner.command_logs.borrow_mut();
+        let bcx = &build_runner.bcx;
         if let Some(output) = outputs.get(metadata) {
             if !output.log_messages.is_empty()
                 && (show_warnings
@@ -997,6 +1139,15 @@ impl<'gctx> DrainState<'gctx> {
                     |msg: &str| format!("{}@{}: {}", unit.pkg.name(), unit.pkg.version(), msg);
 
                 for (severity, message) in output.log_messages.iter() {
+                    if logs.logs_for(commands) {
+                        let level = match severity {
+                            Severity::Error => "error",
+                            Severity::Warning => "warning",
+                        };
+                        let diag = format!("{level}: {}", msg_with_package(message));
+                        logs.write_diag(commands, level, &diag)?;
+                        continue;
+                    }
                     match severity {
                         Severity::Error => {
                             bcx.gctx.shell().error(msg_with_package(message))?;
@@ -1012,6 +1163,36 @@ impl<'gctx> DrainState<'gctx> {
         Ok(())
     }
 
+    /// Writes what a build script printed to the logs of the commands that
+    /// depend on it, see [`CommandLogs`]. The output of failed build scripts
+    /// is part of their error instead.
+    fn log_build_script_output(
+        &self,
+        unit: &Unit,
+        build_runner: &BuildRunner<'_, '_>,
+    ) -> CargoResult<()> {
+        let commands = unit_commands(build_runner, unit);
+        let mut logs = build_runner.command_logs.borrow_mut();
+        if !logs.logs_for(commands) {
+            return Ok(());
+        }
+        let run_dir = build_runner.files().build_script_run_dir(unit);
+        let read = |name| paths::read_bytes(&run_dir.join(name)).unwrap_or_default();
+        let (stdout, stderr) = (read("output"), read("stderr"));
+        if stdout.is_empty() && stderr.is_empty() {
+            return Ok(());
+        }
+        logs.write(
+            commands,
+            &format!(
+                "build script output of `{}`:\n--- stdout\n{}\n--- stderr\n{}",
+                unit.pkg.package_id(),
+                String::from_utf8_lossy(&stdout).trim_end(),
+                String::from_utf8_lossy(&stderr).trim_end()
+            ),
+        )
+    }
+
     fn bump_warning_count(&mut self, id: JobId, emitted: bool, fixable: bool) {
         let cnts = self.warning_count.entry(id).or_default();
         cnts.total += 1;
@@ -1049,6 +1230,13 @@ impl<'gctx> DrainState<'gctx> {
         };
         runner.compilation.warning_count += count.total;
         let unit = &self.active[&id];
+        if runner
+            .command_logs
+            .borrow()
+            .logs_for(unit_commands(runner, unit))
+        {
+            return;
+        }
         let mut message = descriptive_pkg_name(&unit.pkg.name(), &unit.target, &unit.mode);
         message.push_str(" generated ");
         match count.total {
@@ -1122,6 +1310,7 @@ impl<'gctx> DrainState<'gctx> {
                 build_runner,
                 unit.show_warnings(build_runner.bcx.gctx),
             )?;
+            self.log_build_script_output(unit, build_runner)?;
         }
         let unlocked = self.queue.finish(unit, &artifact);
         match artifact {
@@ -1202,7 +1391,7 @@ impl<'gctx> DrainState<'gctx> {
     ) -> CargoResult<()> {
         if unit.pkg.name() != "diesel"
             || unit.pkg.version() >= &Version::new(1, 4, 8)
-            || build_runner.bcx.ws.resolve_behavior() == ResolveBehavior::V1
+            || build_runner.bcx.ws_for(unit).resolve_behavior() == ResolveBehavior::V1
             || !unit.pkg.package_id().source_id().is_registry()
             || !unit.features.is_empty()
         {
@@ -1235,3 +1424,13 @@ impl ErrorsDuringDrain {
         }
     }
 }
+
+/// The merged commands whose roots depend on `unit`, see
+/// [`BuildContext::unit_commands`].
+fn unit_commands<'b>(build_runner: &'b BuildRunner<'_, '_>, unit: &Unit) -> &'b [usize] {
+    build_runner
+        .bcx
+        .unit_commands
+        .get(unit)
+        .map_or(&[], Vec::as_slice)
+}
@@ -36,6 +36,7 @@ mod build_config;
 pub(crate) mod build_context;
 mod build_plan;
 pub(crate) mod build_runner;
+mod command_logs;
 mod compilation;
 mod compile_kind;
 mod crate_type;
@@ -46,6 +47,7 @@ pub(crate) mod job_queue;
 pub(crate) mod layout;
 mod links;
 mod lto;
+mod objcopy;
 mod output_depinfo;
 mod output_sbom;
 pub mod rustdoc;
@@ -69,19 +71,22 @@ use anyhow::{Context as _, Error};
 use lazycell::LazyCell;
 use tracing::{debug, trace};
 
-pub use self::build_config::{BuildConfig, CompileMode, MessageFormat, TimingOutput};
+pub use self::build_config::{
+    BuildConfig, CompileMode, ExportFormat, LinkMethod, MessageFormat, TimingOutput,
+};
 pub use self::build_context::{
-    BuildContext, FileFlavor, FileType, RustDocFingerprint, RustcTargetData, TargetInfo,
+    BuildContext, FileFlavor, FileType, RustDocFingerprint, RustcTargetData, TargetInfo, UnitConfig,
 };
 use self::build_plan::BuildPlan;
-pub use self::build_runner::{BuildRunner, Metadata, UnitHash};
+pub use self::build_runner::{BuildRunner, Metadata, OutputFile, UnitHash};
+pub use self::command_logs::{CommandLog, CommandLogs};
 pub use self::compilation::{Compilation, Doctest, UnitOutput};
 pub use self::compile_kind::{CompileKind, CompileKindFallback, CompileTarget};
 pub use self::crate_type::CrateType;
 pub use self::custom_build::LinkArgTarget;
 pub use self::custom_build::{BuildOutput, BuildScriptOutputs, BuildScripts, LibraryPath};
 pub(crate) use self::fingerprint::DirtyReason;
-pub use self::job_queue::Freshness;
+pub use self::job_queue::{Freshness, UnitResult};
 use self::job_queue::{Job, JobQueue, JobState, Work};
 pub(crate) use self::layout::Layout;
 pub use self::lto::Lto;
@@ -212,8 +217,9 @@ fn compile<'gctx>(
                 PathBuf::from(unit.pkg.manifest_path()),
                 &unit.target,
                 build_runner.files().message_cache_path(unit),
-                build_runner.bcx.build_config.message_format,
+                build_runner.bcx.build_config_for(unit).message_format,
                 show_diagnostics,
+                build_runner.bcx.batch_commands_for(unit),
             );
             // Need to link targets on both the dirty and fresh.
             work.then(link_targets(build_runner, unit, true)?)
@@ -244,7 +250,7 @@ fn make_failed_scrape_diagnostic(
 ) -> String {
     let manifest_path = unit.pkg.manifest_path();
     let relative_manifest_path = manifest_path
-        .strip_prefix(build_runner.bcx.ws.root())
+        .strip_prefix(build_runner.bcx.ws_for(unit).root())
         .unwrap_or(&manifest_path);
 
     format!(
@@ -300,7 +306,7 @@ fn rustc(
     exec.init(build_runner, unit);
     let exec = exec.clone();
 
-    let root_output = build_runner.files().host_dest().to_path_buf();
+    let root_output = build_runner.files().host_dest_for(unit).to_path_buf();
     let build_dir = build_runner.bcx.ws.build_dir().into_path_unlocked();
     let pkg_root = unit.pkg.root().to_path_buf();
     let cwd = rustc
@@ -571,6 +577,40 @@ fn verbose_if_simple_exit_code(err: Error) -> Error {
     }
 }
 
+/// Makes `dst` refer to the compiler output `src` as selected by `method`.
+fn link_output(method: LinkMethod, src: &Path, dst: &Path) -> CargoResult<()> {
+    paths::create_dir_all(dst.parent().unwrap())?;
+    // Directories, like `.dSYM` bundles, are always symlinked.
+    if method == LinkMethod::Hardlink || src.is_dir() {
+        return paths::link_or_copy(src, dst);
+    }
+    // `dst` may be a link to `src` from an earlier build, which must not be
+    // written through.
+    if fs::symlink_metadata(dst).is_ok() {
+        paths::remove_file(dst)?;
+    }
+    match method {
+        LinkMethod::Symlink => {
+            #[cfg(unix)]
+            let result = std::os::unix::fs::symlink(src, dst);
+            #[cfg(windows)]
+            let result = std::os::windows::fs::symlink_file(src, dst);
+            result.with_context(|| {
+                format!(
+                    "failed to symlink `{}` to `{}`",
+                    dst.display(),
+                    src.display()
+                )
+            })?;
+        }
+        LinkMethod::Copy => {
+            paths::copy(src, dst)?;
+        }
+        LinkMethod::Hardlink => unreachable!(),
+    }
+    Ok(())
+}
+
 /// Link the compiled target (often of form `foo-{metadata_hash}`) to the
 /// final target. This must happen during both "Fresh" and "Compile".
 fn link_targets(
@@ -585,7 +625,9 @@ fn link_targets(
     let profile = unit.profile.clone();
     let unit_mode = unit.mode;
     let features = unit.features.iter().map(|s| s.to_string()).collect();
-    let json_messages = bcx.build_config.emit_json();
+    let json_messages = bcx.build_config_for(unit).emit_json();
+    let batch_commands = bcx.batch_commands_for(unit);
+    let link_method = bcx.link_method;
     let executable = build_runner.get_executable(unit)?;
     let mut target = Target::clone(&unit.target);
     if let TargetSourcePath::Metabuild = target.src_path() {
@@ -612,16 +654,25 @@ fn link_targets(
             }
 
             if let Some(dst) = &output.hardlink {
-                paths::create_dir_all(dst.parent().unwrap())?;
-                paths::link_or_copy(src, dst)?;
+                link_output(link_method, src, dst)?;
                 destinations.push(dst.clone());
             }
             if let Some(dst) = &output.export_path {
-                paths::create_dir_all(dst.parent().unwrap())?;
-                paths::link_or_copy(src, dst)?;
+                link_output(link_method, src, dst)?;
                 destinations.push(dst.clone());
             }
-            if output.hardlink.is_none() && output.export_path.is_none() {
+            for (format, dst) in &output.export_formats {
+                objcopy::convert(*format, src, dst)?;
+                destinations.push(dst.clone());
+            }
+            for (_, dst) in &output.command_paths {
+                link_output(link_method, src, dst)?;
+                destinations.push(dst.clone());
+            }
+            if output.hardlink.is_none()
+                && output.export_path.is_none()
+                && output.command_paths.is_empty()
+            {
                 destinations.push(src.clone());
             }
         }
@@ -655,6 +706,7 @@ fn link_targets(
                 filenames: destinations,
                 executable,
                 fresh,
+                batch_commands,
             }
             .to_json_string();
             state.stdout(msg)?;
@@ -731,7 +783,7 @@ where
 fn prepare_rustc(build_runner: &BuildRunner<'_, '_>, unit: &Unit) -> CargoResult<ProcessBuilder> {
     let gctx = build_runner.bcx.gctx;
     let is_primary = build_runner.is_primary_package(unit);
-    let is_workspace = build_runner.bcx.ws.is_member(&unit.pkg);
+    let is_workspace = build_runner.bcx.ws_for(unit).is_member(&unit.pkg);
 
     let mut base = build_runner
         .compilation
@@ -759,7 +811,7 @@ fn prepare_rustc(build_runner: &BuildRunner<'_, '_>, unit: &Unit) -> CargoResult
     }
 
     if unit.target.is_test() || unit.target.is_bench() {
-        let tmp = build_runner.files().layout(unit.kind).prepare_tmp()?;
+        let tmp = build_runner.files().unit_layout(unit).prepare_tmp()?;
         base.env("CARGO_TARGET_TMPDIR", tmp.display().to_string());
     }
 
@@ -779,7 +831,7 @@ fn prepare_rustdoc(build_runner: &BuildRunner<'_, '_>, unit: &Unit) -> CargoResu
     rustdoc.inherit_jobserver(&build_runner.jobserver);
     let crate_name = unit.target.crate_name();
     rustdoc.arg("--crate-name").arg(&crate_name);
-    add_path_args(bcx.ws, unit, &mut rustdoc);
+    add_path_args(bcx.ws_for(unit), unit, &mut rustdoc);
     add_cap_lints(bcx, unit, &mut rustdoc);
 
     if let CompileKind::Target(target) = unit.kind {
@@ -790,7 +842,7 @@ fn prepare_rustdoc(build_runner: &BuildRunner<'_, '_>, unit: &Unit) -> CargoResu
     rustdoc.args(&features_args(unit));
     rustdoc.args(&check_cfg_args(unit));
 
-    add_error_format_and_color(build_runner, &mut rustdoc);
+    add_error_format_and_color(build_runner, unit, &mut rustdoc);
     add_allow_features(build_runner, &mut rustdoc);
 
     if build_runner.bcx.gctx.cli_unstable().rustdoc_depinfo {
@@ -1070,11 +1122,15 @@ fn add_allow_features(build_runner: &BuildRunner<'_, '_>, cmd: &mut ProcessBuild
 /// which Cargo will extract and display to the user.
 ///
 /// [`--error-format`]: https://doc.rust-lang.org/nightly/rustc/command-line-arguments.html#--error-format-control-how-errors-are-produced
-fn add_error_format_and_color(build_runner: &BuildRunner<'_, '_>, cmd: &mut ProcessBuilder) {
+fn add_error_format_and_color(
+    build_runner: &BuildRunner<'_, '_>,
+    unit: &Unit,
+    cmd: &mut ProcessBuilder,
+) {
     cmd.arg("--error-format=json");
     let mut json = String::from("--json=diagnostic-rendered-ansi,artifacts,future-incompat");
 
-    match build_runner.bcx.build_config.message_format {
+    match build_runner.bcx.build_config_for(unit).message_format {
         MessageFormat::Short | MessageFormat::Json { short: true, .. } => {
             json.push_str(",diagnostic-short");
         }
@@ -1120,8 +1176,8 @@ fn build_base_args(
     let edition = unit.target.edition();
     edition.cmd_edition_arg(cmd);
 
-    add_path_args(bcx.ws, unit, cmd);
-    add_error_format_and_color(build_runner, cmd);
+    add_path_args(bcx.ws_for(unit), unit, cmd);
+    add_error_format_and_color(build_runner, unit, cmd);
     add_allow_features(build_runner, cmd);
 
     let mut contains_dy_lib = false;
@@ -1277,7 +1333,7 @@ fn build_base_args(
     if incremental {
         let dir = build_runner
             .files()
-            .layout(unit.kind)
+            .unit_layout(unit)
             .incremental()
             .as_os_str();
         opt(cmd, "-C", "incremental=", Some(dir));
@@ -1422,7 +1478,7 @@ fn sysroot_remap(build_runner: &BuildRunner<'_, '_>, unit: &Unit) -> OsString {
 ///     * otherwise remapped to `<pkg>-<version>`.
 fn package_remap(build_runner: &BuildRunner<'_, '_>, unit: &Unit) -> OsString {
     let pkg_root = unit.pkg.root();
-    let ws_root = build_runner.bcx.ws.root();
+    let ws_root = build_runner.bcx.ws_for(unit).root();
     let mut remap = OsString::from("--remap-path-prefix=");
     let source_id = unit.pkg.package_id().source_id();
     if source_id.is_git() {
@@ -1548,7 +1604,7 @@ fn build_deps_args(
     if !unit.kind.is_host() {
         cmd.arg("-L").arg(&{
             let mut deps = OsString::from("dependency=");
-            deps.push(build_runner.files().host_deps());
+            deps.push(build_runner.files().host_deps_for(unit));
             deps
         });
     }
@@ -1736,6 +1792,8 @@ struct OutputOptions {
     warnings_seen: usize,
     /// Tracks the number of errors we've seen so far.
     errors_seen: usize,
+    /// The batch commands to tag compiler messages with.
+    batch_commands: Vec<machine_message::BatchCommand>,
 }
 
 impl OutputOptions {
@@ -1747,11 +1805,12 @@ impl OutputOptions {
         let show_diagnostics =
             build_runner.bcx.gctx.warning_handling().unwrap_or_default() != WarningHandling::Allow;
         OutputOptions {
-            format: build_runner.bcx.build_config.message_format,
+            format: build_runner.bcx.build_config_for(unit).message_format,
             cache_cell,
             show_diagnostics,
             warnings_seen: 0,
             errors_seen: 0,
+            batch_commands: build_runner.bcx.batch_commands_for(unit),
         }
     }
 }
@@ -1990,6 +2049,7 @@ fn on_stderr_line_inner(
         manifest_path,
         target,
         message: compiler_message,
+        batch_commands: options.batch_commands.clone(),
     }
     .to_json_string();
 
@@ -2010,6 +2070,7 @@ fn replay_output_cache(
     path: PathBuf,
     format: MessageFormat,
     show_diagnostics: bool,
+    batch_commands: Vec<machine_message::BatchCommand>,
 ) -> Work {
     let target = target.clone();
     let mut options = OutputOptions {
@@ -2018,6 +2079,7 @@ fn replay_output_cache(
         show_diagnostics,
         warnings_seen: 0,
         errors_seen: 0,
+        batch_commands,
     };
     Work::new(move |state| {
         if !path.exists() {
@@ -2083,7 +2145,7 @@ pub(crate) fn apply_env_config(
 
 /// Checks if there are some scrape units waiting to be processed.
 fn should_include_scrape_units(bcx: &BuildContext<'_, '_>, unit: &Unit) -> bool {
-    unit.mode.is_doc() && bcx.scrape_units.len() > 0 && bcx.ws.unit_needs_doc_scrape(unit)
+    unit.mode.is_doc() && bcx.scrape_units.len() > 0 && bcx.ws_for(unit).unit_needs_doc_scrape(unit)
 }
 
 /// Gets the file path of function call information output from `rustdoc`.
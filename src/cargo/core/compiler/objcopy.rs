@@ -0,0 +1,197 @@
+//! Converting exported executables to the formats of [`ExportFormat`], like
+//! `objcopy` would, but without spawning a process per file.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::Path;
+
+use anyhow::Context as _;
+use cargo_util::paths;
+
+use super::ExportFormat;
+use crate::util::elf::Elf;
+use crate::util::CargoResult;
+
+/// The largest raw binary written, to catch executables whose sections are
+/// far apart, like most hosted ones.
+const MAX_BIN_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Bytes of the program carried by each UF2 block.
+const UF2_PAYLOAD_SIZE: usize = 256;
+
+/// Converts the ELF executable `src` to `format`, written to `dst`.
+pub fn convert(format: ExportFormat, src: &Path, dst: &Path) -> CargoResult<()> {
+    let data = paths::read_bytes(src)?;
+    let contents = (|| {
+        let elf = Elf::parse(&data).context("not an ELF file")?;
+        let image = elf.load_image().context("malformed ELF file")?;
+        if image.is_empty() {
+            anyhow::bail!("no loadable sections");
+        }
+        match format {
+            ExportFormat::Bin => to_bin(&image),
+            ExportFormat::Hex => to_hex(&image, elf.entry()),
+            ExportFormat::Uf2 { family_id } => to_uf2(&image, family_id),
+        }
+    })()
+    .with_context(|| {
+        format!(
+            "failed to convert `{}` to {}",
+            src.display(),
+            format.extension()
+        )
+    })?;
+    paths::write(dst, contents)
+}
+
+/// Lays out the image in a single buffer starting at its lowest address,
+/// filling gaps with zeros.
+fn to_bin(image: &[(u64, &[u8])]) -> CargoResult<Vec<u8>> {
+    let start = image[0].0;
+    let end = image
+        .iter()
+        .map(|(addr, data)| addr + data.len() as u64)
+        .max()
+        .unwrap();
+    if end - start > MAX_BIN_SIZE {
+        anyhow::bail!(
+            "the sections span {} bytes from {start:#x} to {end:#x}",
+            end - start
+        );
+    }
+    let mut bin = vec![0; (end - start) as usize];
+    for (addr, data) in image {
+        let offset = (addr - start) as usize;
+        bin[offset..offset + data.len()].copy_from_slice(data);
+    }
+    Ok(bin)
+}
+
+/// Writes the image as Intel HEX records of up to 16 bytes, with the entry
+/// point as start address.
+fn to_hex(image: &[(u64, &[u8])], entry: Option<u64>) -> CargoResult<Vec<u8>> {
+    fn record(out: &mut String, addr: u16, kind: u8, data: &[u8]) {
+        let mut bytes = vec![data.len() as u8];
+        bytes.extend(addr.to_be_bytes());
+        bytes.push(kind);
+        bytes.extend(data);
+        let checksum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        out.push(':');
+        for b in bytes.iter().chain([&checksum.wrapping_neg()]) {
+            write!(out, "{b:02X}").unwrap();
+        }
+        out.push('\n');
+    }
+
+    let mut out = String::new();
+    let mut upper = None;
+    for &(addr, data) in image {
+        if addr + data.len() as u64 > 1 << 32 {
+            anyhow::bail!("address {addr:#x} does not fit in 32 bits");
+        }
+        let mut addr = addr as u32;
+        let mut data = data;
+        while !data.is_empty() {
+            if upper != Some(addr >> 16) {
+                upper = Some(addr >> 16);
+                record(&mut out, 0, 0x04, &((addr >> 16) as u16).to_be_bytes());
+            }
+            // Records don't cross 64 KiB boundaries.
+            let len = data.len().min(16).min(0x10000 - (addr & 0xffff) as usize);
+            record(&mut out, addr as u16, 0x00, &data[..len]);
+            addr = addr.wrapping_add(len as u32);
+            data = &data[len..];
+        }
+    }
+    if let Some(entry) = entry.and_then(|entry| u32::try_from(entry).ok()) {
+        record(&mut out, 0, 0x05, &entry.to_be_bytes());
+    }
+    record(&mut out, 0, 0x01, &[]);
+    Ok(out.into_bytes())
+}
+
+/// Writes the image as UF2 blocks, each carrying a 256 byte aligned page of
+/// the image. Gaps within a page are filled with zeros.
+fn to_uf2(image: &[(u64, &[u8])], family_id: u32) -> CargoResult<Vec<u8>> {
+    const MAGIC_START0: u32 = 0x0A324655;
+    const MAGIC_START1: u32 = 0x9E5D5157;
+    const MAGIC_END: u32 = 0x0AB16F30;
+    const FLAG_FAMILY_ID_PRESENT: u32 = 0x00002000;
+
+    let mut pages: BTreeMap<u32, [u8; UF2_PAYLOAD_SIZE]> = BTreeMap::new();
+    for &(addr, data) in image {
+        if addr + data.len() as u64 > 1 << 32 {
+            anyhow::bail!("address {addr:#x} does not fit in 32 bits");
+        }
+        for (i, &b) in data.iter().enumerate() {
+            let addr = addr as u32 + i as u32;
+            let page = addr & !(UF2_PAYLOAD_SIZE as u32 - 1);
+            pages.entry(page).or_insert([0; UF2_PAYLOAD_SIZE])[(addr - page) as usize] = b;
+        }
+    }
+
+    let mut out = Vec::with_capacity(pages.len() * 512);
+    for (block, (addr, payload)) in pages.iter().enumerate() {
+        for word in [
+            MAGIC_START0,
+            MAGIC_START1,
+            FLAG_FAMILY_ID_PRESENT,
+            *addr,
+            UF2_PAYLOAD_SIZE as u32,
+            block as u32,
+            pages.len() as u32,
+            family_id,
+        ] {
+            out.extend(word.to_le_bytes());
+        }
+        out.extend(payload);
+        out.resize(out.len() + 476 - UF2_PAYLOAD_SIZE, 0);
+        out.extend(MAGIC_END.to_le_bytes());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_bin, to_hex, to_uf2};
+
+    #[test]
+    fn bin_fills_gaps() {
+        let image = [(0x100, &[1, 2][..]), (0x104, &[3][..])];
+        assert_eq!(to_bin(&image).unwrap(), [1, 2, 0, 0, 3]);
+    }
+
+    #[test]
+    fn hex_records() {
+        let image = [(0x0800_fffe, &[1, 2, 3][..])];
+        let hex = String::from_utf8(to_hex(&image, Some(0x0800_0101)).unwrap()).unwrap();
+        assert_eq!(
+            hex,
+            ":020000040800F2\n\
+             :02FFFE000102FE\n\
+             :020000040801F1\n\
+             :0100000003FC\n\
+             :0400000508000101ED\n\
+             :00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn uf2_blocks() {
+        let image = [(0x1000_00ff, &[0xaa, 0xbb][..])];
+        let uf2 = to_uf2(&image, 0xe48bff56).unwrap();
+        assert_eq!(uf2.len(), 1024);
+        let word = |block: usize, i: usize| {
+            let offset = block * 512 + i * 4;
+            u32::from_le_bytes(uf2[offset..offset + 4].try_into().unwrap())
+        };
+        assert_eq!(word(0, 3), 0x1000_0000);
+        assert_eq!(word(1, 3), 0x1000_0100);
+        assert_eq!(word(1, 5), 1);
+        assert_eq!(word(1, 6), 2);
+        assert_eq!(word(1, 7), 0xe48bff56);
+        assert_eq!(word(1, 127), 0x0AB16F30);
+        assert_eq!(uf2[32 + 0xff], 0xaa);
+        assert_eq!(uf2[512 + 32], 0xbb);
+    }
+}
@@ -206,6 +206,7 @@ fn generate_roots(
                 target_data.info(kind).rustflags.clone(),
                 target_data.info(kind).rustdocflags.clone(),
                 target_data.target_config(kind).links_overrides.clone(),
+                target_data.env.clone(),
                 /*is_std*/ true,
                 /*dep_hash*/ 0,
                 IsArtifact::No,
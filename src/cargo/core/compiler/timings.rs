@@ -81,6 +81,21 @@ struct UnitTime {
     unlocked_rmeta_units: Vec<Unit>,
 }
 
+/// How much of the build a single merged command accounts for.
+///
+/// Only used when several commands were merged into one build, like
+/// `cargo-batch` does.
+struct CommandTime {
+    /// Index into [`BuildContext::command_lines`].
+    index: usize,
+    /// Number of units the command's roots depend on.
+    units: usize,
+    /// Number of those units that other commands depend on as well.
+    shared_units: usize,
+    /// Total time spent building the command's units, in seconds.
+    duration: f64,
+}
+
 /// Periodic concurrency tracking information.
 #[derive(serde::Serialize)]
 struct Concurrency {
@@ -297,6 +312,21 @@ impl<'gctx> Timings<'gctx> {
         self.mark_concurrency(0, 0, 0);
         self.unit_times
             .sort_unstable_by(|a, b| a.start.partial_cmp(&b.start).unwrap());
+        if self.report_json {
+            let bcx = build_runner.bcx;
+            for time in self.command_times(bcx) {
+                let msg = machine_message::BatchCommandTiming {
+                    index: bcx.command_indices[time.index],
+                    name: bcx.command_names.get(time.index).and_then(Option::as_deref),
+                    command: &bcx.command_lines[time.index],
+                    units: time.units,
+                    shared_units: time.shared_units,
+                    duration: time.duration,
+                }
+                .to_json_string();
+                crate::drop_println!(self.gctx, "{}", msg);
+            }
+        }
         if self.report_html {
             self.report_html(build_runner, error)
                 .context("failed to save timing report")?;
@@ -323,6 +353,7 @@ impl<'gctx> Timings<'gctx> {
             .collect();
         f.write_all(HTML_TMPL.replace("{ROOTS}", &roots.join(", ")).as_bytes())?;
         self.write_summary_table(&mut f, duration, build_runner.bcx, error)?;
+        self.write_command_table(&mut f, build_runner.bcx)?;
         f.write_all(HTML_CANVAS.as_bytes())?;
         self.write_unit_table(&mut f)?;
         // It helps with pixel alignment to use whole numbers.
@@ -435,6 +466,90 @@ impl<'gctx> Timings<'gctx> {
         Ok(())
     }
 
+    /// Splits the build up by the merged commands that requested it.
+    ///
+    /// This is empty unless several commands were merged into this build.
+    fn command_times(&self, bcx: &BuildContext<'_, '_>) -> Vec<CommandTime> {
+        let durations: HashMap<&Unit, f64> = self
+            .unit_times
+            .iter()
+            .map(|ut| (&ut.unit, ut.duration))
+            .collect();
+        let mut times: Vec<CommandTime> = (0..bcx.command_lines.len())
+            .map(|index| CommandTime {
+                index,
+                units: 0,
+                shared_units: 0,
+                duration: 0.0,
+            })
+            .collect();
+        for (unit, commands) in &bcx.unit_commands {
+            for &index in commands {
+                let time = &mut times[index];
+                time.units += 1;
+                if commands.len() > 1 {
+                    time.shared_units += 1;
+                }
+                time.duration += durations.get(unit).copied().unwrap_or(0.0);
+            }
+        }
+        times
+    }
+
+    /// Render the table of merged commands, if any.
+    fn write_command_table(
+        &self,
+        f: &mut impl Write,
+        bcx: &BuildContext<'_, '_>,
+    ) -> CargoResult<()> {
+        let times = self.command_times(bcx);
+        if times.is_empty() {
+            return Ok(());
+        }
+        write!(
+            f,
+            r#"
+<table class="my-table">
+  <thead>
+    <tr>
+      <th></th>
+      <th>Command</th>
+      <th>Units</th>
+      <th>Shared</th>
+      <th>Total</th>
+    </tr>
+  </thead>
+  <tbody>
+"#
+        )?;
+        for time in times {
+            let line = html_escape(&bcx.command_lines[time.index]);
+            let command = match bcx.command_names.get(time.index).and_then(Option::as_deref) {
+                Some(name) => format!("{}<br><code>{line}</code>", html_escape(name)),
+                None => format!("<code>{line}</code>"),
+            };
+            write!(
+                f,
+                r#"
+<tr>
+  <td>{}.</td>
+  <td>{}</td>
+  <td>{}</td>
+  <td>{}</td>
+  <td>{:.1}s</td>
+</tr>
+"#,
+                bcx.command_indices[time.index] + 1,
+                command,
+                time.units,
+                time.shared_units,
+                time.duration,
+            )?;
+        }
+        write!(f, "</tbody>\n</table>\n")?;
+        Ok(())
+    }
+
     /// Write timing data in JavaScript. Primarily for `timings.js` to put data
     /// in a `<script>` HTML element to draw graphs.
     fn write_js_data(&self, f: &mut impl Write) -> CargoResult<()> {
@@ -603,6 +718,23 @@ fn render_rustc_info(bcx: &BuildContext<'_, '_>) -> String {
     )
 }
 
+/// Escapes `s` for use as text or an attribute value in the HTML report, as
+/// command names and lines of batch commands are arbitrary user input.
+fn html_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 static HTML_TMPL: &str = r#"
 <html>
 <head>
@@ -89,6 +89,14 @@ pub struct UnitInner {
     /// running its build script and instead use the given output from the
     /// config file.
     pub links_overrides: Rc<BTreeMap<String, BuildOutput>>,
+    /// Extra environment variables set for `rustc`, `rustdoc` and the build
+    /// script of a given unit.
+    ///
+    /// As of now, these only come from the `--env` option of `cargo-batch`
+    /// commands. See [`BuildConfig::env`] for more.
+    ///
+    /// [`BuildConfig::env`]: crate::core::compiler::BuildConfig::env
+    pub env: Rc<BTreeMap<String, String>>,
     // if `true`, the dependency is an artifact dependency, requiring special handling when
     // calculating output directories, linkage and environment variables provided to builds.
     pub artifact: IsArtifact,
@@ -184,6 +192,7 @@ impl fmt::Debug for Unit {
             .field("rustflags", &self.rustflags)
             .field("rustdocflags", &self.rustdocflags)
             .field("links_overrides", &self.links_overrides)
+            .field("env", &self.env)
             .field("artifact", &self.artifact.is_true())
             .field(
                 "artifact_target_for_features",
@@ -234,6 +243,7 @@ impl UnitInterner {
         rustflags: Rc<[String]>,
         rustdocflags: Rc<[String]>,
         links_overrides: Rc<BTreeMap<String, BuildOutput>>,
+        env: Rc<BTreeMap<String, String>>,
         is_std: bool,
         dep_hash: u64,
         artifact: IsArtifact,
@@ -270,6 +280,7 @@ impl UnitInterner {
             rustflags,
             rustdocflags,
             links_overrides,
+            env,
             is_std,
             dep_hash,
             artifact,
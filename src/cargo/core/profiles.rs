@@ -316,6 +316,7 @@ impl Profiles {
             profile.incremental = false;
         }
         profile.name = self.requested_profile;
+        profile.dir_name = self.get_dir_name();
         profile
     }
 
@@ -327,6 +328,7 @@ impl Profiles {
     pub fn get_profile_run_custom_build(&self, for_unit_profile: &Profile) -> Profile {
         let mut result = Profile::default();
         result.name = for_unit_profile.name;
+        result.dir_name = for_unit_profile.dir_name;
         result.root = for_unit_profile.root;
         result.debuginfo = for_unit_profile.debuginfo;
         result.opt_level = for_unit_profile.opt_level;
@@ -608,6 +610,10 @@ pub enum ProfileRoot {
 #[derive(Clone, Eq, PartialOrd, Ord, serde::Serialize)]
 pub struct Profile {
     pub name: InternedString,
+    /// The directory in the target directory that units with this profile
+    /// are built in, see [`Profiles::get_dir_name`].
+    #[serde(skip)]
+    pub dir_name: InternedString,
     pub opt_level: InternedString,
     #[serde(skip)] // named profiles are unstable
     pub root: ProfileRoot,
@@ -636,6 +642,7 @@ impl Default for Profile {
     fn default() -> Profile {
         Profile {
             name: InternedString::new(""),
+            dir_name: InternedString::new(""),
             opt_level: InternedString::new("0"),
             root: ProfileRoot::Debug,
             lto: Lto::Bool(false),
@@ -665,6 +672,7 @@ compact_debug! {
             };
             [debug_the_fields(
                 name
+                dir_name
                 opt_level
                 lto
                 root
@@ -735,9 +743,13 @@ impl Profile {
     /// Compares all fields except `name`, which doesn't affect compilation.
     /// This is necessary for `Unit` deduplication for things like "test" and
     /// "dev" which are essentially the same.
+    ///
+    /// `dir_name` is compared, so that `cargo-batch` doesn't merge units of
+    /// profiles that only differ by name but are built in different
+    /// directories.
     fn comparable(&self) -> impl Hash + Eq + '_ {
         (
-            self.opt_level,
+            (self.dir_name, self.opt_level),
             self.lto,
             self.codegen_backend,
             self.codegen_units,
@@ -16,6 +16,7 @@ use std::fmt;
 ///
 /// Each instance of `Resolve` also understands the full set of features used
 /// for each package.
+#[derive(Clone)]
 pub struct Resolve {
     /// A graph, whose vertices are packages and edges are dependency specifications
     /// from `Cargo.toml`. We need a `HashSet` here because the same package
@@ -27,7 +27,7 @@ pub struct Shell {
 impl fmt::Debug for Shell {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self.output {
-            ShellOut::Write(_) => f
+            ShellOut::Write(_) | ShellOut::Split { .. } => f
                 .debug_struct("Shell")
                 .field("verbosity", &self.verbosity)
                 .finish(),
@@ -74,6 +74,20 @@ impl Shell {
         }
     }
 
+    /// Creates a shell from plain writable objects for stdout and stderr, with no color, and max
+    /// verbosity.
+    pub fn from_writes(out: Box<dyn Write>, err: Box<dyn Write>) -> Shell {
+        Shell {
+            output: ShellOut::Split {
+                stdout: AutoStream::never(out),
+                stderr: AutoStream::never(err),
+            },
+            verbosity: Verbosity::Verbose,
+            needs_clear: false,
+            hostname: None,
+        }
+    }
+
     /// Prints a message, where the status will have `color` color, and can be justified. The
     /// messages follows without color.
     fn print(
@@ -287,14 +301,14 @@ impl Shell {
 
     pub fn out_unicode(&self) -> bool {
         match &self.output {
-            ShellOut::Write(_) => true,
+            ShellOut::Write(_) | ShellOut::Split { .. } => true,
             ShellOut::Stream { stdout_unicode, .. } => *stdout_unicode,
         }
     }
 
     pub fn err_unicode(&self) -> bool {
         match &self.output {
-            ShellOut::Write(_) => true,
+            ShellOut::Write(_) | ShellOut::Split { .. } => true,
             ShellOut::Stream { stderr_unicode, .. } => *stderr_unicode,
         }
     }
@@ -306,28 +320,28 @@ impl Shell {
     pub fn color_choice(&self) -> ColorChoice {
         match self.output {
             ShellOut::Stream { color_choice, .. } => color_choice,
-            ShellOut::Write(_) => ColorChoice::Never,
+            ShellOut::Write(_) | ShellOut::Split { .. } => ColorChoice::Never,
         }
     }
 
     /// Whether the shell supports color.
     pub fn err_supports_color(&self) -> bool {
         match &self.output {
-            ShellOut::Write(_) => false,
+            ShellOut::Write(_) | ShellOut::Split { .. } => false,
             ShellOut::Stream { stderr, .. } => supports_color(stderr.current_choice()),
         }
     }
 
     pub fn out_supports_color(&self) -> bool {
         match &self.output {
-            ShellOut::Write(_) => false,
+            ShellOut::Write(_) | ShellOut::Split { .. } => false,
             ShellOut::Stream { stdout, .. } => supports_color(stdout.current_choice()),
         }
     }
 
     pub fn out_hyperlink<D: fmt::Display>(&self, url: D) -> Hyperlink<D> {
         let supports_hyperlinks = match &self.output {
-            ShellOut::Write(_) => false,
+            ShellOut::Write(_) | ShellOut::Split { .. } => false,
             ShellOut::Stream {
                 stdout, hyperlinks, ..
             } => stdout.current_choice() == anstream::ColorChoice::AlwaysAnsi && *hyperlinks,
@@ -339,7 +353,7 @@ impl Shell {
 
     pub fn err_hyperlink<D: fmt::Display>(&self, url: D) -> Hyperlink<D> {
         let supports_hyperlinks = match &self.output {
-            ShellOut::Write(_) => false,
+            ShellOut::Write(_) | ShellOut::Split { .. } => false,
             ShellOut::Stream {
                 stderr, hyperlinks, ..
             } => stderr.current_choice() == anstream::ColorChoice::AlwaysAnsi && *hyperlinks,
@@ -430,6 +444,12 @@ impl Default for Shell {
 enum ShellOut {
     /// A plain write object without color support
     Write(AutoStream<Box<dyn Write>>),
+    /// Separate plain write objects for stdout and stderr, without color
+    /// support
+    Split {
+        stdout: AutoStream<Box<dyn Write>>,
+        stderr: AutoStream<Box<dyn Write>>,
+    },
     /// Color-enabled stdio, with information on whether color should be used
     Stream {
         stdout: AutoStream<std::io::Stdout>,
@@ -474,6 +494,7 @@ impl ShellOut {
     fn stdout(&mut self) -> &mut dyn Write {
         match self {
             ShellOut::Stream { stdout, .. } => stdout,
+            ShellOut::Split { stdout, .. } => stdout,
             ShellOut::Write(w) => w,
         }
     }
@@ -482,6 +503,7 @@ impl ShellOut {
     fn stderr(&mut self) -> &mut dyn Write {
         match self {
             ShellOut::Stream { stderr, .. } => stderr,
+            ShellOut::Split { stderr, .. } => stderr,
             ShellOut::Write(w) => w,
         }
     }
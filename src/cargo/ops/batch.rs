@@ -0,0 +1,824 @@
+//! Merging several builds into one, as done by `cargo-batch`.
+//!
+//! Each [`BatchCommand`] is set up like a regular `cargo build`, `cargo check`,
+//! etc. would with [`ops::create_bcx`]. The unit graphs of all commands are
+//! then merged into a single [`BuildContext`], so that units needed by several
+//! commands are only built once. Whatever differs between the commands, like
+//! their workspace or profiles, is kept per unit in
+//! [`BuildContext::unit_configs`].
+//!
+//! ```ignore
+//! let mut batch = Batch::new(gctx, commands);
+//! let compiled = batch.compile(&exec)?;
+//! for (cmd, compilation) in batch.commands().iter().zip(compiled.compilations?) {
+//!     let Some(compilation) = compilation else { continue };
+//!     // ...
+//! }
+//! ```
+
+use std::cell::{OnceCell, RefCell};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::core::compiler::unit_graph::UnitGraph;
+use crate::core::compiler::{
+    BuildConfig, BuildContext, BuildRunner, CommandLog, Compilation, CompileKind, CompileMode,
+    Executor, ExportFormat, LinkMethod, TimingOutput, Unit, UnitConfig, UnitInterner, UnitResult,
+};
+use crate::core::Workspace;
+use crate::ops::{self, BcxCache, CompileFilter, CompileOptions, FilterRule, LibRule, Packages};
+use crate::util::{CargoResult, GlobalContext};
+
+/// A single command of a [`Batch`].
+pub struct BatchCommand<'gctx> {
+    /// The name of the command, if it has one.
+    ///
+    /// Names are used as file names, see
+    /// [`BuildContext::command_dir_name`], so they can't contain path
+    /// separators, be `.` or `..`, or be a number like the index unnamed
+    /// commands are stored under.
+    pub name: Option<String>,
+    /// Describes the command in diagnostics, usually its command line.
+    pub line: String,
+    pub ws: Workspace<'gctx>,
+    pub compile_opts: CompileOptions,
+    /// Directory to copy the final artifacts of the command to, like
+    /// `--artifact-dir` does.
+    pub export_dir: Option<PathBuf>,
+    /// Formats to convert the exported executables to, next to the
+    /// executables in `export_dir`.
+    pub export_formats: Vec<ExportFormat>,
+}
+
+impl<'gctx> BatchCommand<'gctx> {
+    /// Creates an unnamed command, described by a command line equivalent to
+    /// `compile_opts` and `export_dir` in `ws`.
+    pub fn new(
+        ws: Workspace<'gctx>,
+        compile_opts: CompileOptions,
+        export_dir: Option<PathBuf>,
+    ) -> BatchCommand<'gctx> {
+        BatchCommand {
+            name: None,
+            line: command_line(&ws, &compile_opts, export_dir.as_deref()),
+            ws,
+            compile_opts,
+            export_dir,
+            export_formats: Vec::new(),
+        }
+    }
+
+    /// How the command is referred to in output: its name if it has one,
+    /// otherwise [`BatchCommand::line`].
+    pub fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.line)
+    }
+}
+
+/// A command line that builds what `compile_opts` does, to tell commands
+/// created without one apart.
+fn command_line(
+    ws: &Workspace<'_>,
+    compile_opts: &CompileOptions,
+    export_dir: Option<&Path>,
+) -> String {
+    let mut args: Vec<String> = Vec::new();
+    let mut arg = |arg: &str| args.push(arg.to_string());
+    match compile_opts.build_config.mode {
+        CompileMode::Test => arg("test"),
+        CompileMode::Build | CompileMode::RunCustomBuild => arg("build"),
+        CompileMode::Check { .. } => arg("check"),
+        CompileMode::Bench => arg("bench"),
+        CompileMode::Doc { .. } | CompileMode::Docscrape => arg("doc"),
+        CompileMode::Doctest => {
+            arg("test");
+            arg("--doc");
+        }
+    }
+    match &compile_opts.spec {
+        Packages::Default => {}
+        Packages::All(_) => arg("--workspace"),
+        Packages::OptOut(excluded) => {
+            arg("--workspace");
+            for spec in excluded {
+                arg("--exclude");
+                arg(spec);
+            }
+        }
+        Packages::Packages(specs) => {
+            for spec in specs {
+                arg("-p");
+                arg(spec);
+            }
+        }
+    }
+    if let CompileFilter::Only {
+        all_targets,
+        lib,
+        bins,
+        examples,
+        tests,
+        benches,
+    } = &compile_opts.filter
+    {
+        if *all_targets {
+            arg("--all-targets");
+        } else {
+            if *lib == LibRule::True {
+                arg("--lib");
+            }
+            for (rule, all, one) in [
+                (bins, "--bins", "--bin"),
+                (examples, "--examples", "--example"),
+                (tests, "--tests", "--test"),
+                (benches, "--benches", "--bench"),
+            ] {
+                match rule {
+                    FilterRule::All => arg(all),
+                    FilterRule::Just(names) => {
+                        for name in names {
+                            arg(one);
+                            arg(name);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    for kind in &compile_opts.build_config.requested_kinds {
+        if let CompileKind::Target(target) = kind {
+            arg("--target");
+            arg(target.short_name());
+        }
+    }
+    let features = &compile_opts.cli_features;
+    if features.all_features {
+        arg("--all-features");
+    }
+    if !features.uses_default_features {
+        arg("--no-default-features");
+    }
+    if !features.features.is_empty() {
+        arg("--features");
+        arg(&features
+            .features
+            .iter()
+            .map(|feature| feature.to_string())
+            .collect::<Vec<_>>()
+            .join(","));
+    }
+    match compile_opts.build_config.requested_profile.as_str() {
+        "dev" => {}
+        "release" => arg("--release"),
+        profile => {
+            arg("--profile");
+            arg(profile);
+        }
+    }
+    if let Some(export_dir) = export_dir {
+        arg("--artifact-dir");
+        arg(&export_dir.display().to_string());
+    }
+    arg("--manifest-path");
+    arg(&ws.root_manifest().display().to_string());
+    shell_words::join(&args)
+}
+
+/// A list of commands that are built together.
+pub struct Batch<'gctx> {
+    gctx: &'gctx GlobalContext,
+    commands: Vec<BatchCommand<'gctx>>,
+    /// See [`Batch::command_indices`].
+    indices: Vec<usize>,
+    /// Batch-level `--keep-going`, in addition to the commands' own.
+    keep_going: bool,
+    /// Batch-level `--timings`, in addition to the commands' own.
+    timing_outputs: Vec<TimingOutput>,
+    /// See [`Batch::uplift`].
+    uplift: bool,
+    /// See [`Batch::link_method`].
+    link_method: LinkMethod,
+    /// See [`Batch::collect_sources`].
+    collect_sources: bool,
+    /// See [`Batch::log_dir`].
+    log_dir: Option<PathBuf>,
+    /// The configuration of the build as a whole, see [`Batch::build_config`].
+    build_config: OnceCell<BuildConfig>,
+    /// Target information and resolves shared between commands.
+    cache: BatchCache<'gctx>,
+}
+
+/// Target information and resolves shared between the commands of one or
+/// more batches, see [`Batch::cache`].
+///
+/// Clones share the same cache.
+#[derive(Clone, Default)]
+pub struct BatchCache<'gctx> {
+    /// Caches per `GlobalContext`. Commands created with different contexts
+    /// may see different targets and dependencies, so they don't share
+    /// anything.
+    caches: Rc<RefCell<HashMap<*const GlobalContext, BcxCache<'gctx>>>>,
+}
+
+impl<'gctx> BatchCache<'gctx> {
+    /// Forgets everything cached so far, for example because manifests or
+    /// lockfiles changed.
+    pub fn clear(&self) {
+        self.caches.borrow_mut().clear();
+    }
+
+    /// Forgets the workspace resolves, keeping the target information, see
+    /// [`BcxCache::clear_resolves`].
+    pub fn clear_resolves(&self) {
+        for cache in self.caches.borrow_mut().values_mut() {
+            cache.clear_resolves();
+        }
+    }
+}
+
+impl<'gctx> Batch<'gctx> {
+    /// Creates a batch of `commands`. The merged build runs with `gctx`,
+    /// even for commands created with another `GlobalContext`.
+    pub fn new(gctx: &'gctx GlobalContext, commands: Vec<BatchCommand<'gctx>>) -> Batch<'gctx> {
+        Batch {
+            gctx,
+            indices: (0..commands.len()).collect(),
+            commands,
+            keep_going: false,
+            timing_outputs: Vec::new(),
+            uplift: false,
+            link_method: LinkMethod::default(),
+            collect_sources: false,
+            log_dir: None,
+            build_config: OnceCell::new(),
+            cache: BatchCache::default(),
+        }
+    }
+
+    /// The commands of the batch, in order.
+    pub fn commands(&self) -> &[BatchCommand<'gctx>] {
+        &self.commands
+    }
+
+    /// The index of each command in the batch as it was created, which
+    /// [`Batch::retain`] keeps. Output directories, logs and JSON messages
+    /// refer to commands by these indices, so they don't depend on which
+    /// other commands are built.
+    pub fn command_indices(&self) -> &[usize] {
+        &self.indices
+    }
+
+    /// Returns the commands of the batch, in order.
+    pub fn into_commands(self) -> Vec<BatchCommand<'gctx>> {
+        self.commands
+    }
+
+    /// Only keeps the commands for which `keep` returns `true`, given their
+    /// index, see [`Batch::command_indices`], and the command.
+    pub fn retain(&mut self, mut keep: impl FnMut(usize, &BatchCommand<'gctx>) -> bool) {
+        let kept: Vec<bool> = self
+            .indices
+            .iter()
+            .zip(&self.commands)
+            .map(|(&i, cmd)| keep(i, cmd))
+            .collect();
+        let mut kept_commands = kept.iter();
+        self.commands.retain(|_| *kept_commands.next().unwrap());
+        let mut kept_indices = kept.iter();
+        self.indices.retain(|_| *kept_indices.next().unwrap());
+        self.build_config.take();
+    }
+
+    /// Do not abort the batch as soon as one command fails.
+    pub fn keep_going(&mut self, keep_going: bool) -> &mut Self {
+        self.keep_going = keep_going;
+        self.build_config.take();
+        self
+    }
+
+    /// Timing reports to write for the whole batch.
+    pub fn timing_outputs(&mut self, timing_outputs: Vec<TimingOutput>) -> &mut Self {
+        self.timing_outputs = timing_outputs;
+        self.build_config.take();
+        self
+    }
+
+    /// Shares target information and resolves with other batches using
+    /// `cache`.
+    ///
+    /// The cache doesn't notice changes to manifests or lockfiles, it's up to
+    /// the caller to [`BatchCache::clear`] it.
+    pub fn cache(&mut self, cache: BatchCache<'gctx>) -> &mut Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Uplift the final artifacts of each command into
+    /// `target/batch/<command>/`, where `<command>` is the name of the
+    /// command or its index.
+    ///
+    /// Commands share the build directories, so their artifacts are never
+    /// uplifted into `target/<profile>/` like a regular build does.
+    pub fn uplift(&mut self, uplift: bool) -> &mut Self {
+        self.uplift = uplift;
+        self
+    }
+
+    /// How uplifted and exported artifacts are linked to the compiler's
+    /// outputs.
+    pub fn link_method(&mut self, link_method: LinkMethod) -> &mut Self {
+        self.link_method = link_method;
+        self
+    }
+
+    /// Record which source files each command was built from, see
+    /// [`BatchCompilation::command_sources`].
+    pub fn collect_sources(&mut self, collect_sources: bool) -> &mut Self {
+        self.collect_sources = collect_sources;
+        self
+    }
+
+    /// Write the diagnostics and build script output of each command to
+    /// `<dir>/<command>.log` instead of the console, see
+    /// [`BatchCompilation::command_logs`].
+    ///
+    /// Units shared by several commands have their output in every log.
+    pub fn log_dir(&mut self, log_dir: Option<PathBuf>) -> &mut Self {
+        self.log_dir = log_dir;
+        self
+    }
+
+    /// Options of the build as a whole.
+    ///
+    /// These are the options of the first command, combined with what every
+    /// command and the batch itself asked for. Everything else is kept per
+    /// unit in [`BuildContext::unit_configs`].
+    pub fn build_config(&self) -> CargoResult<&BuildConfig> {
+        if let Some(build_config) = self.build_config.get() {
+            return Ok(build_config);
+        }
+        let Some(first) = self.commands.first() else {
+            anyhow::bail!("no commands to build");
+        };
+        let mut build_config = first.compile_opts.build_config.clone();
+        build_config.keep_going |= self.keep_going;
+        build_config.timing_outputs = self.timing_outputs.clone();
+        for cmd in &self.commands {
+            let config = &cmd.compile_opts.build_config;
+            build_config.keep_going |= config.keep_going;
+            build_config.future_incompat_report |= config.future_incompat_report;
+            for output in &config.timing_outputs {
+                if !build_config.timing_outputs.contains(output) {
+                    build_config.timing_outputs.push(*output);
+                }
+            }
+        }
+        Ok(self.build_config.get_or_init(|| build_config))
+    }
+
+    /// Sets up the build of a single command, sharing target information and
+    /// resolves with earlier commands.
+    fn create_command_bcx<'a>(
+        &self,
+        cmd: &'a BatchCommand<'gctx>,
+        interner: &'a UnitInterner,
+    ) -> CargoResult<BuildContext<'a, 'gctx>> {
+        let mut caches = self.cache.caches.borrow_mut();
+        let cache = caches
+            .entry(cmd.ws.gctx() as *const GlobalContext)
+            .or_default();
+        ops::create_bcx_cached(&cmd.ws, &cmd.compile_opts, interner, cache)
+    }
+
+    /// Returns the units each command needs, without merging them.
+    ///
+    /// Commands that fail to set up need no units; their errors are reported
+    /// by [`Batch::create_bcx`].
+    pub fn command_units(&self, interner: &UnitInterner) -> Vec<HashSet<Unit>> {
+        self.commands
+            .iter()
+            .map(|cmd| {
+                self.create_command_bcx(cmd, interner)
+                    .map(|bcx| reachable(&bcx.roots, &bcx.unit_graph))
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// Sets up the builds of all commands and merges them into one.
+    ///
+    /// With [`Batch::keep_going`], commands that fail to set up are reported
+    /// and skipped. Otherwise the first such failure is returned.
+    pub fn create_bcx<'a>(
+        &'a self,
+        interner: &'a UnitInterner,
+    ) -> CargoResult<BatchBuildContext<'a, 'gctx>> {
+        let build_config = self.build_config()?;
+        let cmds = &self.commands;
+        for name in cmds.iter().filter_map(|cmd| cmd.name.as_deref()) {
+            validate_command_name(name)?;
+        }
+
+        let mut merged_bcx: Option<BuildContext<'a, 'gctx>> = None;
+        let mut command_roots = Vec::new();
+        let mut failed = HashSet::new();
+        // Which command exported each unit, to report conflicting export dirs.
+        let mut export_commands: HashMap<Unit, usize> = HashMap::new();
+        let mut unit_uses: HashMap<Unit, usize> = HashMap::new();
+        // The first command building for each kind, whose linker and runner
+        // the merged build uses.
+        let mut kind_commands: HashMap<CompileKind, usize> = HashMap::new();
+
+        for (i, cmd) in cmds.iter().enumerate() {
+            let mut bcx = match self.create_command_bcx(cmd, interner) {
+                Ok(bcx) => bcx,
+                Err(e) if build_config.keep_going => {
+                    crate::display_error(&e, &mut self.gctx.shell());
+                    failed.insert(i);
+                    command_roots.push(Vec::new());
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if let Some(export_dir) = &cmd.export_dir {
+                // Documented dependencies are shared with other commands, so
+                // they are copied into the export dir after the build instead.
+                for unit in &bcx.roots {
+                    if let Some(&other) = export_commands.get(unit) {
+                        if cmds[other].export_dir.as_ref() != Some(export_dir) {
+                            anyhow::bail!(
+                                "the {} target `{}` in package `{}` is exported to two different \
+                                 `--artifact-dir`s\n\
+                                 The first export is requested by:\n    {}\n\
+                                 The second export is requested by:\n    {}",
+                                unit.target.kind().description(),
+                                unit.target.name(),
+                                unit.pkg.package_id(),
+                                cmds[other].label(),
+                                cmd.label(),
+                            );
+                        }
+                    }
+                    export_commands.insert(unit.clone(), i);
+                    bcx.unit_export_dirs
+                        .insert(unit.clone(), export_dir.clone());
+                }
+                for root in &bcx.roots {
+                    bcx.unit_export_formats
+                        .insert(root.clone(), cmd.export_formats.clone());
+                }
+            } else if !cmd.export_formats.is_empty() {
+                anyhow::bail!(
+                    "`{}` converts its exported executables to other formats, \
+                     but has no artifact directory to export to",
+                    cmd.label()
+                );
+            }
+            for root in &bcx.roots {
+                bcx.root_commands.insert(root.clone(), vec![i]);
+            }
+            command_roots.push(bcx.roots.clone());
+            for unit in bcx.unit_graph.keys() {
+                *unit_uses.entry(unit.clone()).or_default() += 1;
+            }
+
+            for &kind in &bcx.all_kinds {
+                let Some(&other) = kind_commands.get(&kind) else {
+                    kind_commands.insert(kind, i);
+                    continue;
+                };
+                let merged = merged_bcx.as_ref().unwrap().target_data.target_config(kind);
+                let config = bcx.target_data.target_config(kind);
+                if merged.linker.as_ref().map(|l| &l.val) != config.linker.as_ref().map(|l| &l.val)
+                    || merged.runner.as_ref().map(|r| &r.val)
+                        != config.runner.as_ref().map(|r| &r.val)
+                {
+                    anyhow::bail!(
+                        "commands configure a different linker or runner for `{}`, \
+                         but a batch builds each target with one of them\n\
+                         The first command is:\n    {}\n\
+                         The second command is:\n    {}",
+                        bcx.target_data.short_name(&kind),
+                        cmds[other].label(),
+                        cmd.label(),
+                    );
+                }
+            }
+
+            if let Some(merged_bcx) = &mut merged_bcx {
+                let config = Rc::new(UnitConfig {
+                    ws: bcx.ws,
+                    profiles: bcx.profiles,
+                    build_config: bcx.build_config,
+                });
+                for unit in bcx.unit_graph.keys() {
+                    if !merged_bcx.unit_graph.contains_key(unit) {
+                        merged_bcx
+                            .unit_configs
+                            .insert(unit.clone(), Rc::clone(&config));
+                    }
+                }
+                merged_bcx.unit_graph.extend(bcx.unit_graph);
+                merged_bcx.roots.extend(bcx.roots);
+                merged_bcx.unit_export_dirs.extend(bcx.unit_export_dirs);
+                // Commands exporting the same executable get all of their
+                // formats.
+                for (unit, formats) in bcx.unit_export_formats {
+                    let merged = merged_bcx.unit_export_formats.entry(unit).or_default();
+                    for format in formats {
+                        if !merged.contains(&format) {
+                            merged.push(format);
+                        }
+                    }
+                }
+                merged_bcx.all_kinds.extend(bcx.all_kinds);
+                // Targets keep the configuration of the first command that
+                // builds for them, see `kind_commands`.
+                for (target, config) in bcx.target_data.target_config {
+                    merged_bcx
+                        .target_data
+                        .target_config
+                        .entry(target)
+                        .or_insert(config);
+                }
+                for (target, info) in bcx.target_data.target_info {
+                    merged_bcx
+                        .target_data
+                        .target_info
+                        .entry(target)
+                        .or_insert(info);
+                }
+                merged_bcx.packages.packages.extend(bcx.packages.packages);
+                merged_bcx
+                    .packages
+                    .sources
+                    .borrow_mut()
+                    .add_source_map(bcx.packages.sources.into_inner());
+                merged_bcx
+                    .extra_compiler_args
+                    .extend(bcx.extra_compiler_args);
+                merged_bcx.scrape_units.extend(bcx.scrape_units);
+                for (unit, commands) in bcx.root_commands {
+                    merged_bcx
+                        .root_commands
+                        .entry(unit)
+                        .or_default()
+                        .extend(commands);
+                }
+            } else {
+                merged_bcx = Some(bcx)
+            }
+        }
+
+        if let Some(bcx) = &mut merged_bcx {
+            bcx.do_uplift = false;
+            bcx.uplift_commands = self.uplift;
+            bcx.link_method = self.link_method;
+            bcx.collect_unit_sources = self.collect_sources;
+            bcx.command_log_dir = self.log_dir.clone();
+            // The first command may come with its own `GlobalContext`, but the
+            // build as a whole runs with the batch's.
+            bcx.gctx = self.gctx;
+            bcx.build_config = build_config;
+            bcx.command_lines = cmds.iter().map(|cmd| cmd.line.clone()).collect();
+            bcx.command_names = cmds.iter().map(|cmd| cmd.name.clone()).collect();
+            bcx.command_indices = self.indices.clone();
+            bcx.failed_commands = failed.clone();
+            for (i, roots) in command_roots.iter().enumerate() {
+                for unit in reachable(roots, &bcx.unit_graph) {
+                    // Every command gets all of its docs, wherever the unit
+                    // ends up being documented.
+                    let doc_dir = cmds[i].export_dir.as_ref();
+                    if unit.mode.is_doc() && bcx.unit_export_dirs.get(&unit) != doc_dir {
+                        let copies = bcx.unit_doc_copies.entry(unit.clone()).or_default();
+                        if !copies.iter().any(|dir| dir.as_ref() == doc_dir) {
+                            copies.push(doc_dir.cloned());
+                        }
+                    }
+                    bcx.unit_commands.entry(unit).or_default().push(i);
+                }
+            }
+        }
+
+        Ok(BatchBuildContext {
+            bcx: merged_bcx,
+            command_roots,
+            failed,
+            unit_uses,
+        })
+    }
+
+    /// Builds all commands as one build.
+    pub fn compile(&self, exec: &Arc<dyn Executor>) -> CargoResult<BatchCompilation<'gctx>> {
+        let interner = UnitInterner::new();
+        Ok(self.create_bcx(&interner)?.compile(exec))
+    }
+}
+
+/// The merged build of a [`Batch`], see [`Batch::create_bcx`].
+pub struct BatchBuildContext<'a, 'gctx> {
+    /// The merged build, or `None` if no command could be set up.
+    pub bcx: Option<BuildContext<'a, 'gctx>>,
+    /// The root units of each command, empty for commands that failed to
+    /// set up.
+    pub command_roots: Vec<Vec<Unit>>,
+    /// Indices of the commands that failed to set up.
+    pub failed: HashSet<usize>,
+    /// How many commands need each unit.
+    pub unit_uses: HashMap<Unit, usize>,
+}
+
+impl<'a, 'gctx> BatchBuildContext<'a, 'gctx> {
+    /// Lists the entries of the build directories that the merged build
+    /// would not produce, see [`BuildRunner::unused_paths`].
+    ///
+    /// Fails if a command failed to set up, since its artifacts would be
+    /// listed too.
+    pub fn unused_paths(&self) -> CargoResult<Vec<PathBuf>> {
+        if !self.failed.is_empty() {
+            anyhow::bail!(
+                "cannot tell which files are unused, {} of {} commands failed to set up",
+                self.failed.len(),
+                self.command_roots.len()
+            );
+        }
+        match &self.bcx {
+            Some(bcx) => BuildRunner::new(bcx)?.unused_paths(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Runs the merged build.
+    pub fn compile(self, exec: &Arc<dyn Executor>) -> BatchCompilation<'gctx> {
+        let Some(bcx) = self.bcx else {
+            let count = self.command_roots.len();
+            return BatchCompilation {
+                results: vec![CommandResult::Failed; count],
+                command_sources: vec![BTreeSet::new(); count],
+                command_logs: Vec::new(),
+                compilations: Err(anyhow::format_err!("all {} commands failed", count)),
+            };
+        };
+
+        let (unit_results, unit_sources, command_logs, compiled) = match BuildRunner::new(&bcx) {
+            Ok(build_runner) => {
+                let unit_results = Rc::clone(&build_runner.unit_results);
+                let unit_sources = Rc::clone(&build_runner.unit_sources);
+                let command_logs = Rc::clone(&build_runner.command_logs);
+                let compiled = build_runner.compile(exec);
+                (unit_results, unit_sources, command_logs, compiled)
+            }
+            Err(e) => (
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                Err(e),
+            ),
+        };
+
+        let results: Vec<_> = self
+            .command_roots
+            .iter()
+            .enumerate()
+            .map(|(i, roots)| {
+                if self.failed.contains(&i) {
+                    CommandResult::Failed
+                } else {
+                    command_result(roots, &bcx.unit_graph, &unit_results.borrow())
+                }
+            })
+            .collect();
+        let unit_sources = unit_sources.borrow();
+        let command_sources = self
+            .command_roots
+            .iter()
+            .map(|roots| {
+                reachable(roots, &bcx.unit_graph)
+                    .iter()
+                    .filter_map(|unit| unit_sources.get(unit))
+                    .flatten()
+                    .cloned()
+                    .collect()
+            })
+            .collect();
+        let compilations = compiled.map(|compilation| {
+            self.command_roots
+                .iter()
+                .zip(&results)
+                .map(|(roots, result)| {
+                    matches!(result, CommandResult::Built | CommandResult::Fresh)
+                        .then(|| compilation.for_roots(roots))
+                })
+                .collect()
+        });
+        let command_logs = command_logs.borrow().logs().to_vec();
+        BatchCompilation {
+            results,
+            command_sources,
+            command_logs,
+            compilations,
+        }
+    }
+}
+
+/// The outcome of [`BatchBuildContext::compile`].
+pub struct BatchCompilation<'gctx> {
+    /// How each command fared.
+    pub results: Vec<CommandResult>,
+    /// The local source files each command's units were built from, if
+    /// [`Batch::collect_sources`] was set. Commands that failed to set up,
+    /// or whose build failed before compiling anything, have none.
+    pub command_sources: Vec<BTreeSet<PathBuf>>,
+    /// The log of each command if [`Batch::log_dir`] was set, in command
+    /// order. Empty if the build stopped before the logs were created.
+    pub command_logs: Vec<CommandLog>,
+    /// The compilation of each command, or the error that failed the build.
+    ///
+    /// With [`Batch::keep_going`], the build fails only if no command could
+    /// be set up. Commands that failed or were skipped have no compilation.
+    pub compilations: CargoResult<Vec<Option<Compilation<'gctx>>>>,
+}
+
+/// How a single batch command fared in the merged build.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandResult {
+    /// Everything the command needed was up to date.
+    Fresh,
+    /// At least one unit of the command was built, and none failed.
+    Built,
+    /// A unit of the command failed, or the command could not be set up.
+    Failed,
+    /// The build stopped before all units of the command ran.
+    Skipped,
+}
+
+impl CommandResult {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CommandResult::Fresh => "fresh",
+            CommandResult::Built => "built",
+            CommandResult::Failed => "failed",
+            CommandResult::Skipped => "skipped",
+        }
+    }
+}
+
+/// Derives the result of a command from the results of the units its roots
+/// transitively depend on.
+fn command_result(
+    roots: &[Unit],
+    unit_graph: &UnitGraph,
+    unit_results: &HashMap<Unit, UnitResult>,
+) -> CommandResult {
+    let mut built = false;
+    let mut skipped = false;
+    for unit in reachable(roots, unit_graph) {
+        match unit_results.get(&unit) {
+            Some(UnitResult::Failed) => return CommandResult::Failed,
+            Some(UnitResult::Built) => built = true,
+            Some(UnitResult::Fresh) => {}
+            None => skipped = true,
+        }
+    }
+    if skipped {
+        CommandResult::Skipped
+    } else if built {
+        CommandResult::Built
+    } else {
+        CommandResult::Fresh
+    }
+}
+
+/// Checks that `name` can be used as the name of a command, see
+/// [`BatchCommand::name`].
+fn validate_command_name(name: &str) -> CargoResult<()> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.contains(['/', '\\'])
+        || name.parse::<usize>().is_ok()
+    {
+        anyhow::bail!(
+            "invalid command name `{name}`\n\
+             Command names are used as file names, so they can't be empty, `.`, `..`, \
+             a number, or contain `/` or `\\`."
+        );
+    }
+    Ok(())
+}
+
+/// Returns `roots` and every unit they transitively depend on.
+pub fn reachable(roots: &[Unit], unit_graph: &UnitGraph) -> HashSet<Unit> {
+    let mut seen: HashSet<Unit> = roots.iter().cloned().collect();
+    let mut stack: Vec<&Unit> = roots.iter().collect();
+    while let Some(unit) = stack.pop() {
+        for dep in &unit_graph[unit] {
+            if seen.insert(dep.unit.clone()) {
+                stack.push(&dep.unit);
+            }
+        }
+    }
+    seen
+}
@@ -37,6 +37,7 @@
 
 use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
+use std::rc::Rc;
 use std::sync::Arc;
 
 use crate::core::compiler::unit_dependencies::build_unit_dependencies;
@@ -211,6 +212,49 @@ pub fn create_bcx<'a, 'gctx>(
     ws: &'a Workspace<'gctx>,
     options: &'a CompileOptions,
     interner: &'a UnitInterner,
+) -> CargoResult<BuildContext<'a, 'gctx>> {
+    create_bcx_cached(ws, options, interner, &mut BcxCache::default())
+}
+
+/// Information shared between several [`create_bcx_cached`] calls, so that
+/// builds which are merged into one, like `cargo-batch` does, only probe
+/// each target and resolve each workspace once.
+///
+/// A cache must only be used with a single [`GlobalContext`]. The calls
+/// are made one after the other: a `GlobalContext` and its `Shell` are
+/// `RefCell`-based and not `Sync`, so build contexts can't be set up on
+/// several threads at once.
+///
+/// [`GlobalContext`]: crate::GlobalContext
+#[derive(Default)]
+pub struct BcxCache<'gctx> {
+    /// Target information, keyed by the requested kinds it was created for.
+    ///
+    /// Everything else it depends on, like `rustc`, its wrappers and the
+    /// target config, comes from the `GlobalContext`. The kinds forced by
+    /// packages of a workspace are merged in on every lookup, see
+    /// [`RustcTargetData::merge_workspace_kinds`].
+    target_data: HashMap<Vec<CompileKind>, RustcTargetData<'gctx>>,
+    resolves: ops::ResolveCache,
+}
+
+impl<'gctx> BcxCache<'gctx> {
+    /// Forgets the workspace resolves, for example because manifests or
+    /// lockfiles changed. Target information doesn't depend on them and is
+    /// kept.
+    pub fn clear_resolves(&mut self) {
+        self.resolves = ops::ResolveCache::default();
+    }
+}
+
+/// Like [`create_bcx`], but reuses target information and workspace resolves
+/// from `cache`.
+#[tracing::instrument(skip_all)]
+pub fn create_bcx_cached<'a, 'gctx>(
+    ws: &'a Workspace<'gctx>,
+    options: &'a CompileOptions,
+    interner: &'a UnitInterner,
+    cache: &mut BcxCache<'gctx>,
 ) -> CargoResult<BuildContext<'a, 'gctx>> {
     let CompileOptions {
         ref build_config,
@@ -248,7 +292,21 @@ pub fn create_bcx<'a, 'gctx>(
     }
     gctx.validate_term_config()?;
 
-    let mut target_data = RustcTargetData::new(ws, &build_config.requested_kinds)?;
+    let mut target_data = match cache.target_data.get(&build_config.requested_kinds) {
+        Some(target_data) => {
+            let mut target_data = target_data.clone();
+            target_data.merge_workspace_kinds(ws)?;
+            target_data
+        }
+        None => {
+            let target_data = RustcTargetData::new(ws, &build_config.requested_kinds)?;
+            cache
+                .target_data
+                .insert(build_config.requested_kinds.clone(), target_data.clone());
+            target_data
+        }
+    };
+    target_data.env = Rc::new(build_config.env.clone());
 
     let specs = spec.to_package_id_specs(ws)?;
     let has_dev_units = {
@@ -273,7 +331,7 @@ pub fn create_bcx<'a, 'gctx>(
         }
     };
     let dry_run = false;
-    let resolve = ops::resolve_ws_with_opts(
+    let resolve = ops::resolve_ws_with_opts_cached(
         ws,
         &mut target_data,
         &build_config.requested_kinds,
@@ -282,6 +340,7 @@ pub fn create_bcx<'a, 'gctx>(
         has_dev_units,
         crate::core::resolver::features::ForceAllTargets::No,
         dry_run,
+        &mut cache.resolves,
     )?;
     let WorkspaceResolve {
         mut pkg_set,
@@ -715,6 +774,7 @@ fn traverse_and_share(
             unit.rustflags.clone(),
             unit.rustdocflags.clone(),
             unit.links_overrides.clone(),
+            unit.env.clone(),
             unit.is_std,
             unit.dep_hash,
             unit.artifact,
@@ -743,6 +803,7 @@ fn traverse_and_share(
         unit.rustflags.clone(),
         unit.rustdocflags.clone(),
         unit.links_overrides.clone(),
+        unit.env.clone(),
         unit.is_std,
         new_dep_hash,
         unit.artifact,
@@ -907,6 +968,7 @@ fn override_rustc_crate_types(
             unit.rustflags.clone(),
             unit.rustdocflags.clone(),
             unit.links_overrides.clone(),
+            unit.env.clone(),
             unit.is_std,
             unit.dep_hash,
             unit.artifact,
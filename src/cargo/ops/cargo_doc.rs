@@ -66,14 +66,7 @@ pub fn doc(ws: &Workspace<'_>, options: &DocOptions) -> CargoResult<()> {
         let path = path_by_output_format(&compilation, &kind, &name, &options.output_format);
 
         if path.exists() {
-            let config_browser = {
-                let cfg: Option<PathAndArgs> = ws.gctx().get("doc.browser")?;
-                cfg.map(|path_args| (path_args.path.resolve_program(ws.gctx()), path_args.args))
-            };
-            let mut shell = ws.gctx().shell();
-            let link = shell.err_file_hyperlink(&path);
-            shell.status("Opening", format!("{link}{}{link:#}", path.display()))?;
-            open_docs(&path, &mut shell, config_browser, ws.gctx())?;
+            open_doc(ws, &path)?;
         }
     } else if ws.gctx().shell().verbosity() == Verbosity::Verbose {
         for name in &compilation.root_crate_names {
@@ -117,6 +110,19 @@ pub fn doc(ws: &Workspace<'_>, options: &DocOptions) -> CargoResult<()> {
     Ok(())
 }
 
+/// Opens the generated documentation at `path` in a browser, honoring the
+/// `doc.browser` config and the `BROWSER` environment variable.
+pub fn open_doc(ws: &Workspace<'_>, path: &Path) -> CargoResult<()> {
+    let config_browser = {
+        let cfg: Option<PathAndArgs> = ws.gctx().get("doc.browser")?;
+        cfg.map(|path_args| (path_args.path.resolve_program(ws.gctx()), path_args.args))
+    };
+    let mut shell = ws.gctx().shell();
+    let link = shell.err_file_hyperlink(path);
+    shell.status("Opening", format!("{link}{}{link:#}", path.display()))?;
+    open_docs(path, &mut shell, config_browser, ws.gctx())
+}
+
 fn path_by_output_format(
     compilation: &Compilation<'_>,
     kind: &CompileKind,
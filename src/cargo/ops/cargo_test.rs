@@ -66,7 +66,20 @@ impl UnitTestError {
 /// code that Cargo should use.
 pub fn run_tests(ws: &Workspace<'_>, options: &TestOptions, test_args: &[&str]) -> CliResult {
     let compilation = compile_tests(ws, options)?;
+    run_compiled_tests(ws, options, test_args, &compilation)
+}
 
+/// Runs the tests of an already finished [`Compilation`].
+///
+/// This is the second half of [`run_tests`], for callers such as
+/// `cargo-batch` that compile several commands in one build and only then
+/// run the tests of each command.
+pub fn run_compiled_tests(
+    ws: &Workspace<'_>,
+    options: &TestOptions,
+    test_args: &[&str],
+    compilation: &Compilation<'_>,
+) -> CliResult {
     if options.no_run {
         if !options.compile_opts.build_config.emit_json() {
             display_no_run_information(ws, test_args, &compilation, "unittests")?;
@@ -86,7 +99,18 @@ pub fn run_tests(ws: &Workspace<'_>, options: &TestOptions, test_args: &[&str])
 /// code that Cargo should use.
 pub fn run_benches(ws: &Workspace<'_>, options: &TestOptions, args: &[&str]) -> CliResult {
     let compilation = compile_tests(ws, options)?;
+    run_compiled_benches(ws, options, args, &compilation)
+}
 
+/// Runs the benchmarks of an already finished [`Compilation`].
+///
+/// See [`run_compiled_tests`].
+pub fn run_compiled_benches(
+    ws: &Workspace<'_>,
+    options: &TestOptions,
+    args: &[&str],
+    compilation: &Compilation<'_>,
+) -> CliResult {
     if options.no_run {
         if !options.compile_opts.build_config.emit_json() {
             display_no_run_information(ws, args, &compilation, "benches")?;
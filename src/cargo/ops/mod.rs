@@ -2,10 +2,11 @@ use crate::sources::CRATES_IO_DOMAIN;
 
 pub use self::cargo_clean::{clean, CleanContext, CleanOptions};
 pub use self::cargo_compile::{
-    compile, compile_with_exec, compile_ws, create_bcx, print, resolve_all_features, CompileOptions,
+    compile, compile_with_exec, compile_ws, create_bcx, create_bcx_cached, print,
+    resolve_all_features, BcxCache, CompileOptions,
 };
 pub use self::cargo_compile::{CompileFilter, FilterRule, LibRule, Packages};
-pub use self::cargo_doc::{doc, DocOptions, OutputFormat};
+pub use self::cargo_doc::{doc, open_doc, DocOptions, OutputFormat};
 pub use self::cargo_fetch::{fetch, FetchOptions};
 pub use self::cargo_install::{install, install_list};
 pub use self::cargo_new::{init, new, NewOptions, NewProjectKind, VersionControl};
@@ -17,7 +18,9 @@ pub use self::cargo_package::PackageOpts;
 pub use self::cargo_pkgid::pkgid;
 pub use self::cargo_read_manifest::read_package;
 pub use self::cargo_run::run;
-pub use self::cargo_test::{run_benches, run_tests, TestOptions};
+pub use self::cargo_test::{
+    run_benches, run_compiled_benches, run_compiled_tests, run_tests, TestOptions,
+};
 pub use self::cargo_uninstall::uninstall;
 pub use self::cargo_update::generate_lockfile;
 pub use self::cargo_update::print_lockfile_changes;
@@ -41,10 +44,11 @@ pub use self::registry::RegistryCredentialConfig;
 pub use self::registry::RegistryOrIndex;
 pub use self::resolve::{
     add_overrides, get_resolved_packages, resolve_with_previous, resolve_ws, resolve_ws_with_opts,
-    WorkspaceResolve,
+    resolve_ws_with_opts_cached, ResolveCache, WorkspaceResolve,
 };
 pub use self::vendor::{vendor, VendorOptions};
 
+pub mod batch;
 pub mod cargo_add;
 mod cargo_clean;
 pub(crate) mod cargo_compile;
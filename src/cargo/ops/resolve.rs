@@ -82,8 +82,20 @@ use anyhow::Context as _;
 use cargo_util::paths;
 use cargo_util_schemas::core::PartialVersion;
 use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use tracing::{debug, trace};
 
+/// Workspace resolves kept across several [`resolve_ws_with_opts_cached`]
+/// calls, keyed by the root manifest of the workspace and the lockfile
+/// requested with `--lockfile-path`, if any.
+///
+/// Commands that only differ by features or targets then only resolve and
+/// lock the workspace once.
+#[derive(Default)]
+pub struct ResolveCache {
+    workspace_resolves: HashMap<(PathBuf, Option<PathBuf>), Resolve>,
+}
+
 /// Filter for keep using Package ID from previous lockfile.
 type Keep<'a> = &'a dyn Fn(&PackageId) -> bool;
 
@@ -144,6 +156,32 @@ pub fn resolve_ws_with_opts<'gctx>(
     has_dev_units: HasDevUnits,
     force_all_targets: ForceAllTargets,
     dry_run: bool,
+) -> CargoResult<WorkspaceResolve<'gctx>> {
+    resolve_ws_with_opts_cached(
+        ws,
+        target_data,
+        requested_targets,
+        cli_features,
+        specs,
+        has_dev_units,
+        force_all_targets,
+        dry_run,
+        &mut ResolveCache::default(),
+    )
+}
+
+/// Like [`resolve_ws_with_opts`], but reuses the resolve of the entire
+/// workspace from `cache` if it was resolved before.
+pub fn resolve_ws_with_opts_cached<'gctx>(
+    ws: &Workspace<'gctx>,
+    target_data: &mut RustcTargetData<'gctx>,
+    requested_targets: &[CompileKind],
+    cli_features: &CliFeatures,
+    specs: &[PackageIdSpec],
+    has_dev_units: HasDevUnits,
+    force_all_targets: ForceAllTargets,
+    dry_run: bool,
+    cache: &mut ResolveCache,
 ) -> CargoResult<WorkspaceResolve<'gctx>> {
     let specs = match ws.resolve_feature_unification() {
         FeatureUnification::Selected => specs,
@@ -168,9 +206,21 @@ pub fn resolve_ws_with_opts<'gctx>(
     } else if ws.require_optional_deps() {
         // First, resolve the root_package's *listed* dependencies, as well as
         // downloading and updating all remotes and such.
-        let resolve = resolve_with_registry(ws, &mut registry, dry_run)?;
-        // No need to add patches again, `resolve_with_registry` has done it.
-        let add_patches = false;
+        let key = (
+            ws.root_manifest().to_path_buf(),
+            ws.requested_lockfile_path().map(Path::to_path_buf),
+        );
+        let (resolve, add_patches) = match cache.workspace_resolves.get(&key) {
+            // Patches are registered while resolving, so they need to be
+            // added to this registry when reusing an earlier resolve.
+            Some(resolve) => (resolve.clone(), true),
+            None => {
+                let resolve = resolve_with_registry(ws, &mut registry, dry_run)?;
+                cache.workspace_resolves.insert(key, resolve.clone());
+                // No need to add patches again, `resolve_with_registry` has done it.
+                (resolve, false)
+            }
+        };
 
         // Second, resolve with precisely what we're doing. Filter out
         // transitive dependencies if necessary, specify features, handle
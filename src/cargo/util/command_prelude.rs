@@ -720,14 +720,8 @@ Run `{cmd}` to see possible targets."
         )
     }
 
-    fn compile_options(
-        &self,
-        gctx: &GlobalContext,
-        mode: CompileMode,
-        workspace: Option<&Workspace<'_>>,
-        profile_checking: ProfileChecking,
-    ) -> CargoResult<CompileOptions> {
-        let spec = self.packages_from_flags()?;
+    /// Parses `--message-format`, returning `None` if it wasn't given.
+    fn message_format(&self) -> CargoResult<Option<MessageFormat>> {
         let mut message_format = None;
         let default_json = MessageFormat::Json {
             short: false,
@@ -790,20 +784,12 @@ Run `{cmd}` to see possible targets."
                 }
             }
         }
+        Ok(message_format)
+    }
 
-        let mut build_config = BuildConfig::new(
-            gctx,
-            self.jobs()?,
-            self.keep_going(),
-            &self.targets()?,
-            mode,
-        )?;
-        build_config.message_format = message_format.unwrap_or(MessageFormat::Human);
-        build_config.requested_profile = self.get_profile_name("dev", profile_checking)?;
-        build_config.build_plan = self.flag("build-plan");
-        build_config.unit_graph = self.flag("unit-graph");
-        build_config.future_incompat_report = self.flag("future-incompat-report");
-
+    /// Parses `--timings`, returning no outputs if it wasn't given.
+    fn timing_outputs(&self, gctx: &GlobalContext) -> CargoResult<Vec<TimingOutput>> {
+        let mut timing_outputs = Vec::new();
         if self._contains("timings") {
             for timing_output in self._values_of("timings") {
                 for timing_output in timing_output.split(',') {
@@ -821,13 +807,37 @@ Run `{cmd}` to see possible targets."
                         }
                         s => bail!("invalid timings output specifier: `{}`", s),
                     };
-                    build_config.timing_outputs.push(timing_output);
+                    timing_outputs.push(timing_output);
                 }
             }
-            if build_config.timing_outputs.is_empty() {
-                build_config.timing_outputs.push(TimingOutput::Html);
+            if timing_outputs.is_empty() {
+                timing_outputs.push(TimingOutput::Html);
             }
         }
+        Ok(timing_outputs)
+    }
+
+    fn compile_options(
+        &self,
+        gctx: &GlobalContext,
+        mode: CompileMode,
+        workspace: Option<&Workspace<'_>>,
+        profile_checking: ProfileChecking,
+    ) -> CargoResult<CompileOptions> {
+        let spec = self.packages_from_flags()?;
+        let mut build_config = BuildConfig::new(
+            gctx,
+            self.jobs()?,
+            self.keep_going(),
+            &self.targets()?,
+            mode,
+        )?;
+        build_config.message_format = self.message_format()?.unwrap_or(MessageFormat::Human);
+        build_config.requested_profile = self.get_profile_name("dev", profile_checking)?;
+        build_config.build_plan = self.flag("build-plan");
+        build_config.unit_graph = self.flag("unit-graph");
+        build_config.future_incompat_report = self.flag("future-incompat-report");
+        build_config.timing_outputs = self.timing_outputs(gctx)?;
 
         if build_config.build_plan {
             gctx.cli_unstable()
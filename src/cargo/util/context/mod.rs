@@ -51,7 +51,7 @@
 
 use crate::util::cache_lock::{CacheLock, CacheLockMode, CacheLocker};
 use std::borrow::Cow;
-use std::cell::{RefCell, RefMut};
+use std::cell::{Cell, RefCell, RefMut};
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 use std::collections::{HashMap, HashSet};
 use std::env;
@@ -205,7 +205,7 @@ pub struct GlobalContext {
     /// If false, don't cache `rustc --version --verbose` invocations
     cache_rustc_info: bool,
     /// Creation time of this config, used to output the total build time
-    creation_time: Instant,
+    creation_time: Cell<Instant>,
     /// Target Directory via resolved Cli parameter
     target_dir: Option<Filesystem>,
     /// Environment variable snapshot.
@@ -307,7 +307,7 @@ impl GlobalContext {
             easy: LazyCell::new(),
             crates_io_source_id: LazyCell::new(),
             cache_rustc_info,
-            creation_time: Instant::now(),
+            creation_time: Cell::new(Instant::now()),
             target_dir: None,
             env,
             updated_sources: LazyCell::new(),
@@ -2027,7 +2027,13 @@ impl GlobalContext {
     }
 
     pub fn creation_time(&self) -> Instant {
-        self.creation_time
+        self.creation_time.get()
+    }
+
+    /// Restarts the clock of [`GlobalContext::creation_time`], for processes
+    /// that run several builds with the same context.
+    pub fn reset_creation_time(&self) {
+        self.creation_time.set(Instant::now());
     }
 
     /// Retrieves a config variable.
@@ -0,0 +1,230 @@
+//! Just enough of the ELF format to read the sections of an executable.
+//!
+//! Used by `cargo-batch` to report section sizes and to convert exported
+//! executables to other formats, see [`ExportFormat`].
+//!
+//! [`ExportFormat`]: crate::core::compiler::ExportFormat
+
+/// Section type of sections that occupy no space in the file, like `.bss`.
+pub const SHT_NOBITS: u32 = 8;
+/// Section flag of sections that occupy memory when the program runs.
+pub const SHF_ALLOC: u64 = 0x2;
+/// Segment type of segments that are loaded into memory.
+const PT_LOAD: u32 = 1;
+
+/// A parsed ELF file, borrowing its contents.
+pub struct Elf<'a> {
+    data: &'a [u8],
+    is_64: bool,
+    little_endian: bool,
+}
+
+/// A section header of an [`Elf`] file.
+#[derive(Debug)]
+pub struct Section<'a> {
+    pub name: &'a str,
+    pub kind: u32,
+    pub flags: u64,
+    /// The address of the section when the program runs.
+    pub addr: u64,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// A program header of an [`Elf`] file.
+#[derive(Debug)]
+pub struct Segment {
+    pub kind: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    /// The address the segment is loaded from, like flash for the initial
+    /// values of `.data`.
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+impl<'a> Elf<'a> {
+    /// Parses the header of an ELF file, or returns `None` if `data` isn't
+    /// one.
+    pub fn parse(data: &'a [u8]) -> Option<Elf<'a>> {
+        if data.get(..4)? != b"\x7fELF" {
+            return None;
+        }
+        let is_64 = match data.get(4)? {
+            1 => false,
+            2 => true,
+            _ => return None,
+        };
+        let little_endian = match data.get(5)? {
+            1 => true,
+            2 => false,
+            _ => return None,
+        };
+        Some(Elf {
+            data,
+            is_64,
+            little_endian,
+        })
+    }
+
+    /// Reads the `size` byte integer at `offset`.
+    fn read(&self, offset: u64, size: usize) -> Option<u64> {
+        let offset = usize::try_from(offset).ok()?;
+        let bytes = self.data.get(offset..offset.checked_add(size)?)?;
+        let value = if self.little_endian {
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |value, &b| value << 8 | u64::from(b))
+        } else {
+            bytes.iter().fold(0, |value, &b| value << 8 | u64::from(b))
+        };
+        Some(value)
+    }
+
+    /// Reads an address-sized integer at `offset`.
+    fn read_addr(&self, offset: u64) -> Option<u64> {
+        self.read(offset, if self.is_64 { 8 } else { 4 })
+    }
+
+    /// The address execution starts at.
+    pub fn entry(&self) -> Option<u64> {
+        self.read_addr(0x18)
+    }
+
+    /// All section headers, in file order.
+    pub fn sections(&self) -> Option<Vec<Section<'a>>> {
+        let (shoff, shentsize, shnum, shstrndx) = if self.is_64 {
+            (
+                self.read(0x28, 8)?,
+                self.read(0x3a, 2)?,
+                self.read(0x3c, 2)?,
+                self.read(0x3e, 2)?,
+            )
+        } else {
+            (
+                self.read(0x20, 4)?,
+                self.read(0x2e, 2)?,
+                self.read(0x30, 2)?,
+                self.read(0x32, 2)?,
+            )
+        };
+        let header = |index: u64| -> Option<(u64, Section<'a>)> {
+            let base = shoff.checked_add(index.checked_mul(shentsize)?)?;
+            let name = self.read(base, 4)?;
+            let kind = self.read(base + 4, 4)? as u32;
+            let section = if self.is_64 {
+                Section {
+                    name: "",
+                    kind,
+                    flags: self.read(base + 0x08, 8)?,
+                    addr: self.read(base + 0x10, 8)?,
+                    offset: self.read(base + 0x18, 8)?,
+                    size: self.read(base + 0x20, 8)?,
+                }
+            } else {
+                Section {
+                    name: "",
+                    kind,
+                    flags: self.read(base + 0x08, 4)?,
+                    addr: self.read(base + 0x0c, 4)?,
+                    offset: self.read(base + 0x10, 4)?,
+                    size: self.read(base + 0x14, 4)?,
+                }
+            };
+            Some((name, section))
+        };
+
+        let (_, names) = header(shstrndx)?;
+        let mut sections = Vec::new();
+        for index in 0..shnum {
+            let (name, mut section) = header(index)?;
+            let start = usize::try_from(names.offset.checked_add(name)?).ok()?;
+            let name = self.data.get(start..)?.split(|&b| b == 0).next()?;
+            section.name = std::str::from_utf8(name).unwrap_or_default();
+            sections.push(section);
+        }
+        Some(sections)
+    }
+
+    /// All program headers, in file order.
+    pub fn segments(&self) -> Option<Vec<Segment>> {
+        let (phoff, phentsize, phnum) = if self.is_64 {
+            (
+                self.read(0x20, 8)?,
+                self.read(0x36, 2)?,
+                self.read(0x38, 2)?,
+            )
+        } else {
+            (
+                self.read(0x1c, 4)?,
+                self.read(0x2a, 2)?,
+                self.read(0x2c, 2)?,
+            )
+        };
+        (0..phnum)
+            .map(|index| {
+                let base = phoff.checked_add(index.checked_mul(phentsize)?)?;
+                let kind = self.read(base, 4)? as u32;
+                if self.is_64 {
+                    Some(Segment {
+                        kind,
+                        offset: self.read(base + 0x08, 8)?,
+                        vaddr: self.read(base + 0x10, 8)?,
+                        paddr: self.read(base + 0x18, 8)?,
+                        filesz: self.read(base + 0x20, 8)?,
+                        memsz: self.read(base + 0x28, 8)?,
+                    })
+                } else {
+                    Some(Segment {
+                        kind,
+                        offset: self.read(base + 0x04, 4)?,
+                        vaddr: self.read(base + 0x08, 4)?,
+                        paddr: self.read(base + 0x0c, 4)?,
+                        filesz: self.read(base + 0x10, 4)?,
+                        memsz: self.read(base + 0x14, 4)?,
+                    })
+                }
+            })
+            .collect()
+    }
+
+    /// The contents of `section` in the file.
+    pub fn section_data(&self, section: &Section<'_>) -> Option<&'a [u8]> {
+        if section.kind == SHT_NOBITS {
+            return Some(&[]);
+        }
+        let start = usize::try_from(section.offset).ok()?;
+        let end = start.checked_add(usize::try_from(section.size).ok()?)?;
+        self.data.get(start..end)
+    }
+
+    /// The contents of the program as it is stored in memory before it runs,
+    /// as pairs of load address and data, sorted by address.
+    ///
+    /// Like `objcopy`, these are the allocated sections that have contents
+    /// in the file, placed at their load address. That is where their
+    /// loadable segment is loaded from, rather than where they run.
+    pub fn load_image(&self) -> Option<Vec<(u64, &'a [u8])>> {
+        let segments = self.segments()?;
+        let mut image = Vec::new();
+        for section in self.sections()? {
+            if section.flags & SHF_ALLOC == 0 || section.kind == SHT_NOBITS || section.size == 0 {
+                continue;
+            }
+            let addr = segments
+                .iter()
+                .find(|segment| {
+                    segment.kind == PT_LOAD
+                        && section.offset >= segment.offset
+                        && section.offset + section.size <= segment.offset + segment.filesz
+                })
+                .map(|segment| segment.paddr + (section.offset - segment.offset))
+                .unwrap_or(section.addr);
+            image.push((addr, self.section_data(&section)?));
+        }
+        image.sort_by_key(|&(addr, _)| addr);
+        Some(image)
+    }
+}
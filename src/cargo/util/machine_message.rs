@@ -25,6 +25,8 @@ pub struct FromCompiler<'a> {
     pub manifest_path: &'a Path,
     pub target: &'a Target,
     pub message: Box<RawValue>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub batch_commands: Vec<BatchCommand>,
 }
 
 impl<'a> Message for FromCompiler<'a> {
@@ -43,6 +45,8 @@ pub struct Artifact<'a> {
     pub filenames: Vec<PathBuf>,
     pub executable: Option<PathBuf>,
     pub fresh: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub batch_commands: Vec<BatchCommand>,
 }
 
 impl<'a> Message for Artifact<'a> {
@@ -51,6 +55,15 @@ impl<'a> Message for Artifact<'a> {
     }
 }
 
+/// A command of a `cargo-batch` build whose roots depend on the unit a
+/// message is about.
+#[derive(Clone, Serialize)]
+pub struct BatchCommand {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
 /// This is different from the regular `Profile` to maintain backwards
 /// compatibility (in particular, `test` is no longer in `Profile`, but we
 /// still want it to be included here).
@@ -103,6 +116,67 @@ impl<'a> Message for TimingInfo<'a> {
     }
 }
 
+#[derive(Serialize)]
+pub struct BatchCommandTiming<'a> {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<&'a str>,
+    pub command: &'a str,
+    pub units: usize,
+    pub shared_units: usize,
+    pub duration: f64,
+}
+
+impl<'a> Message for BatchCommandTiming<'a> {
+    fn reason(&self) -> &str {
+        "batch-command-timing"
+    }
+}
+
+#[derive(Serialize)]
+pub struct BatchDedupReport<'a> {
+    pub commands: usize,
+    pub total_units: usize,
+    pub unique_units: usize,
+    pub shared_units: Vec<SharedUnit<'a>>,
+}
+
+impl<'a> Message for BatchDedupReport<'a> {
+    fn reason(&self) -> &str {
+        "batch-dedup-report"
+    }
+}
+
+#[derive(Serialize)]
+pub struct SharedUnit<'a> {
+    pub package_id: PackageIdSpec,
+    pub target: &'a Target,
+    pub mode: CompileMode,
+    pub commands: usize,
+}
+
+#[derive(Serialize)]
+pub struct BatchSizeComparison<'a> {
+    pub binaries: Vec<BinarySize<'a>>,
+}
+
+impl<'a> Message for BatchSizeComparison<'a> {
+    fn reason(&self) -> &str {
+        "batch-size-comparison"
+    }
+}
+
+#[derive(Serialize)]
+pub struct BinarySize<'a> {
+    pub command: &'a str,
+    pub target: &'a str,
+    pub flash: u64,
+    pub ram: u64,
+    pub baseline_flash: Option<u64>,
+    pub baseline_ram: Option<u64>,
+    pub exceeded: bool,
+}
+
 #[derive(Serialize)]
 pub struct BuildFinished {
     pub success: bool,
@@ -113,3 +187,18 @@ impl Message for BuildFinished {
         "build-finished"
     }
 }
+
+#[derive(Serialize)]
+pub struct BatchCommandFinished<'a> {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<&'a str>,
+    pub command: &'a str,
+    pub result: &'a str,
+}
+
+impl<'a> Message for BatchCommandFinished<'a> {
+    fn reason(&self) -> &str {
+        "batch-command-finished"
+    }
+}
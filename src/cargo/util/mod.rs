@@ -39,6 +39,7 @@ pub mod credential;
 mod dependency_queue;
 pub mod diagnostic_server;
 pub mod edit_distance;
+pub mod elf;
 pub mod errors;
 mod flock;
 pub mod graph;
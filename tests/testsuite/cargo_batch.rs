@@ -0,0 +1,1277 @@
+//! Tests for `cargo-batch` and the `cargo::ops::batch` API it is built on.
+
+use std::env;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use cargo::core::compiler::{CompileMode, DefaultExecutor, Executor};
+use cargo::core::{Shell, Workspace};
+use cargo::ops::batch::{Batch, BatchCommand, CommandResult};
+use cargo::ops::{CompileOptions, Packages};
+use cargo::util::context::GlobalContext;
+use cargo_test_support::prelude::*;
+use cargo_test_support::registry::Package;
+use cargo_test_support::{basic_manifest, paths, project, rustc_host, str, Execs, Project};
+
+/// A workspace of the binaries `a` and `b`, which both depend on the library
+/// `c`.
+fn shared_dep_project() -> Project {
+    project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [workspace]
+                members = ["a", "b", "c"]
+                resolver = "2"
+            "#,
+        )
+        .file(
+            "a/Cargo.toml",
+            r#"
+                [package]
+                name = "a"
+                version = "0.1.0"
+                edition = "2015"
+
+                [dependencies]
+                c = { path = "../c" }
+            "#,
+        )
+        .file("a/src/main.rs", "fn main() { c::hello(); }")
+        .file(
+            "b/Cargo.toml",
+            r#"
+                [package]
+                name = "b"
+                version = "0.1.0"
+                edition = "2015"
+
+                [dependencies]
+                c = { path = "../c" }
+            "#,
+        )
+        .file("b/src/main.rs", "fn main() { c::hello(); }")
+        .file("c/Cargo.toml", &basic_manifest("c", "0.1.0"))
+        .file("c/src/lib.rs", "pub fn hello() {}")
+        .build()
+}
+
+fn cargo_batch(p: &Project, args: &str) -> Execs {
+    let mut execs = p.process(env!("CARGO_BIN_EXE_cargo-batch"));
+    execs.args(&args.split_whitespace().collect::<Vec<_>>());
+    execs
+}
+
+#[cargo_test]
+fn shared_dependencies_are_built_once() {
+    let p = shared_dep_project();
+
+    cargo_batch(&p, "--- build -p a --- build -p b")
+        .with_stderr_data(
+            str![[r#"
+[COMPILING] c v0.1.0 ([ROOT]/foo/c)
+[COMPILING] a v0.1.0 ([ROOT]/foo/a)
+[COMPILING] b v0.1.0 ([ROOT]/foo/b)
+[FINISHED] `dev` profile [unoptimized + debuginfo] target(s) in [ELAPSED]s
+[SUMMARY] 2 commands: 2 built, 0 fresh, 0 failed, 0 skipped
+
+"#]]
+            .unordered(),
+        )
+        .run();
+
+    cargo_batch(&p, "--- build -p a --- build -p b")
+        .with_stderr_data(str![[r#"
+[FINISHED] `dev` profile [unoptimized + debuginfo] target(s) in [ELAPSED]s
+[SUMMARY] 2 commands: 0 built, 2 fresh, 0 failed, 0 skipped
+
+"#]])
+        .run();
+}
+
+#[cargo_test]
+fn keep_going_reports_each_command() {
+    let p = shared_dep_project();
+    p.change_file("b/src/main.rs", "fn main() { oops }");
+
+    cargo_batch(
+        &p,
+        "--keep-going --message-format json --- build -p a --- build -p b --- build -p nope",
+    )
+    .with_status(101)
+    .with_stdout_data(
+        str![[r#"
+[
+  {
+    "reason": "batch-command-finished",
+    "index": 0,
+    "command": "build -p a",
+    "result": "built"
+  },
+  {
+    "reason": "batch-command-finished",
+    "index": 1,
+    "command": "build -p b",
+    "result": "failed"
+  },
+  {
+    "reason": "batch-command-finished",
+    "index": 2,
+    "command": "build -p nope",
+    "result": "failed"
+  },
+  "{...}"
+]
+"#]]
+        .is_json()
+        .against_jsonlines()
+        .unordered(),
+    )
+    .with_stderr_contains("[ERROR] package ID specification `nope` did not match any packages")
+    .with_stderr_contains("[ERROR] 2 of 3 commands failed")
+    .run();
+}
+
+#[cargo_test]
+fn conflicting_artifact_dirs() {
+    let p = shared_dep_project();
+
+    cargo_batch(
+        &p,
+        "--- build -p a --artifact-dir out1 --- build -p a --artifact-dir out2",
+    )
+    .with_status(101)
+    .with_stderr_data(str![[r#"
+[ERROR] the bin target `a` in package `a v0.1.0 ([ROOT]/foo/a)` is exported to two different `--artifact-dir`s
+The first export is requested by:
+    build -p a --artifact-dir out1
+The second export is requested by:
+    build -p a --artifact-dir out2
+
+"#]])
+    .run();
+
+    // The same directory is fine.
+    cargo_batch(
+        &p,
+        "--- build -p a --artifact-dir out --- build -p a -p b --artifact-dir out",
+    )
+    .run();
+    assert!(p
+        .root()
+        .join("out")
+        .join(format!("a{}", env::consts::EXE_SUFFIX))
+        .is_file());
+    assert!(p
+        .root()
+        .join("out")
+        .join(format!("b{}", env::consts::EXE_SUFFIX))
+        .is_file());
+}
+
+#[cargo_test]
+fn command_names_must_be_file_names() {
+    let p = shared_dep_project();
+    p.change_file(
+        "batch.toml",
+        r#"
+            [[command]]
+            name = "../escape"
+            subcommand = "build"
+            package = "a"
+        "#,
+    );
+
+    cargo_batch(&p, "--batch-file batch.toml")
+        .with_status(101)
+        .with_stderr_data(str![[r#"
+[ERROR] invalid command name `../escape`
+...
+"#]])
+        .run();
+}
+
+#[cargo_test]
+fn conflicting_linkers() {
+    let p = shared_dep_project();
+    let config = format!("--config target.{}.linker=\"my-linker\"", rustc_host());
+
+    cargo_batch(&p, &format!("--- build -p a {config} --- build -p b"))
+        .with_status(101)
+        .with_stderr_data(str![[r#"
+[ERROR] commands configure a different linker or runner for `[HOST_TARGET]`, but a batch builds each target with one of them
+The first command is:
+    build -p a --config 'target.[HOST_TARGET].linker="my-linker"'
+The second command is:
+    build -p b
+
+"#]])
+        .run();
+
+    // Both configuring the same one is fine.
+    cargo_batch(
+        &p,
+        &format!("--unit-graph -Zunstable-options --- build -p a {config} --- build -p b {config}"),
+    )
+    .masquerade_as_nightly_cargo(&["unit-graph"])
+    .run();
+}
+
+#[cargo_test]
+fn api_returns_a_compilation_per_command() {
+    let p = shared_dep_project();
+
+    let shell = Shell::from_write(Box::new(Vec::new()));
+    let gctx = GlobalContext::new(shell, env::current_dir().unwrap(), paths::home());
+    let command = |package: &str| {
+        let ws = Workspace::new(&p.root().join("Cargo.toml"), &gctx).unwrap();
+        let mut compile_opts = CompileOptions::new(&gctx, CompileMode::Build).unwrap();
+        compile_opts.spec = Packages::Packages(vec![package.to_string()]);
+        BatchCommand::new(ws, compile_opts, None)
+    };
+    let batch = Batch::new(&gctx, vec![command("a"), command("b")]);
+    let manifest = p.root().join("Cargo.toml");
+    let labels: Vec<_> = batch.commands().iter().map(|cmd| cmd.label()).collect();
+    assert_eq!(
+        labels,
+        [
+            format!("build -p a --manifest-path {}", manifest.display()),
+            format!("build -p b --manifest-path {}", manifest.display()),
+        ]
+    );
+
+    let exec: Arc<dyn Executor> = Arc::new(DefaultExecutor);
+    let compiled = batch.compile(&exec).unwrap();
+    assert_eq!(
+        compiled.results,
+        [CommandResult::Built, CommandResult::Built]
+    );
+    let compilations = compiled.compilations.unwrap();
+    assert_eq!(compilations.len(), 2);
+    for (compilation, name) in compilations.iter().zip(["a", "b"]) {
+        let compilation = compilation.as_ref().unwrap();
+        assert_eq!(compilation.binaries.len(), 1);
+        assert!(compilation.binaries[0].path.exists());
+        assert_eq!(compilation.binaries[0].unit.target.name(), name);
+    }
+}
+
+#[cargo_test]
+fn test_and_bench_commands() {
+    let p = shared_dep_project();
+    p.change_file(
+        "c/src/lib.rs",
+        r#"
+            pub fn hello() {}
+
+            #[test]
+            fn c_works() {}
+        "#,
+    );
+
+    cargo_batch(&p, "--- test -p c --no-run --- bench -p c --no-run")
+        .with_stderr_data(
+            str![[r#"
+[COMPILING] c v0.1.0 ([ROOT]/foo/c)
+[FINISHED] `test` profile [unoptimized + debuginfo] target(s) in [ELAPSED]s
+[SUMMARY] 2 commands: 2 built, 0 fresh, 0 failed, 0 skipped
+[EXECUTABLE] unittests src/lib.rs (target/debug/deps/c-[HASH][EXE])
+[EXECUTABLE] benches src/lib.rs (target/release/deps/c-[HASH][EXE])
+
+"#]]
+            .unordered(),
+        )
+        .run();
+
+    // Without `--no-run`, the tests of each command run once the whole batch
+    // is built.
+    cargo_batch(&p, "--- test -p c --- build -p a")
+        .with_stdout_data(str![[r#"
+
+running 1 test
+test c_works ... ok
+
+test result: ok. 1 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out; finished in [ELAPSED]s
+
+
+running 0 tests
+
+test result: ok. 0 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out; finished in [ELAPSED]s
+
+
+"#]])
+        .with_stderr_data(str![[r#"
+[COMPILING] a v0.1.0 ([ROOT]/foo/a)
+[FINISHED] `test` profile [unoptimized + debuginfo] target(s) in [ELAPSED]s
+[SUMMARY] 2 commands: 1 built, 1 fresh, 0 failed, 0 skipped
+[RUNNING] unittests src/lib.rs (target/debug/deps/c-[HASH][EXE])
+[DOCTEST] c
+
+"#]])
+        .run();
+
+    p.change_file(
+        "c/src/lib.rs",
+        r#"
+            pub fn hello() {}
+
+            #[test]
+            fn c_fails() { panic!() }
+        "#,
+    );
+    cargo_batch(&p, "--- test -p c")
+        .with_status(101)
+        .with_stderr_contains("[ERROR] test failed, to rerun pass `-p c --lib`")
+        .run();
+}
+
+#[cargo_test]
+fn doc_commands() {
+    let p = shared_dep_project();
+
+    cargo_batch(
+        &p,
+        "--- doc -p a --artifact-dir docs-a --- doc -p b --no-deps",
+    )
+    .with_stderr_data(
+        str![[r#"
+[CHECKING] c v0.1.0 ([ROOT]/foo/c)
+[DOCUMENTING] c v0.1.0 ([ROOT]/foo/c)
+[DOCUMENTING] a v0.1.0 ([ROOT]/foo/a)
+[DOCUMENTING] b v0.1.0 ([ROOT]/foo/b)
+[FINISHED] `dev` profile [unoptimized + debuginfo] target(s) in [ELAPSED]s
+[SUMMARY] 2 commands: 2 built, 0 fresh, 0 failed, 0 skipped
+
+"#]]
+        .unordered(),
+    )
+    .run();
+
+    // `doc` documents dependencies, unlike `rustdoc`, and `--artifact-dir`
+    // gets the whole tree of its command.
+    assert!(p.root().join("docs-a/a/index.html").is_file());
+    assert!(p.root().join("docs-a/c/index.html").is_file());
+    assert!(!p.root().join("docs-a/b").exists());
+    assert!(p.root().join("target/doc/b/index.html").is_file());
+}
+
+#[cargo_test]
+fn commands_keep_their_own_profiles() {
+    let p = shared_dep_project();
+
+    cargo_batch(&p, "-v --- build -p a --- build -p a --release")
+        .with_stderr_contains(
+            "[RUNNING] `rustc --crate-name a [..]-C embed-bitcode=no -C debuginfo=2 [..]`",
+        )
+        .with_stderr_contains(
+            "[RUNNING] `rustc --crate-name a [..]-C opt-level=3 -C embed-bitcode=no [..]`",
+        )
+        .run();
+
+    // Both profiles match the standalone builds.
+    p.cargo("build -p a")
+        .with_stderr_data(str![[r#"
+[FINISHED] `dev` profile [unoptimized + debuginfo] target(s) in [ELAPSED]s
+
+"#]])
+        .run();
+    p.cargo("build -p a --release")
+        .with_stderr_data(str![[r#"
+[FINISHED] `release` profile [optimized] target(s) in [ELAPSED]s
+
+"#]])
+        .run();
+}
+
+#[cargo_test]
+fn batch_file_commands() {
+    let p = shared_dep_project();
+    p.change_file(
+        "ci/batch.toml",
+        r#"
+            [[command]]
+            name = "app"
+            subcommand = "build"
+            package = "a"
+            artifact-dir = "out"
+
+            [[command]]
+            subcommand = "check"
+            package = ["b", "c"]
+        "#,
+    );
+
+    cargo_batch(&p, "--message-format json --batch-file ci/batch.toml")
+        .with_stdout_data(
+            str![[r#"
+[
+  "{...}",
+  {
+    "reason": "build-finished",
+    "success": true
+  },
+  {
+    "command": "build --package a --artifact-dir [ROOT]/foo/ci/out",
+    "index": 0,
+    "name": "app",
+    "reason": "batch-command-finished",
+    "result": "built"
+  },
+  {
+    "command": "check --package b --package c",
+    "index": 1,
+    "reason": "batch-command-finished",
+    "result": "built"
+  }
+]
+"#]]
+            .is_json()
+            .against_jsonlines()
+            .unordered(),
+        )
+        .run();
+    // Relative paths are relative to the batch file.
+    assert!(p
+        .root()
+        .join("ci/out")
+        .join(format!("a{}", env::consts::EXE_SUFFIX))
+        .is_file());
+
+    p.change_file(
+        "batch.json",
+        r#"{ "command": [{ "name": "json", "subcommand": "build", "package": "b" }] }"#,
+    );
+    // Batch files can be JSON, and combined with commands on the command line.
+    cargo_batch(
+        &p,
+        "--message-format json --batch-file batch.json --- check -p a",
+    )
+    .with_stdout_data(
+        str![[r#"
+[
+  "{...}",
+  {
+    "reason": "build-finished",
+    "success": true
+  },
+  {
+    "command": "build --package b",
+    "index": 0,
+    "name": "json",
+    "reason": "batch-command-finished",
+    "result": "built"
+  },
+  {
+    "command": "check -p a",
+    "index": 1,
+    "reason": "batch-command-finished",
+    "result": "built"
+  }
+]
+"#]]
+        .is_json()
+        .against_jsonlines()
+        .unordered(),
+    )
+    .run();
+}
+
+#[cargo_test]
+fn stdin_commands() {
+    let p = shared_dep_project();
+
+    cargo_batch(&p, "--stdin")
+        .with_stdin("build -p a\n\n# b is only checked\ncheck -p b\n")
+        .with_stderr_data(
+            str![[r#"
+[..] c v0.1.0 ([ROOT]/foo/c)
+[CHECKING] b v0.1.0 ([ROOT]/foo/b)
+[COMPILING] a v0.1.0 ([ROOT]/foo/a)
+[FINISHED] `dev` profile [unoptimized + debuginfo] target(s) in [ELAPSED]s
+[SUMMARY] 2 commands: 2 built, 0 fresh, 0 failed, 0 skipped
+
+"#]]
+            .unordered(),
+        )
+        .run();
+
+    cargo_batch(&p, "--stdin")
+        .with_stdin("build -p a\ncheck -p 'b\n")
+        .with_status(101)
+        .with_stderr_data(str![[r#"
+[ERROR] failed to parse line 2 of stdin: `check -p 'b`
+
+Caused by:
+  missing closing quote
+
+"#]])
+        .run();
+}
+
+#[cargo_test]
+fn artifact_messages_name_their_commands() {
+    let p = shared_dep_project();
+
+    cargo_batch(&p, "--message-format json --- build -p a --- build -p b")
+        .with_stdout_data(
+            str![[r#"
+[
+  {
+    "...": "{...}",
+    "batch_commands": [
+      {
+        "index": 0
+      },
+      {
+        "index": 1
+      }
+    ],
+    "package_id": "path+[ROOTURL]/foo/c#0.1.0",
+    "reason": "compiler-artifact",
+    "target": {
+      "...": "{...}",
+      "name": "c"
+    }
+  },
+  {
+    "...": "{...}",
+    "batch_commands": [
+      {
+        "index": 1
+      }
+    ],
+    "package_id": "path+[ROOTURL]/foo/b#0.1.0",
+    "reason": "compiler-artifact",
+    "target": {
+      "...": "{...}",
+      "name": "b"
+    }
+  },
+  {
+    "...": "{...}",
+    "batch_commands": [
+      {
+        "index": 0
+      }
+    ],
+    "package_id": "path+[ROOTURL]/foo/a#0.1.0",
+    "reason": "compiler-artifact",
+    "target": {
+      "...": "{...}",
+      "name": "a"
+    }
+  },
+  {
+    "reason": "build-finished",
+    "success": true
+  },
+  {
+    "command": "build -p a",
+    "index": 0,
+    "reason": "batch-command-finished",
+    "result": "built"
+  },
+  {
+    "command": "build -p b",
+    "index": 1,
+    "reason": "batch-command-finished",
+    "result": "built"
+  }
+]
+"#]]
+            .is_json()
+            .against_jsonlines()
+            .unordered(),
+        )
+        .run();
+}
+
+#[cargo_test]
+fn dedup_report() {
+    let p = shared_dep_project();
+
+    cargo_batch(&p, "--dedup-report --- build -p a --- build -p b")
+        .with_stdout_data(str![[r#"
+2 commands need 4 units, 3 after merging (1 deduplicated, 25.0%)
+most shared units:
+     2 commands  c v0.1.0 lib (build, host)
+
+"#]])
+        .with_stderr_data(str![[""]])
+        .run();
+
+    cargo_batch(
+        &p,
+        "--dedup-report --message-format json --- build -p a --- build -p b",
+    )
+    .with_stdout_data(
+        str![[r#"
+[
+  {
+    "commands": 2,
+    "reason": "batch-dedup-report",
+    "shared_units": [
+      {
+        "commands": 2,
+        "mode": "build",
+        "package_id": "path+[ROOTURL]/foo/c#0.1.0",
+        "target": {
+          "...": "{...}",
+          "name": "c"
+        }
+      }
+    ],
+    "total_units": 4,
+    "unique_units": 3
+  }
+]
+"#]]
+        .is_json()
+        .against_jsonlines(),
+    )
+    .run();
+
+    // Nothing was built.
+    assert!(!p.root().join("target").exists());
+}
+
+#[cargo_test]
+fn commands_of_several_workspaces() {
+    let p = shared_dep_project();
+    p.change_file(
+        "other/Cargo.toml",
+        r#"
+            [package]
+            name = "other"
+            version = "0.1.0"
+            edition = "2015"
+
+            [dependencies]
+            c = { path = "../c" }
+
+            [profile.dev]
+            opt-level = 1
+
+            [workspace]
+        "#,
+    );
+    p.change_file("other/src/main.rs", "fn main() { c::hello(); }");
+
+    cargo_batch(
+        &p,
+        "-v --- build -p a --- build --manifest-path other/Cargo.toml",
+    )
+    .with_stderr_contains(
+        "[RUNNING] `rustc --crate-name c [..]-C embed-bitcode=no -C debuginfo=2 [..]`",
+    )
+    .with_stderr_contains("[RUNNING] `rustc --crate-name c [..]-C opt-level=1 [..]`")
+    .run();
+    assert!(p.root().join("other/Cargo.lock").is_file());
+
+    // Each workspace's units are those of a standalone build.
+    p.cargo("build -p a")
+        .with_stderr_data(str![[r#"
+[FINISHED] `dev` profile [unoptimized + debuginfo] target(s) in [ELAPSED]s
+
+"#]])
+        .run();
+    p.cargo("build --manifest-path other/Cargo.toml --target-dir target")
+        .with_stderr_data(str![[r#"
+[FINISHED] `dev` profile [optimized + debuginfo] target(s) in [ELAPSED]s
+
+"#]])
+        .run();
+}
+
+#[cargo_test]
+fn commands_share_resolves_per_lockfile() {
+    Package::new("dep", "1.0.0").publish();
+    let p = shared_dep_project();
+    p.change_file(
+        "c/Cargo.toml",
+        r#"
+            [package]
+            name = "c"
+            version = "0.1.0"
+            edition = "2015"
+
+            [dependencies]
+            dep = "1.0"
+        "#,
+    );
+
+    // The workspace is resolved and locked once.
+    cargo_batch(&p, "--- build -p a --- check -p b")
+        .with_stderr_data(
+            str![[r#"
+[UPDATING] `dummy-registry` index
+[LOCKING] 1 package to latest compatible version
+[DOWNLOADING] crates ...
+[DOWNLOADED] dep v1.0.0 (registry `dummy-registry`)
+[CHECKING] b v0.1.0 ([ROOT]/foo/b)
+[COMPILING] a v0.1.0 ([ROOT]/foo/a)
+[FINISHED] `dev` profile [unoptimized + debuginfo] target(s) in [ELAPSED]s
+[SUMMARY] 2 commands: 2 built, 0 fresh, 0 failed, 0 skipped
+[..] dep v1.0.0
+[..] c v0.1.0 ([ROOT]/foo/c)
+
+"#]]
+            .unordered(),
+        )
+        .run();
+
+    // Commands with their own lockfile resolve it on their own.
+    p.root().join("Cargo.lock").rm_rf();
+    cargo_batch(
+        &p,
+        "-Zunstable-options --- build -p a --lockfile-path l1/Cargo.lock \
+         --- build -p b --lockfile-path l2/Cargo.lock",
+    )
+    .masquerade_as_nightly_cargo(&["lockfile-path"])
+    .run();
+    assert!(p.root().join("l1/Cargo.lock").is_file());
+    assert!(p.root().join("l2/Cargo.lock").is_file());
+    assert!(!p.root().join("Cargo.lock").exists());
+}
+
+#[cargo_test]
+fn artifact_manifest() {
+    let p = shared_dep_project();
+
+    cargo_batch(
+        &p,
+        "--artifact-manifest artifacts.json --- build -p a --artifact-dir out --- build -p c",
+    )
+    .run();
+
+    let manifest: serde_json::Value = serde_json::from_str(&p.read_file("artifacts.json")).unwrap();
+    let commands = manifest["commands"].as_array().unwrap();
+    assert_eq!(commands.len(), 2);
+    assert_eq!(commands[0]["index"], 0);
+    assert_eq!(commands[0]["command"], "build -p a --artifact-dir out");
+    assert_eq!(commands[1]["command"], "build -p c");
+
+    let units = commands[0]["units"].as_array().unwrap();
+    assert_eq!(units.len(), 1);
+    assert_eq!(units[0]["target"]["name"], "a");
+    let bin = units[0]["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|file| file["flavor"] == "normal")
+        .unwrap();
+    assert!(Path::new(bin["path"].as_str().unwrap()).is_file());
+    assert_eq!(
+        Path::new(bin["export_path"].as_str().unwrap()),
+        p.root()
+            .join("out")
+            .join(format!("a{}", env::consts::EXE_SUFFIX))
+    );
+
+    // Libraries aren't exported without `--artifact-dir`.
+    let units = commands[1]["units"].as_array().unwrap();
+    assert_eq!(units[0]["target"]["name"], "c");
+    for file in units[0]["files"].as_array().unwrap() {
+        assert!(Path::new(file["path"].as_str().unwrap()).is_file());
+        assert!(file["export_path"].is_null());
+    }
+}
+
+#[cargo_test]
+fn commands_with_their_own_env() {
+    let p = shared_dep_project();
+    p.change_file(
+        "c/build.rs",
+        r#"
+            fn main() {
+                println!("cargo::rerun-if-env-changed=LEVEL");
+                println!("cargo::warning=LEVEL={}", std::env::var("LEVEL").unwrap_or_default());
+            }
+        "#,
+    );
+
+    // Each environment is a unit of its own.
+    cargo_batch(
+        &p,
+        "--- build -p c --env LEVEL=1 --- build -p c --env LEVEL=2 --- build -p c",
+    )
+    .with_stderr_data(
+        str![[r#"
+[COMPILING] c v0.1.0 ([ROOT]/foo/c)
+[WARNING] c@0.1.0: LEVEL=
+[WARNING] c@0.1.0: LEVEL=2
+[WARNING] c@0.1.0: LEVEL=1
+[FINISHED] `dev` profile [unoptimized + debuginfo] target(s) in [ELAPSED]s
+[SUMMARY] 3 commands: 3 built, 0 fresh, 0 failed, 0 skipped
+
+"#]]
+        .unordered(),
+    )
+    .run();
+
+    cargo_batch(
+        &p,
+        "--- build -p c --env LEVEL=1 --- build -p c --env LEVEL=2 --- build -p c",
+    )
+    .with_stderr_data(
+        str![[r#"
+[WARNING] c@0.1.0: LEVEL=
+[WARNING] c@0.1.0: LEVEL=2
+[WARNING] c@0.1.0: LEVEL=1
+[FINISHED] `dev` profile [unoptimized + debuginfo] target(s) in [ELAPSED]s
+[SUMMARY] 3 commands: 0 built, 3 fresh, 0 failed, 0 skipped
+
+"#]]
+        .unordered(),
+    )
+    .run();
+}
+
+#[cargo_test]
+fn uplift_into_command_dirs() {
+    let p = shared_dep_project();
+    p.change_file(
+        "batch.toml",
+        r#"
+            [[command]]
+            name = "first"
+            subcommand = "build"
+            package = "a"
+
+            [[command]]
+            subcommand = "build"
+            package = "b"
+        "#,
+    );
+    let bin = |dir: &str, name: &str| {
+        p.root()
+            .join(dir)
+            .join(format!("{name}{}", env::consts::EXE_SUFFIX))
+    };
+
+    cargo_batch(&p, "--uplift --batch-file batch.toml").run();
+    assert!(bin("target/batch/first/debug", "a").is_file());
+    assert!(bin("target/batch/1/debug", "b").is_file());
+    assert!(!bin("target/debug", "a").exists());
+
+    cargo_batch(
+        &p,
+        "--uplift --link-method copy --- build -p a --artifact-dir out",
+    )
+    .run();
+    for path in [bin("target/batch/0/debug", "a"), bin("out", "a")] {
+        assert!(path.is_file());
+        assert!(!path.symlink_metadata().unwrap().is_symlink());
+    }
+}
+
+#[cfg(unix)]
+#[cargo_test]
+fn uplift_with_symlinks() {
+    let p = shared_dep_project();
+
+    cargo_batch(
+        &p,
+        "--uplift --link-method symlink --- build -p a --artifact-dir out",
+    )
+    .run();
+    for path in ["target/batch/0/debug/a", "out/a"] {
+        let path = p.root().join(path);
+        assert!(path.symlink_metadata().unwrap().is_symlink());
+        assert!(path.is_file());
+    }
+}
+
+#[cargo_test]
+fn matrix_commands() {
+    let p = shared_dep_project();
+    p.change_file(
+        "c/Cargo.toml",
+        r#"
+            [package]
+            name = "c"
+            version = "0.1.0"
+            edition = "2015"
+
+            [features]
+            default = ["x"]
+            x = []
+            y = []
+        "#,
+    );
+
+    cargo_batch(
+        &p,
+        "--message-format json --- check -p c --matrix-features x --matrix-features y \
+         --matrix-features x,y --matrix-exclude features=y --matrix-include package=a",
+    )
+    .with_stdout_data(str![[r#"
+[
+  {
+    "...": "{...}",
+    "batch_commands": [
+      {
+        "index": 0
+      },
+      {
+        "index": 2
+      }
+    ],
+    "features": [
+      "default",
+      "x"
+    ],
+    "package_id": "path+[ROOTURL]/foo/c#0.1.0",
+    "reason": "compiler-artifact",
+    "target": {
+      "...": "{...}",
+      "name": "c"
+    }
+  },
+  {
+    "...": "{...}",
+    "batch_commands": [
+      {
+        "index": 2
+      }
+    ],
+    "features": [],
+    "package_id": "path+[ROOTURL]/foo/a#0.1.0",
+    "reason": "compiler-artifact",
+    "target": {
+      "...": "{...}",
+      "name": "a"
+    }
+  },
+  {
+    "...": "{...}",
+    "batch_commands": [
+      {
+        "index": 1
+      }
+    ],
+    "features": [
+      "default",
+      "x",
+      "y"
+    ],
+    "package_id": "path+[ROOTURL]/foo/c#0.1.0",
+    "reason": "compiler-artifact",
+    "target": {
+      "...": "{...}",
+      "name": "c"
+    }
+  },
+  {
+    "reason": "build-finished",
+    "success": true
+  },
+  {
+    "command": "check -p c --matrix-features x --matrix-features y --matrix-features x,y --matrix-exclude 'features=y' --matrix-include 'package=a' [features=x]",
+    "index": 0,
+    "reason": "batch-command-finished",
+    "result": "built"
+  },
+  {
+    "command": "check -p c --matrix-features x --matrix-features y --matrix-features x,y --matrix-exclude 'features=y' --matrix-include 'package=a' [features=x,y]",
+    "index": 1,
+    "reason": "batch-command-finished",
+    "result": "built"
+  },
+  {
+    "command": "check -p c --matrix-features x --matrix-features y --matrix-features x,y --matrix-exclude 'features=y' --matrix-include 'package=a' [package=a]",
+    "index": 2,
+    "reason": "batch-command-finished",
+    "result": "built"
+  }
+]
+"#]].is_json().against_jsonlines().unordered())
+    .run();
+
+    cargo_batch(
+        &p,
+        "--message-format json --- check -p c --feature-powerset",
+    )
+    .with_stdout_data(
+        str![[r#"
+[
+  {
+    "...": "{...}",
+    "batch_commands": [
+      {
+        "index": 1
+      }
+    ],
+    "features": [
+      "x"
+    ],
+    "package_id": "path+[ROOTURL]/foo/c#0.1.0",
+    "reason": "compiler-artifact",
+    "target": {
+      "...": "{...}",
+      "name": "c"
+    }
+  },
+  {
+    "...": "{...}",
+    "batch_commands": [
+      {
+        "index": 2
+      }
+    ],
+    "features": [
+      "y"
+    ],
+    "package_id": "path+[ROOTURL]/foo/c#0.1.0",
+    "reason": "compiler-artifact",
+    "target": {
+      "...": "{...}",
+      "name": "c"
+    }
+  },
+  {
+    "...": "{...}",
+    "batch_commands": [
+      {
+        "index": 3
+      }
+    ],
+    "features": [
+      "x",
+      "y"
+    ],
+    "package_id": "path+[ROOTURL]/foo/c#0.1.0",
+    "reason": "compiler-artifact",
+    "target": {
+      "...": "{...}",
+      "name": "c"
+    }
+  },
+  {
+    "...": "{...}",
+    "batch_commands": [
+      {
+        "index": 0
+      }
+    ],
+    "features": [],
+    "package_id": "path+[ROOTURL]/foo/c#0.1.0",
+    "reason": "compiler-artifact",
+    "target": {
+      "...": "{...}",
+      "name": "c"
+    }
+  },
+  {
+    "reason": "build-finished",
+    "success": true
+  },
+  {
+    "command": "check -p c --feature-powerset [features=]",
+    "index": 0,
+    "reason": "batch-command-finished",
+    "result": "built"
+  },
+  {
+    "command": "check -p c --feature-powerset [features=x]",
+    "index": 1,
+    "reason": "batch-command-finished",
+    "result": "built"
+  },
+  {
+    "command": "check -p c --feature-powerset [features=y]",
+    "index": 2,
+    "reason": "batch-command-finished",
+    "result": "built"
+  },
+  {
+    "command": "check -p c --feature-powerset [features=x,y]",
+    "index": 3,
+    "reason": "batch-command-finished",
+    "result": "built"
+  }
+]
+"#]]
+        .is_json()
+        .against_jsonlines()
+        .unordered(),
+    )
+    .run();
+
+    cargo_batch(&p, "--- check --feature-powerset")
+        .with_status(101)
+        .with_stderr_data(str![[r#"
+[ERROR] `--feature-powerset` needs a single package, but 3 are selected
+Select one with `--package` or `--matrix-package`
+
+"#]])
+        .run();
+}
+
+/// Runs `cargo-batch` with `args` until it prints a line containing
+/// `ready` to stderr. Returns the process and the rest of its stderr.
+fn spawn_cargo_batch(
+    p: &Project,
+    args: &str,
+    ready: &str,
+) -> (std::process::Child, mpsc::Receiver<String>) {
+    let mut cmd = cargo_batch(p, args).build_command();
+    cmd.stdout(Stdio::null()).stderr(Stdio::piped());
+    let mut child = cmd.spawn().unwrap();
+    let stderr = BufReader::new(child.stderr.take().unwrap());
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in stderr.lines() {
+            let Ok(line) = line else { break };
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    wait_for_line(&rx, ready);
+    (child, rx)
+}
+
+/// Waits for a line of `lines` containing `expected`, and returns the lines
+/// before it.
+fn wait_for_line(lines: &mpsc::Receiver<String>, expected: &str) -> Vec<String> {
+    let mut seen = Vec::new();
+    loop {
+        match lines.recv_timeout(Duration::from_secs(60)) {
+            Ok(line) if line.contains(expected) => return seen,
+            Ok(line) => seen.push(line),
+            Err(e) => panic!("no line containing `{expected}` ({e}), got:\n{seen:#?}"),
+        }
+    }
+}
+
+#[cfg(unix)]
+#[cargo_test]
+fn serve_and_connect() {
+    let p = shared_dep_project();
+    let (mut server, _stderr) = spawn_cargo_batch(&p, "serve --socket batch.sock", "Listening");
+
+    cargo_batch(
+        &p,
+        "--connect batch.sock --message-format json --- build -p a",
+    )
+    .with_stdout_data(
+        str![[r#"
+[
+  "{...}",
+  {
+    "reason": "build-finished",
+    "success": true
+  },
+  {
+    "command": "build -p a",
+    "index": 0,
+    "reason": "batch-command-finished",
+    "result": "built"
+  }
+]
+"#]]
+        .is_json()
+        .against_jsonlines()
+        .unordered(),
+    )
+    .with_stderr_data(str![[r#"
+[COMPILING] c v0.1.0 ([ROOT]/foo/c)
+[COMPILING] a v0.1.0 ([ROOT]/foo/a)
+[FINISHED] `dev` profile [unoptimized + debuginfo] target(s) in [ELAPSED]s
+
+"#]])
+    .run();
+
+    // Requests are relative to the client's directory.
+    cargo_batch(&p, "--connect ../batch.sock --- build")
+        .cwd(p.root().join("b"))
+        .with_stderr_data(str![[r#"
+[COMPILING] b v0.1.0 ([ROOT]/foo/b)
+[FINISHED] `dev` profile [unoptimized + debuginfo] target(s) in [ELAPSED]s
+[SUMMARY] 1 commands: 1 built, 0 fresh, 0 failed, 0 skipped
+
+"#]])
+        .run();
+
+    cargo_batch(&p, "--connect batch.sock --- build -p nope")
+        .with_status(101)
+        .with_stdout_data("")
+        .with_stderr_data(str![[r#"
+[ERROR] package ID specification `nope` did not match any packages
+
+"#]])
+        .run();
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[cargo_test]
+fn watch_rebuilds_affected_commands() {
+    let p = shared_dep_project();
+    let (mut watcher, stderr) =
+        spawn_cargo_batch(&p, "--watch --- build -p a --- build -p b", "Watching");
+
+    p.change_file("a/src/main.rs", "fn main() { c::hello(); c::hello(); }");
+    let rebuilt = wait_for_line(&stderr, "Watching");
+    assert!(rebuilt[0].contains("Rebuilding 1 of 2 commands after changes to"));
+    assert!(rebuilt
+        .iter()
+        .any(|line| line.contains("Compiling a v0.1.0")));
+    assert!(!rebuilt
+        .iter()
+        .any(|line| line.contains("Compiling b v0.1.0")));
+
+    p.change_file("c/src/lib.rs", "pub fn hello() { }");
+    let rebuilt = wait_for_line(&stderr, "Watching");
+    assert!(rebuilt[0].contains("Rebuilding 2 of 2 commands after changes to"));
+    assert!(rebuilt
+        .iter()
+        .any(|line| line.contains("Compiling b v0.1.0")));
+
+    watcher.kill().unwrap();
+    watcher.wait().unwrap();
+}
+
+#[cargo_test]
+fn gc_unused_outputs() {
+    let p = shared_dep_project();
+    cargo_batch(&p, "--- build -p a --- build -p b").run();
+
+    cargo_batch(&p, "-v --gc-unused --dry-run --- build -p a")
+        .with_stdout_contains("[..]/target/debug/.fingerprint/b-[..]/bin-b")
+        .with_stdout_does_not_contain("[..]/a-[..]")
+        .with_stdout_does_not_contain("[..]/c-[..]")
+        .with_stderr_data(str![[r#"
+[SUMMARY] [FILE_NUM] files, [FILE_SIZE]B total
+[WARNING] no files deleted due to --dry-run
+
+"#]])
+        .run();
+    assert!(p.glob("target/debug/deps/b-*").count() > 0);
+    cargo_batch(&p, "--gc-unused --- build -p a")
+        .with_stderr_data(str![[r#"
+[REMOVED] [FILE_NUM] files, [FILE_SIZE]B total
+[FINISHED] `dev` profile [unoptimized + debuginfo] target(s) in [ELAPSED]s
+[SUMMARY] 1 commands: 0 built, 1 fresh, 0 failed, 0 skipped
+
+"#]])
+        .run();
+    assert!(p.glob("target/debug/deps/a-*").count() > 0);
+    assert_eq!(p.glob("target/debug/deps/b-*").count(), 0);
+    assert_eq!(p.glob("target/debug/.fingerprint/b-*").count(), 0);
+
+    // What the batch still produces is fresh.
+    cargo_batch(&p, "--- build -p a")
+        .with_stderr_data(str![[r#"
+[FINISHED] `dev` profile [unoptimized + debuginfo] target(s) in [ELAPSED]s
+[SUMMARY] 1 commands: 0 built, 1 fresh, 0 failed, 0 skipped
+
+"#]])
+        .run();
+}
@@ -21,6 +21,7 @@ mod cache_messages;
 mod cargo;
 mod cargo_add;
 mod cargo_alias_config;
+mod cargo_batch;
 mod cargo_bench;
 mod cargo_build;
 mod cargo_check;